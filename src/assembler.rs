@@ -0,0 +1,1032 @@
+// A two pass assembler for MARS flavoured assembly source.
+//
+// The first pass splits every line into labels, directives and instructions, expands pseudo
+// instructions into basic ones and lays everything out in its section so each label gets its
+// address. The second pass resolves the remaining symbol references and encodes the
+// instructions with the shared instruction table.
+
+use crate::instruction::{self, OpInfo, Syntax, REGISTER_NAMES};
+use crate::preprocessor::{Preprocessor, SourceLine};
+
+use std::collections::HashMap;
+use std::fmt;
+
+// default base addresses, same as MARS
+const TEXT_BASE: u32 = 0x0040_0000;
+const DATA_BASE: u32 = 0x1001_0000;
+const KTEXT_BASE: u32 = 0x8000_0000;
+const KDATA_BASE: u32 = 0x9000_0000;
+
+// $at is reserved for pseudo instruction expansions
+const AT: Operand = Operand::Reg(1);
+const ZERO: Operand = Operand::Reg(0);
+
+// something went wrong, and where
+#[derive(Debug)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+// a contiguous chunk of assembled bytes
+pub struct Section {
+    pub base: u32,
+    pub bytes: Vec<u8>,
+}
+
+// the result of assembling a source file, ready to be loaded into RAM
+pub struct Program {
+    pub sections: Vec<Section>,
+}
+
+///////////////
+//
+// TOKENS AND OPERANDS
+//
+///////////////
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Reg(String),
+    Num(i64),
+    Str(Vec<u8>),
+    Punct(char),
+}
+
+// which part of a resolved value an operand is interested in
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Part {
+    Full,
+    Hi,         //upper half, for lui + ori pairs
+    HiAdj,      //upper half adjusted for a sign extended lower half, for lui + load/store pairs
+    Lo,         //lower half zero extended
+    LoSigned,   //lower half sign extended
+}
+
+// a symbol plus a constant offset
+#[derive(Clone, Debug, PartialEq)]
+struct Expr {
+    symbol: Option<String>,
+    offset: i64,
+    part: Part,
+}
+
+impl Expr {
+    fn constant(value: i64) -> Expr {
+        Expr { symbol: None, offset: value, part: Part::Full }
+    }
+
+    //the value if it's known without a symbol table
+    fn literal(&self) -> Option<i64> {
+        match self.symbol {
+            None => Some(apply_part(self.offset, self.part)),
+            Some(_) => None,
+        }
+    }
+
+    fn with_part(&self, part: Part) -> Expr {
+        Expr { part, ..self.clone() }
+    }
+
+    fn resolve(&self, symbols: &HashMap<String, u32>) -> Result<i64, String> {
+        let base = match &self.symbol {
+            Some(name) => *symbols.get(name).ok_or(format!("undefined symbol '{}'", name))? as i64,
+            None => 0,
+        };
+
+        Ok(apply_part(base + self.offset, self.part))
+    }
+}
+
+fn apply_part(value: i64, part: Part) -> i64 {
+    let word = value as u32;
+    match part {
+        Part::Full => value,
+        Part::Hi => (word >> 16) as i64,
+        Part::HiAdj => (word.wrapping_add(0x8000) >> 16) as i64,
+        Part::Lo => (word & 0xFFFF) as i64,
+        Part::LoSigned => word as u16 as i16 as i64,
+    }
+}
+
+// an instruction operand as written in the source
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Reg(u8),
+    Expr(Expr),
+    Mem(Expr, u8),  //offset(base)
+}
+
+// an operand after symbol resolution, what the encoder works with
+#[derive(Clone, Copy, Debug)]
+enum Value {
+    Reg(u8),
+    Imm(i64),
+    Mem(i64, u8),
+}
+
+// a basic (non pseudo) instruction waiting to be encoded
+struct Basic {
+    info: &'static OpInfo,
+    operands: Vec<Operand>,
+}
+
+impl Basic {
+    //resolve the operands and encode the instruction
+    fn encode(&self, address: u32, symbols: &HashMap<String, u32>) -> Result<u32, String> {
+        let values = self.operands.iter().map(|operand| match operand {
+            Operand::Reg(r) => Ok(Value::Reg(*r)),
+            Operand::Expr(expr) => Ok(Value::Imm(expr.resolve(symbols)?)),
+            Operand::Mem(offset, base) => Ok(Value::Mem(offset.resolve(symbols)?, *base)),
+        }).collect::<Result<Vec<Value>, String>>()?;
+
+        encode(self.info, &values, address)
+    }
+}
+
+fn basic(name: &str, operands: Vec<Operand>) -> Basic {
+    Basic { info: instruction::lookup_name(name).unwrap(), operands }
+}
+
+// split a line into tokens, dropping the comment
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        }
+        else if c == '#' {
+            break;
+        }
+        else if c == '"' {
+            let mut bytes = Vec::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("unterminated string".to_string()),
+                    Some('"') => break,
+                    Some('\\') => {
+                        bytes.push(unescape(chars.get(i + 1).copied())?);
+                        i += 2;
+                    }
+                    Some(&c) => {
+                        let mut buf = [0; 4];
+                        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Str(bytes));
+            i += 1;
+        }
+        else if c == '\'' {
+            //character literals are just numbers
+            let (value, len) = match chars.get(i + 1) {
+                Some('\\') => (unescape(chars.get(i + 2).copied())?, 4),
+                Some(&c) if c.is_ascii() => (c as u8, 3),
+                _ => return Err("bad character literal".to_string()),
+            };
+            if chars.get(i + len - 1) != Some(&'\'') {
+                return Err("unterminated character literal".to_string());
+            }
+            tokens.push(Token::Num(value as i64));
+            i += len;
+        }
+        else if c == '$' {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            tokens.push(Token::Reg(chars[start..i].iter().collect()));
+        }
+        else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(Token::Num(parse_number(&text).ok_or(format!("bad number '{}'", text))?));
+        }
+        else if c.is_alphabetic() || c == '_' || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        }
+        else if ",():+-".contains(c) {
+            tokens.push(Token::Punct(c));
+            i += 1;
+        }
+        else {
+            return Err(format!("unexpected character '{}'", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn unescape(c: Option<char>) -> Result<u8, String> {
+    match c {
+        Some('n') => Ok(b'\n'),
+        Some('t') => Ok(b'\t'),
+        Some('r') => Ok(b'\r'),
+        Some('0') => Ok(0),
+        Some('\\') => Ok(b'\\'),
+        Some('"') => Ok(b'"'),
+        Some('\'') => Ok(b'\''),
+        _ => Err("unknown escape sequence".to_string()),
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    }
+    else if let Some(bin) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()
+    }
+    else {
+        text.parse().ok()
+    }
+}
+
+// look up a register by number ($8) or by name ($t0)
+pub fn parse_register(name: &str) -> Option<u8> {
+    if let Ok(number) = name.parse::<u8>() {
+        return if number < 32 { Some(number) } else { None };
+    }
+
+    match name {
+        "s8" => Some(30),
+        _ => REGISTER_NAMES.iter().position(|&r| r == name).map(|n| n as u8),
+    }
+}
+
+// walks over the tokens of one statement
+struct Cursor<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(tokens: &'a [Token]) -> Cursor<'a> {
+        Cursor { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        match self.next() {
+            Some(Token::Reg(name)) => parse_register(name).ok_or(format!("unknown register '${}'", name)),
+            _ => Err("expected a register".to_string()),
+        }
+    }
+
+    //symbol and/or numbers glued together by + and -
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut expr = Expr::constant(0);
+        let mut negative = self.eat('-');
+
+        loop {
+            match self.next() {
+                Some(Token::Num(value)) => expr.offset += if negative { -value } else { *value },
+                Some(Token::Ident(name)) if !negative && expr.symbol.is_none() => expr.symbol = Some(name.clone()),
+                Some(Token::Ident(name)) => return Err(format!("can't use symbol '{}' here", name)),
+                _ => return Err("expected a number or a symbol".to_string()),
+            }
+
+            if self.eat('+') {
+                negative = false;
+            }
+            else if self.eat('-') {
+                negative = true;
+            }
+            else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    //one instruction operand
+    fn operand(&mut self) -> Result<Operand, String> {
+        if let Some(Token::Reg(_)) = self.peek() {
+            return Ok(Operand::Reg(self.register()?));
+        }
+
+        let offset = if self.peek() == Some(&Token::Punct('(')) { Expr::constant(0) } else { self.expr()? };
+
+        if self.eat('(') {
+            let base = self.register()?;
+            if !self.eat(')') {
+                return Err("expected ')'".to_string());
+            }
+            Ok(Operand::Mem(offset, base))
+        }
+        else {
+            Ok(Operand::Expr(offset))
+        }
+    }
+
+    //all operands of an instruction, commas are optional like in MARS
+    fn operands(&mut self) -> Result<Vec<Operand>, String> {
+        let mut operands = Vec::new();
+        while !self.done() {
+            operands.push(self.operand()?);
+            self.eat(',');
+        }
+        Ok(operands)
+    }
+}
+
+///////////////
+//
+// PSEUDO INSTRUCTIONS
+//
+///////////////
+
+fn fits_signed16(value: i64) -> bool {
+    (-0x8000..=0x7FFF).contains(&value)
+}
+
+fn fits_unsigned16(value: i64) -> bool {
+    (0..=0xFFFF).contains(&value)
+}
+
+fn fits_word(value: i64) -> bool {
+    (-0x8000_0000..=0xFFFF_FFFF).contains(&value)
+}
+
+// the register form of an instruction with an immediate
+fn register_form(name: &str) -> Option<&'static str> {
+    match name {
+        "addi" => Some("add"),
+        "addiu" => Some("addu"),
+        "slti" => Some("slt"),
+        "sltiu" => Some("sltu"),
+        "andi" => Some("and"),
+        "ori" => Some("or"),
+        "xori" => Some("xor"),
+        _ => None,
+    }
+}
+
+// the immediate form of an instruction with three registers
+fn immediate_form(name: &str) -> Option<&'static str> {
+    match name {
+        "add" => Some("addi"),
+        "addu" => Some("addiu"),
+        "slt" => Some("slti"),
+        "sltu" => Some("sltiu"),
+        "and" => Some("andi"),
+        "or" => Some("ori"),
+        "xor" => Some("xori"),
+        _ => None,
+    }
+}
+
+// load a value into a register, in as few instructions as possible
+fn load_immediate(rt: Operand, value: &Expr) -> Result<Vec<Basic>, String> {
+    match value.literal() {
+        Some(v) if fits_signed16(v) => Ok(vec![basic("addiu", vec![rt, ZERO, Operand::Expr(Expr::constant(v))])]),
+        Some(v) if fits_unsigned16(v) => Ok(vec![basic("ori", vec![rt, ZERO, Operand::Expr(Expr::constant(v))])]),
+        Some(v) if !fits_word(v) => Err(format!("value {} doesn't fit into 32 bit", v)),
+        _ => Ok(vec![
+            basic("lui", vec![AT, Operand::Expr(value.with_part(Part::Hi))]),
+            basic("ori", vec![rt, AT, Operand::Expr(value.with_part(Part::Lo))]),
+        ]),
+    }
+}
+
+// turn one source instruction into basic instructions
+fn expand(name: &str, ops: Vec<Operand>) -> Result<Vec<Basic>, String> {
+    use Operand::{Expr as E, Mem, Reg};
+
+    let info = instruction::lookup_name(name);
+
+    let result = match (name, ops.as_slice()) {
+        ("nop", []) => vec![basic("sll", vec![ZERO, ZERO, E(Expr::constant(0))])],
+        ("move", [rd @ Reg(_), rs @ Reg(_)]) => vec![basic("addu", vec![rd.clone(), ZERO, rs.clone()])],
+        ("li", [rt @ Reg(_), E(value)]) => load_immediate(rt.clone(), value)?,
+        ("la", [rt @ Reg(_), E(value)]) => vec![
+            basic("lui", vec![AT, E(value.with_part(Part::Hi))]),
+            basic("ori", vec![rt.clone(), AT, E(value.with_part(Part::Lo))]),
+        ],
+        ("la", [rt @ Reg(_), Mem(offset, base)]) => match offset.literal() {
+            Some(v) if fits_signed16(v) => vec![basic("addiu", vec![rt.clone(), Reg(*base), E(offset.clone())])],
+            _ => vec![
+                basic("lui", vec![AT, E(offset.with_part(Part::Hi))]),
+                basic("ori", vec![AT, AT, E(offset.with_part(Part::Lo))]),
+                basic("addu", vec![rt.clone(), AT, Reg(*base)]),
+            ],
+        },
+        ("b", [label @ E(_)]) => vec![basic("beq", vec![ZERO, ZERO, label.clone()])],
+        ("beqz", [rs @ Reg(_), label @ E(_)]) => vec![basic("beq", vec![rs.clone(), ZERO, label.clone()])],
+        ("bnez", [rs @ Reg(_), label @ E(_)]) => vec![basic("bne", vec![rs.clone(), ZERO, label.clone()])],
+        ("blt", [a @ Reg(_), b, label @ E(_)]) | ("bltu", [a @ Reg(_), b, label @ E(_)]) =>
+            compare_and_branch(a, b, false, "bne", name == "bltu", label)?,
+        ("bge", [a @ Reg(_), b, label @ E(_)]) | ("bgeu", [a @ Reg(_), b, label @ E(_)]) =>
+            compare_and_branch(a, b, false, "beq", name == "bgeu", label)?,
+        ("bgt", [a @ Reg(_), b, label @ E(_)]) | ("bgtu", [a @ Reg(_), b, label @ E(_)]) =>
+            compare_and_branch(a, b, true, "bne", name == "bgtu", label)?,
+        ("ble", [a @ Reg(_), b, label @ E(_)]) | ("bleu", [a @ Reg(_), b, label @ E(_)]) =>
+            compare_and_branch(a, b, true, "beq", name == "bleu", label)?,
+        ("neg", [rd @ Reg(_), rs @ Reg(_)]) => vec![basic("sub", vec![rd.clone(), ZERO, rs.clone()])],
+        ("negu", [rd @ Reg(_), rs @ Reg(_)]) => vec![basic("subu", vec![rd.clone(), ZERO, rs.clone()])],
+        ("not", [rd @ Reg(_), rs @ Reg(_)]) => vec![basic("nor", vec![rd.clone(), rs.clone(), ZERO])],
+        ("sgt", [rd @ Reg(_), rs @ Reg(_), rt @ Reg(_)]) => vec![basic("slt", vec![rd.clone(), rt.clone(), rs.clone()])],
+        ("sgtu", [rd @ Reg(_), rs @ Reg(_), rt @ Reg(_)]) => vec![basic("sltu", vec![rd.clone(), rt.clone(), rs.clone()])],
+        ("subi", [rt @ Reg(_), rs @ Reg(_), E(value)]) | ("subiu", [rt @ Reg(_), rs @ Reg(_), E(value)]) => {
+            let negated = E(Expr::constant(-value.literal().ok_or("subtracting a symbol isn't supported")?));
+            return expand(if name == "subi" { "addi" } else { "addiu" }, vec![rt.clone(), rs.clone(), negated]);
+        }
+        ("div", [rd @ Reg(_), rs @ Reg(_), rt @ Reg(_)]) | ("divu", [rd @ Reg(_), rs @ Reg(_), rt @ Reg(_)]) =>
+            vec![basic(name, vec![rs.clone(), rt.clone()]), basic("mflo", vec![rd.clone()])],
+        ("rem", [rd @ Reg(_), rs @ Reg(_), rt @ Reg(_)]) | ("remu", [rd @ Reg(_), rs @ Reg(_), rt @ Reg(_)]) =>
+            vec![basic(if name == "rem" { "div" } else { "divu" }, vec![rs.clone(), rt.clone()]), basic("mfhi", vec![rd.clone()])],
+
+        //three register instructions with an immediate as the last operand
+        (_, [rd @ Reg(_), rs @ Reg(_), E(value)]) if info.map(|i| i.syntax) == Some(Syntax::RdRsRt) => {
+            match (name, immediate_form(name)) {
+                ("sub", _) | ("subu", _) if value.literal().is_some() => {
+                    let negated = E(Expr::constant(-value.literal().unwrap()));
+                    return expand(if name == "sub" { "addi" } else { "addiu" }, vec![rd.clone(), rs.clone(), negated]);
+                }
+                (_, Some(immediate)) => return expand(immediate, ops.clone()),
+                _ => {
+                    let mut out = load_immediate(AT, value)?;
+                    out.push(basic(name, vec![rd.clone(), rs.clone(), AT]));
+                    out
+                }
+            }
+        }
+
+        //immediates too big for 16 bit go through $at
+        (_, [rt @ Reg(_), rs @ Reg(_), E(value)]) if register_form(name).is_some() => {
+            let fits = match info.unwrap().syntax {
+                Syntax::RtRsUimm => fits_unsigned16,
+                _ => fits_signed16,
+            };
+            match value.literal() {
+                Some(v) if !fits(v) => {
+                    let mut out = load_immediate(AT, value)?;
+                    out.push(basic(register_form(name).unwrap(), vec![rt.clone(), rs.clone(), AT]));
+                    out
+                }
+                _ => vec![Basic { info: info.unwrap(), operands: ops.clone() }],
+            }
+        }
+
+        //loads and stores from a label
+        (_, [rt @ Reg(_), address]) if info.map(|i| i.syntax) == Some(Syntax::RtMem) && needs_at(address) => {
+            let (offset, base) = match address {
+                Mem(offset, base) => (offset.clone(), Some(*base)),
+                E(offset) => (offset.clone(), None),
+                _ => unreachable!(),
+            };
+            let mut out = vec![basic("lui", vec![AT, E(offset.with_part(Part::HiAdj))])];
+            if let Some(base) = base {
+                out.push(basic("addu", vec![AT, AT, Reg(base)]));
+            }
+            out.push(Basic { info: info.unwrap(), operands: vec![rt.clone(), Mem(offset.with_part(Part::LoSigned), 1)] });
+            out
+        }
+
+        _ => match info {
+            Some(info) => vec![Basic { info, operands: ops.clone() }],
+            None => return Err(format!("unknown instruction '{}'", name)),
+        },
+    };
+
+    Ok(result)
+}
+
+// set $at by comparing two operands (the second may be an immediate) and branch on it
+fn compare_and_branch(rs: &Operand, rt: &Operand, swap: bool, branch: &str, unsigned: bool, label: &Operand) -> Result<Vec<Basic>, String> {
+    let mut out = vec![];
+    let rt = match rt {
+        Operand::Expr(value) => {
+            out = load_immediate(AT, value)?;
+            AT
+        }
+        other => other.clone(),
+    };
+    let (a, b) = if swap { (rt, rs.clone()) } else { (rs.clone(), rt) };
+
+    out.push(basic(if unsigned { "sltu" } else { "slt" }, vec![AT, a, b]));
+    out.push(basic(branch, vec![AT, ZERO, label.clone()]));
+    Ok(out)
+}
+
+// does a memory operand need to go through $at?
+fn needs_at(address: &Operand) -> bool {
+    match address {
+        Operand::Expr(_) => true,
+        Operand::Mem(offset, _) => offset.literal().map(|v| !fits_signed16(v)).unwrap_or(true),
+        Operand::Reg(_) => false,
+    }
+}
+
+///////////////
+//
+// ENCODING
+//
+///////////////
+
+// encode a basic instruction at `address` with all operands resolved
+fn encode(info: &OpInfo, operands: &[Value], address: u32) -> Result<u32, String> {
+    use Value::{Imm, Mem, Reg};
+
+    let rs = |r: u8| (r as u32) << 21;
+    let rt = |r: u8| (r as u32) << 16;
+    let rd = |r: u8| (r as u32) << 11;
+    let sa = |v: i64| -> Result<u32, String> {
+        if (0..32).contains(&v) { Ok((v as u32) << 6) } else { Err(format!("shift amount {} out of range", v)) }
+    };
+    let simm = |v: i64| -> Result<u32, String> {
+        if fits_signed16(v) { Ok(v as u32 & 0xFFFF) } else { Err(format!("immediate {} doesn't fit into 16 bit signed", v)) }
+    };
+    let uimm = |v: i64| -> Result<u32, String> {
+        if fits_unsigned16(v) { Ok(v as u32) } else { Err(format!("immediate {} doesn't fit into 16 bit unsigned", v)) }
+    };
+    let branch = |target: i64| -> Result<u32, String> {
+        let offset = target - (address as i64 + 4);
+        if offset % 4 != 0 || !fits_signed16(offset >> 2) {
+            return Err(format!("branch target 0x{:08x} out of reach", target));
+        }
+        Ok((offset >> 2) as u32 & 0xFFFF)
+    };
+
+    let fields = match (info.syntax, operands) {
+        (Syntax::None, []) => 0,
+        (Syntax::Code, []) => 0,
+        (Syntax::Code, [Imm(code)]) if (0..0x10_0000).contains(code) => (*code as u32) << 6,
+        (Syntax::RdRsRt, [Reg(d), Reg(s), Reg(t)]) => rd(*d) | rs(*s) | rt(*t),
+        (Syntax::RdRtRs, [Reg(d), Reg(t), Reg(s)]) => rd(*d) | rt(*t) | rs(*s),
+        (Syntax::RdRtSa, [Reg(d), Reg(t), Imm(shift)]) => rd(*d) | rt(*t) | sa(*shift)?,
+        (Syntax::RdRs, [Reg(d), Reg(s)]) => rd(*d) | rt(*d) | rs(*s),
+        (Syntax::RdRt, [Reg(d), Reg(t)]) => rd(*d) | rt(*t),
+        (Syntax::RsRt, [Reg(s), Reg(t)]) => rs(*s) | rt(*t),
+        (Syntax::Rd, [Reg(d)]) => rd(*d),
+        (Syntax::Rs, [Reg(s)]) => rs(*s),
+        (Syntax::RdRsJalr, [Reg(s)]) => rd(31) | rs(*s),
+        (Syntax::RdRsJalr, [Reg(d), Reg(s)]) => rd(*d) | rs(*s),
+        (Syntax::RtRsImm, [Reg(t), Reg(s), Imm(v)]) => rt(*t) | rs(*s) | simm(*v)?,
+        (Syntax::RtRsUimm, [Reg(t), Reg(s), Imm(v)]) => rt(*t) | rs(*s) | uimm(*v)?,
+        (Syntax::RtUimm, [Reg(t), Imm(v)]) => rt(*t) | uimm(if fits_signed16(*v) { *v & 0xFFFF } else { *v })?,
+        (Syntax::RsRtBranch, [Reg(s), Reg(t), Imm(target)]) => rs(*s) | rt(*t) | branch(*target)?,
+        (Syntax::RsBranch, [Reg(s), Imm(target)]) => rs(*s) | branch(*target)?,
+        (Syntax::Jump, [Imm(target)]) => {
+            let target = *target as u32;
+            if target & 3 != 0 || (target & 0xF000_0000) != (address.wrapping_add(4) & 0xF000_0000) {
+                return Err(format!("jump target 0x{:08x} out of reach", target));
+            }
+            (target >> 2) & 0x03FF_FFFF
+        }
+        (Syntax::RtMem, [Reg(t), Mem(offset, base)]) => rt(*t) | rs(*base) | simm(*offset)?,
+        (Syntax::HintMem, [Imm(hint), Mem(offset, base)]) if (0..32).contains(hint) => rt(*hint as u8) | rs(*base) | simm(*offset)?,
+        (Syntax::Mem, [Mem(offset, base)]) => rs(*base) | simm(*offset)?,
+        (Syntax::Cop0, [Reg(t), Reg(d)]) => rt(*t) | rd(*d),
+        (Syntax::Cop0, [Reg(t), Reg(d), Imm(sel)]) if (0..8).contains(sel) => rt(*t) | rd(*d) | *sel as u32,
+        (Syntax::RtOpt, []) => 0,
+        (Syntax::RtOpt, [Reg(t)]) => rt(*t),
+        (Syntax::ExtIns, [Reg(t), Reg(s), Imm(pos), Imm(size)]) => {
+            if !(0..32).contains(pos) || *size < 1 || pos + size > 32 {
+                return Err(format!("bit field {}, {} out of range", pos, size));
+            }
+            let msb = if info.name == "ext" { size - 1 } else { pos + size - 1 };
+            rt(*t) | rs(*s) | rd(msb as u8) | sa(*pos)?
+        }
+        _ => return Err(format!("'{}' expects {}", info.name, info.syntax.usage())),
+    };
+
+    Ok(info.bits | fields)
+}
+
+///////////////
+//
+// ASSEMBLER
+//
+///////////////
+
+// something waiting for the second pass
+enum Pending {
+    Inst(Basic),
+    Word(Expr),
+    Half(Expr),
+    Byte(Expr),
+}
+
+struct Item {
+    line: usize,    //index into the preprocessed lines
+    section: usize,
+    address: u32,
+    pending: Pending,
+}
+
+struct Assembler {
+    sections: Vec<Section>,
+    current: usize,
+    named: HashMap<&'static str, usize>,    //latest section for each segment directive
+    symbols: HashMap<String, u32>,
+    items: Vec<Item>,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        let mut assembler = Assembler {
+            sections: vec![],
+            current: 0,
+            named: HashMap::new(),
+            symbols: HashMap::new(),
+            items: vec![],
+        };
+        assembler.switch_section(".text", None);
+        assembler
+    }
+
+    fn cursor(&self) -> u32 {
+        let section = &self.sections[self.current];
+        section.base.wrapping_add(section.bytes.len() as u32)
+    }
+
+    //jump to a segment, continuing where it left off unless an address is given
+    fn switch_section(&mut self, name: &'static str, address: Option<u32>) {
+        let base = match (address, self.named.get(name)) {
+            (None, Some(&index)) => {
+                self.current = index;
+                return;
+            }
+            (Some(address), _) => address,
+            (None, None) => match name {
+                ".data" => DATA_BASE,
+                ".ktext" => KTEXT_BASE,
+                ".kdata" => KDATA_BASE,
+                _ => TEXT_BASE,
+            },
+        };
+
+        self.sections.push(Section { base, bytes: vec![] });
+        self.current = self.sections.len() - 1;
+        self.named.insert(name, self.current);
+    }
+
+    //reserve space in the current section, the second pass fills it in
+    fn reserve(&mut self, line: usize, size: u32, pending: Option<Pending>) -> Result<(), String> {
+        let address = self.cursor();
+        self.grow(size)?;
+        if let Some(pending) = pending {
+            self.items.push(Item { line, section: self.current, address, pending });
+        }
+        Ok(())
+    }
+
+    fn align(&mut self, alignment: u32) -> Result<(), String> {
+        let padding = (alignment - self.cursor() % alignment) % alignment;
+        self.grow(padding)
+    }
+
+    //add zeroed bytes to the current section, which has to end within the address space
+    fn grow(&mut self, size: u32) -> Result<(), String> {
+        let section = &mut self.sections[self.current];
+        let end = section.base as u64 + section.bytes.len() as u64 + size as u64;
+        if end > 1 << 32 {
+            return Err(format!("section at 0x{:08x} runs past the end of the address space", section.base));
+        }
+        section.bytes.resize(section.bytes.len() + size as usize, 0);
+        Ok(())
+    }
+
+    //data directives align their first value, labels in front of them should point there
+    fn alignment(name: &str) -> Option<u32> {
+        match name {
+            ".word" => Some(4),
+            ".half" => Some(2),
+            _ => None,
+        }
+    }
+
+    //first pass over a single line
+    fn statement(&mut self, line: usize, text: &str) -> Result<(), String> {
+        let tokens = tokenize(text)?;
+        let mut cursor = Cursor::new(&tokens);
+
+        //any number of labels up front
+        let mut labels = vec![];
+        while let (Some(Token::Ident(name)), Some(Token::Punct(':'))) = (cursor.peek().cloned(), cursor.tokens.get(cursor.pos + 1)) {
+            labels.push(name);
+            cursor.pos += 2;
+        }
+
+        let name = match cursor.next() {
+            None => None,
+            Some(Token::Ident(name)) => Some(name.to_lowercase()),
+            Some(token) => return Err(format!("expected an instruction or directive, found {:?}", token)),
+        };
+
+        //bind the labels once the cursor is where the data will go
+        if let Some(alignment) = name.as_deref().and_then(Assembler::alignment) {
+            self.align(alignment)?;
+        }
+        for label in labels {
+            if self.symbols.insert(label.clone(), self.cursor()).is_some() {
+                return Err(format!("label '{}' defined twice", label));
+            }
+        }
+
+        let name = match name {
+            None => return Ok(()),
+            Some(name) => name,
+        };
+
+        if name.starts_with('.') {
+            self.directive(line, &name, &mut cursor)
+        }
+        else {
+            let basics = expand(&name, cursor.operands()?)?;
+            for basic in basics {
+                self.reserve(line, 4, Some(Pending::Inst(basic)))?;
+            }
+            Ok(())
+        }
+    }
+
+    fn directive(&mut self, line: usize, name: &str, cursor: &mut Cursor) -> Result<(), String> {
+        //optional address after a segment directive
+        let address = |cursor: &mut Cursor| -> Result<Option<u32>, String> {
+            if cursor.done() { Ok(None) } else { Ok(Some(cursor.expr()?.literal().ok_or("segment address must be a number")? as u32)) }
+        };
+
+        match name {
+            ".text" => self.switch_section(".text", address(cursor)?),
+            ".data" => self.switch_section(".data", address(cursor)?),
+            ".ktext" => self.switch_section(".ktext", address(cursor)?),
+            ".kdata" => self.switch_section(".kdata", address(cursor)?),
+            ".word" | ".half" | ".byte" => {
+                let (size, make): (u32, fn(Expr) -> Pending) = match name {
+                    ".word" => (4, Pending::Word),
+                    ".half" => (2, Pending::Half),
+                    _ => (1, Pending::Byte),
+                };
+                self.align(size)?;
+                while !cursor.done() {
+                    let value = cursor.expr()?;
+                    //MARS allows value:count to repeat a value
+                    let count = if cursor.eat(':') { cursor.expr()?.literal().ok_or("repeat count must be a number")? } else { 1 };
+                    for _ in 0..count {
+                        self.reserve(line, size, Some(make(value.clone())))?;
+                    }
+                    cursor.eat(',');
+                }
+            }
+            ".ascii" | ".asciiz" => {
+                if cursor.done() {
+                    return Err(format!("{} expects a string", name));
+                }
+                while let Some(token) = cursor.peek() {
+                    let bytes = match token {
+                        Token::Str(bytes) => bytes.clone(),
+                        token => return Err(format!("expected a string, found {:?}", token)),
+                    };
+                    cursor.pos += 1;
+                    let start = self.sections[self.current].bytes.len();
+                    self.reserve(line, bytes.len() as u32 + (name == ".asciiz") as u32, None)?;
+                    self.sections[self.current].bytes[start..start + bytes.len()].copy_from_slice(&bytes);
+                    cursor.eat(',');
+                }
+            }
+            ".space" => {
+                let size = cursor.expr()?.literal().ok_or(".space needs a number")?;
+                if !(0..=0xFFFF_FFFF).contains(&size) {
+                    return Err(format!("can't reserve {} bytes", size));
+                }
+                self.reserve(line, size as u32, None)?;
+            }
+            ".align" => {
+                let power = cursor.expr()?.literal().ok_or(".align needs a number")?;
+                if !(0..=16).contains(&power) {
+                    return Err(format!("can't align to 2^{}", power));
+                }
+                self.align(1 << power)?;
+            }
+            //only one file, so there's nothing to export or import
+            ".globl" | ".global" | ".extern" | ".set" => (),
+            _ => return Err(format!("unknown directive '{}'", name)),
+        }
+
+        if !cursor.done() {
+            return Err(format!("unexpected operands after {}", name));
+        }
+
+        Ok(())
+    }
+
+    //second pass: fill in everything that needed the symbol table
+    fn resolve(&mut self, lines: &[SourceLine]) -> Result<(), AsmError> {
+        for item in &self.items {
+            let error = |message: String| lines[item.line].error(message);
+
+            let bytes = match &item.pending {
+                Pending::Inst(basic) => basic.encode(item.address, &self.symbols).map_err(error)?.to_le_bytes().to_vec(),
+                Pending::Word(expr) => (expr.resolve(&self.symbols).map_err(error)? as u32).to_le_bytes().to_vec(),
+                Pending::Half(expr) => (expr.resolve(&self.symbols).map_err(error)? as u16).to_le_bytes().to_vec(),
+                Pending::Byte(expr) => vec![expr.resolve(&self.symbols).map_err(error)? as u8],
+            };
+
+            let section = &mut self.sections[item.section];
+            let start = (item.address - section.base) as usize;
+            section.bytes[start..start + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        Ok(())
+    }
+}
+
+// assemble a source file and everything it includes
+pub fn assemble_file(path: &str) -> Result<Program, AsmError> {
+    let lines = Preprocessor::new().run_file(path)?;
+    let mut assembler = Assembler::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        assembler.statement(index, &line.text).map_err(|message| line.error(message))?;
+    }
+
+    assembler.resolve(&lines)?;
+
+    Ok(Program {
+        sections: assembler.sections.into_iter().filter(|section| !section.bytes.is_empty()).collect(),
+    })
+}
+
+// encode a single instruction at `address`, e.g. for patching memory from the REPL.
+// Pseudo instructions work too, so the result may be more than one word long.
+pub fn assemble_line(text: &str, address: u32) -> Result<Vec<u32>, String> {
+    let tokens = tokenize(text)?;
+    let mut cursor = Cursor::new(&tokens);
+
+    let name = match cursor.next() {
+        Some(Token::Ident(name)) if !name.starts_with('.') => name.to_lowercase(),
+        Some(Token::Ident(name)) => return Err(format!("directives like '{}' aren't supported here", name)),
+        Some(token) => return Err(format!("expected an instruction, found {:?}", token)),
+        None => return Err("expected an instruction".to_string()),
+    };
+    if cursor.peek() == Some(&Token::Punct(':')) {
+        return Err("labels aren't supported here".to_string());
+    }
+
+    //there is no symbol table, so everything has to be a number
+    let no_symbols = HashMap::new();
+    let mut words = vec![];
+
+    for (index, basic) in expand(&name, cursor.operands()?)?.iter().enumerate() {
+        words.push(basic.encode(address.wrapping_add(4 * index as u32), &no_symbols)?);
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::{disassemble, Style};
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    //assemble_file wants a path, so every test source gets its own file
+    fn assemble(source: &str) -> Result<Program, AsmError> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("rem-asm-{}-{}.asm", std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+        std::fs::write(&path, source).unwrap();
+        let program = assemble_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        program
+    }
+
+    fn section(program: &Program, base: u32) -> &[u8] {
+        &program.sections.iter().find(|section| section.base == base).unwrap().bytes
+    }
+
+    fn words(bytes: &[u8]) -> Vec<u32> {
+        bytes.chunks(4).map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect()
+    }
+
+    fn error(source: &str) -> String {
+        match assemble(source) {
+            Ok(_) => panic!("assembled without an error"),
+            Err(error) => error.message,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "
+            main:   addiu $sp, $sp, -8
+                    sw $ra, 4($sp)
+                    lui $t0, 0x1001
+                    lw $t1, 0($t0)
+                    sll $t2, $t1, 3
+                    mult $t1, $t2
+                    mflo $v0
+                    beq $t1, $zero, main
+                    nop
+                    jal main
+                    jr $ra
+                    syscall
+        ";
+        let program = assemble(source).unwrap();
+        let text = words(section(&program, TEXT_BASE));
+        assert_eq!(text.len(), 12);
+
+        for (index, &word) in text.iter().enumerate() {
+            let address = TEXT_BASE + 4 * index as u32;
+            let line = disassemble(word, address, Style::Mars);
+            assert_eq!(assemble_line(&line, address).unwrap(), vec![word], "{}", line);
+        }
+
+        assert_eq!(disassemble(text[0], TEXT_BASE, Style::Mars), "addiu $sp, $sp, -8");
+        assert_eq!(disassemble(text[7], TEXT_BASE + 28, Style::Mars), "beq $t1, $zero, 0x00400000");
+        assert_eq!(disassemble(text[9], TEXT_BASE + 36, Style::Mars), "jal 0x00400000");
+    }
+
+    #[test]
+    fn labels_point_at_aligned_data() {
+        let source = "
+            .data
+            b:  .byte 1
+            w:  .word 7
+            h2: .byte 2
+            h:  .half 3
+            .text
+                la $t0, w
+                la $t1, h
+        ";
+        let program = assemble(source).unwrap();
+        let data = section(&program, DATA_BASE);
+        assert_eq!(&data[4..8], &7u32.to_le_bytes());
+        assert_eq!(&data[10..12], &3u16.to_le_bytes());
+
+        //la is lui + ori with the label's address
+        let text = words(section(&program, TEXT_BASE));
+        assert_eq!(text[1] & 0xFFFF, 0x0004);
+        assert_eq!(text[3] & 0xFFFF, 0x000A);
+    }
+
+    #[test]
+    fn strings() {
+        let program = assemble(".data\n.ascii \"ab\", \"c\"\n.asciiz \"d\"").unwrap();
+        assert_eq!(section(&program, DATA_BASE), b"abcd\0");
+    }
+
+    #[test]
+    fn strings_only() {
+        assert_eq!(error(".data\n.ascii \"a\", 5"), "expected a string, found Num(5)");
+        assert_eq!(error(".data\n.asciiz 5"), "expected a string, found Num(5)");
+        assert_eq!(error(".data\n.asciiz"), ".asciiz expects a string");
+    }
+
+    #[test]
+    fn space() {
+        let program = assemble(".data\n.space 3\n.byte 9").unwrap();
+        assert_eq!(section(&program, DATA_BASE), &[0, 0, 0, 9]);
+
+        assert_eq!(error(".data\n.space -1"), "can't reserve -1 bytes");
+        assert_eq!(error(".data 0xFFFFFFF0\n.space 32"), "section at 0xfffffff0 runs past the end of the address space");
+        assert!(assemble(".data 0xFFFFFFF0\n.space 16").is_ok());
+    }
+}
@@ -0,0 +1,265 @@
+// Basic block execution engine.
+//
+// Instead of fetching, decoding and dispatching one word per step, straight line code up to and
+// including the delay slot of the next branch is translated once into a list of handler/operand
+// pairs and then run back to back. Blocks remember the blocks they were left for, so hot loops
+// skip the block lookup entirely.
+//
+// The engine never has to know what an instruction does: after every step it checks that the PC
+// still points to the next instruction of the block and leaves the block as soon as it doesn't,
+// which covers taken branches as well as anything else that redirects execution.
+//
+// Built with the jit feature, blocks that ran often enough are compiled to host code as well.
+
+use crate::cpu::{CPU, Handler};
+use crate::instruction::{Instruction, Op};
+use crate::ram::PAGE_BITS;
+
+#[cfg(feature = "jit")]
+use crate::jit::{Jit, NativeBlock};
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+// longest block we translate in one go
+const MAX_BLOCK_LENGTH: usize = 64;
+
+struct ThreadedOp {
+    handler: Handler,
+    inst: Instruction,
+    store: bool,    //might change the code of the block itself
+}
+
+struct Block {
+    start: u32,
+    physical: u32,                      //where start was in physical memory when it got translated
+    version: u32,                       //version of the block's page it was translated from
+    ops: Vec<ThreadedOp>,
+    exits: [Option<(u32, usize)>; 2],   //blocks we continued with before, (start address, index)
+    #[cfg(feature = "jit")]
+    runs: u32,
+    #[cfg(feature = "jit")]
+    native: Option<NativeBlock>,
+}
+
+pub struct BlockEngine {
+    blocks: Vec<Block>,
+    index: HashMap<u32, usize>,     //block start address to position in blocks
+    pub translations: u64,
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
+    #[cfg(feature = "jit")]
+    pub jit_threshold: u32,         //runs of a block before it gets compiled
+}
+
+impl BlockEngine {
+    //construct an engine without any translated code
+    pub fn new() -> BlockEngine {
+        BlockEngine {
+            blocks: vec![],
+            index: HashMap::new(),
+            translations: 0,
+            #[cfg(feature = "jit")]
+            jit: Jit::new().map_err(|e| println!("JIT unavailable: {}", e)).ok(),
+            #[cfg(feature = "jit")]
+            jit_threshold: 16,
+        }
+    }
+
+    //switch compiling hot blocks on or off
+    #[cfg(feature = "jit")]
+    pub fn toggle_jit(&mut self) {
+        if self.jit.take().is_none() {
+            self.jit = Jit::new().map_err(|e| println!("JIT unavailable: {}", e)).ok();
+        }
+        for block in &mut self.blocks {
+            block.runs = 0;
+            block.native = None;
+        }
+        println!("JIT {}", if self.jit.is_some() { "enabled" } else { "disabled" });
+    }
+
+    //number of blocks compiled to host code so far
+    #[cfg(feature = "jit")]
+    pub fn compiled(&self) -> u64 {
+        self.jit.as_ref().map_or(0, |jit| jit.compiled)
+    }
+
+    //run (at most) n instructions, returns how many were actually executed
+    pub fn run(&mut self, cpu: &mut CPU, n: u64) -> u64 {
+        let first = cpu.instructions;
+        let last = first + n;
+        let mut previous: Option<usize> = None;
+
+        while cpu.instructions < last && !cpu.stopped {
+            //interrupts are taken between blocks, which end early where the timer goes off
+            //so it interrupts the same instruction as when single stepping
+            cpu.poll_interrupts();
+            if cpu.stopped {
+                break;
+            }
+            let until = last.min(cpu.instructions.saturating_add(cpu.instructions_to_interrupt()));
+            let current = self.find(cpu, previous);
+
+            #[cfg(feature = "jit")]
+            if self.run_native(cpu, current, until) {
+                previous = Some(current);
+                continue;
+            }
+
+            let block = &self.blocks[current];
+            let mut expected = block.start;
+
+            for op in &block.ops {
+                //left the block through a branch, an exception or something else
+                if cpu.PC != expected || cpu.instructions >= until {
+                    break;
+                }
+
+                cpu.step(op.handler, op.inst);
+                expected = expected.wrapping_add(4);
+
+                //self modifying code, the rest of the block might be stale now
+                if op.store && cpu.MEM.page_version(block.physical) != block.version {
+                    break;
+                }
+            }
+
+            previous = Some(current);
+        }
+
+        cpu.instructions - first
+    }
+
+    //run a block as host code once it is hot, returns false if it has to be interpreted this time
+    #[cfg(feature = "jit")]
+    fn run_native(&mut self, cpu: &mut CPU, current: usize, last: u64) -> bool {
+        let jit = match &mut self.jit {
+            Some(jit) => jit,
+            None => return false,
+        };
+
+        let block = &mut self.blocks[current];
+        block.runs = block.runs.saturating_add(1);
+        if block.runs == self.jit_threshold {
+            let insts: Vec<Instruction> = block.ops.iter().map(|op| op.inst).collect();
+            block.native = jit.compile(block.start, block.physical, block.version, &insts);
+        }
+
+        //compiled code always runs the whole block, can't start in a delay slot and doesn't check fetches
+        match &block.native {
+            Some(native) if last - cpu.instructions >= block.ops.len() as u64 && cpu.next_PC == block.start.wrapping_add(4)
+                && cpu.may_run_native(block.start) => {
+                native.run(cpu);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    //get the block starting at the PC, translating it if needed
+    fn find(&mut self, cpu: &mut CPU, previous: Option<usize>) -> usize {
+        let pc = cpu.PC;
+        let physical = cpu.physical(pc);
+        let version = cpu.MEM.page_version(physical);
+
+        //a block is stale once its page was written to or the address maps somewhere else
+        let valid = |block: &Block| block.version == version && block.physical == physical;
+
+        //fast path: we went from the previous block to this one before
+        if let Some(previous) = previous {
+            for exit in self.blocks[previous].exits.iter().flatten() {
+                if exit.0 == pc && valid(&self.blocks[exit.1]) {
+                    return exit.1;
+                }
+            }
+        }
+
+        let current = match self.index.get(&pc) {
+            Some(&current) if valid(&self.blocks[current]) => current,
+            Some(&stale) => {
+                self.blocks[stale] = self.translate(cpu, pc);
+                stale
+            }
+            None => {
+                let block = self.translate(cpu, pc);
+                self.blocks.push(block);
+                self.index.insert(pc, self.blocks.len() - 1);
+                self.blocks.len() - 1
+            }
+        };
+
+        //chain the previous block to this one, replacing the older of the two exits
+        if let Some(previous) = previous {
+            let exits = &mut self.blocks[previous].exits;
+            exits[1] = exits[0];
+            exits[0] = Some((pc, current));
+        }
+
+        current
+    }
+
+    //translate the straight line code starting at an address
+    fn translate(&mut self, cpu: &mut CPU, start: u32) -> Block {
+        self.translations += 1;
+
+        let mut ops = vec![];
+        let mut address = start;
+        let mut in_delay_slot = false;
+
+        loop {
+            let inst = cpu.fetch(address);
+            ops.push(ThreadedOp { handler: CPU::handler(inst.op), inst, store: is_store(inst.op) });
+            address = address.wrapping_add(4);
+
+            //blocks end after the delay slot of a branch and never cross a page, they also end where
+            //an interrupt might have to be taken
+            if in_delay_slot || inst.enables_interrupts() || ops.len() >= MAX_BLOCK_LENGTH || address >> PAGE_BITS != start >> PAGE_BITS {
+                break;
+            }
+            in_delay_slot = inst.has_delay_slot();
+        }
+
+        let physical = cpu.physical(start);
+        Block {
+            start,
+            physical,
+            version: cpu.MEM.page_version(physical),
+            ops,
+            exits: [None, None],
+            #[cfg(feature = "jit")]
+            runs: 0,
+            #[cfg(feature = "jit")]
+            native: None,
+        }
+    }
+
+    //compare the single step interpreter against a new block engine, running the same n instructions
+    //on two machines in the same state
+    pub fn benchmark(stepped: &mut CPU, threaded: &mut CPU, n: u64) {
+        stepped.trace = false;
+        threaded.trace = false;
+
+        let started = Instant::now();
+        let first = stepped.instructions;
+        while stepped.instructions - first < n && !stepped.stopped {
+            stepped.clock();
+        }
+        let single = stepped.instructions - first;
+        let single_time = started.elapsed().as_secs_f64();
+
+        let mut engine = BlockEngine::new();
+        let started = Instant::now();
+        let executed = engine.run(threaded, n);
+        let threaded_time = started.elapsed().as_secs_f64();
+
+        println!("Single step:\t{} instructions in {:.3}s\t{:.2} MIPS", single, single_time, single as f64 / single_time / 1e6);
+        println!("Block engine:\t{} instructions in {:.3}s\t{:.2} MIPS ({} blocks translated)", executed, threaded_time, executed as f64 / threaded_time / 1e6, engine.translations);
+        #[cfg(feature = "jit")]
+        println!("\t\t{} blocks compiled to host code", engine.compiled());
+    }
+}
+
+fn is_store(op: Option<Op>) -> bool {
+    matches!(op, Some(Op::SB) | Some(Op::SH) | Some(Op::SW) | Some(Op::SWL) | Some(Op::SWR) | Some(Op::SC))
+}
@@ -0,0 +1,362 @@
+// Branch predictor simulation.
+//
+// Predictors only watch: every branch and jump the CPU executes is shown to all of them, first to
+// get a prediction and then with the actual outcome so they can learn from it. Whether they were
+// right is tracked per predictor, both overall and per branch site.
+//
+// Predictors that only guess a direction (static, bimodal, gshare) are judged on the direction,
+// the target of a taken branch is assumed to be known after decode. The BTB has to get the target
+// right as well, since it is what fetch would use before anything is decoded.
+
+use crate::disassembler::{self, Style};
+use crate::instruction::{Instruction, Op};
+
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BranchKind {
+    Conditional,    //beq, bne, bltz, ...
+    Direct,         //j, jal
+    Return,         //jr $ra
+    Indirect,       //jr and jalr through any other register
+}
+
+// one executed branch or jump
+pub struct Branch {
+    pub address: u32,
+    pub kind: BranchKind,
+    pub links: bool,    //writes a return address (jal, jalr, bltzal, bgezal)
+    pub taken: bool,
+    pub target: u32,    //where a taken branch goes
+}
+
+pub struct Prediction {
+    pub taken: bool,
+    pub target: Option<u32>,    //None if the predictor doesn't predict targets
+}
+
+impl Prediction {
+    fn matches(&self, branch: &Branch) -> bool {
+        self.taken == branch.taken && (!branch.taken || self.target.is_none_or(|target| target == branch.target))
+    }
+}
+
+pub trait Predictor {
+    fn name(&self) -> String;
+    //guess the outcome of a branch before it executes
+    fn predict(&self, address: u32, kind: BranchKind) -> Prediction;
+    //learn from what actually happened
+    fn update(&mut self, branch: &Branch);
+}
+
+// 2 bit saturating counters, 0 and 1 predict not taken, 2 and 3 taken
+fn train(counter: &mut u8, taken: bool) {
+    *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+}
+
+// conditional branches are never taken, jumps always are
+pub struct StaticNotTaken;
+
+impl Predictor for StaticNotTaken {
+    fn name(&self) -> String {
+        "static not-taken".to_string()
+    }
+
+    fn predict(&self, _address: u32, kind: BranchKind) -> Prediction {
+        Prediction { taken: kind != BranchKind::Conditional, target: None }
+    }
+
+    fn update(&mut self, _branch: &Branch) {}
+}
+
+// a table of 2 bit counters indexed by the branch address
+pub struct Bimodal {
+    counters: Vec<u8>,
+}
+
+impl Bimodal {
+    pub fn new(entries: usize) -> Bimodal {
+        Bimodal { counters: vec![1; entries] }
+    }
+
+    fn index(&self, address: u32) -> usize {
+        (address >> 2) as usize % self.counters.len()
+    }
+}
+
+impl Predictor for Bimodal {
+    fn name(&self) -> String {
+        format!("bimodal ({} entries)", self.counters.len())
+    }
+
+    fn predict(&self, address: u32, kind: BranchKind) -> Prediction {
+        let taken = kind != BranchKind::Conditional || self.counters[self.index(address)] >= 2;
+        Prediction { taken, target: None }
+    }
+
+    fn update(&mut self, branch: &Branch) {
+        if branch.kind == BranchKind::Conditional {
+            let index = self.index(branch.address);
+            train(&mut self.counters[index], branch.taken);
+        }
+    }
+}
+
+// 2 bit counters indexed by the branch address xor the global history of conditional branches
+pub struct Gshare {
+    counters: Vec<u8>,
+    history: usize,
+    history_bits: u32,
+}
+
+impl Gshare {
+    pub fn new(history_bits: u32) -> Gshare {
+        Gshare { counters: vec![1; 1 << history_bits], history: 0, history_bits }
+    }
+
+    fn index(&self, address: u32) -> usize {
+        ((address >> 2) as usize ^ self.history) & (self.counters.len() - 1)
+    }
+}
+
+impl Predictor for Gshare {
+    fn name(&self) -> String {
+        format!("gshare ({} bit history)", self.history_bits)
+    }
+
+    fn predict(&self, address: u32, kind: BranchKind) -> Prediction {
+        let taken = kind != BranchKind::Conditional || self.counters[self.index(address)] >= 2;
+        Prediction { taken, target: None }
+    }
+
+    fn update(&mut self, branch: &Branch) {
+        if branch.kind == BranchKind::Conditional {
+            let index = self.index(branch.address);
+            train(&mut self.counters[index], branch.taken);
+            self.history = ((self.history << 1) | branch.taken as usize) & (self.counters.len() - 1);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BtbEntry {
+    address: u32,
+    target: u32,
+    counter: u8,
+}
+
+// a direct mapped branch target buffer with a 2 bit counter per entry, plus a return address
+// stack for jr $ra. Branches that miss in the BTB are predicted not taken.
+pub struct BtbRas {
+    entries: Vec<Option<BtbEntry>>,
+    stack: Vec<u32>,
+    depth: usize,
+}
+
+impl BtbRas {
+    pub fn new(entries: usize, depth: usize) -> BtbRas {
+        BtbRas { entries: vec![None; entries], stack: vec![], depth }
+    }
+
+    fn index(&self, address: u32) -> usize {
+        (address >> 2) as usize % self.entries.len()
+    }
+}
+
+impl Predictor for BtbRas {
+    fn name(&self) -> String {
+        format!("BTB ({} entries) + RAS ({} deep)", self.entries.len(), self.depth)
+    }
+
+    fn predict(&self, address: u32, kind: BranchKind) -> Prediction {
+        if kind == BranchKind::Return {
+            if let Some(&target) = self.stack.last() {
+                return Prediction { taken: true, target: Some(target) };
+            }
+        }
+
+        match self.entries[self.index(address)] {
+            Some(entry) if entry.address == address => {
+                let taken = kind != BranchKind::Conditional || entry.counter >= 2;
+                Prediction { taken, target: Some(entry.target) }
+            }
+            _ => Prediction { taken: false, target: None },
+        }
+    }
+
+    fn update(&mut self, branch: &Branch) {
+        if branch.kind == BranchKind::Return {
+            self.stack.pop();
+        }
+        if branch.links && branch.taken {
+            //the oldest return address falls off the bottom of a full stack
+            if self.stack.len() == self.depth {
+                self.stack.remove(0);
+            }
+            self.stack.push(branch.address.wrapping_add(8));
+        }
+
+        let index = self.index(branch.address);
+        match &mut self.entries[index] {
+            Some(entry) if entry.address == branch.address => {
+                train(&mut entry.counter, branch.taken);
+                if branch.taken {
+                    entry.target = branch.target;
+                }
+            }
+            //only taken branches get allocated, a not taken one is predicted fine by a miss
+            slot if branch.taken => *slot = Some(BtbEntry { address: branch.address, target: branch.target, counter: 2 }),
+            _ => (),
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct SiteStats {
+    executed: u64,
+    taken: u64,
+    correct: u64,
+}
+
+// a predictor together with how well it did
+struct Tracked {
+    predictor: Box<dyn Predictor>,
+    correct: u64,
+    sites: HashMap<u32, SiteStats>,
+}
+
+pub struct BranchPredictors {
+    tracked: Vec<Tracked>,
+    branches: u64,
+    instructions: HashMap<u32, u32>,    //the instruction word at every branch site, for printing
+}
+
+impl BranchPredictors {
+    pub fn new(predictors: Vec<Box<dyn Predictor>>) -> BranchPredictors {
+        let tracked = predictors.into_iter().map(|predictor| Tracked { predictor, correct: 0, sites: HashMap::new() }).collect();
+        BranchPredictors { tracked, branches: 0, instructions: HashMap::new() }
+    }
+
+    //the four standard predictors with table sizes suitable for small programs
+    pub fn standard() -> BranchPredictors {
+        BranchPredictors::new(vec![
+            Box::new(StaticNotTaken),
+            Box::new(Bimodal::new(1024)),
+            Box::new(Gshare::new(10)),
+            Box::new(BtbRas::new(256, 8)),
+        ])
+    }
+
+    //show an executed branch to every predictor, next_pc is where the CPU goes after the delay slot
+    pub fn observe(&mut self, address: u32, inst: &Instruction, next_pc: u32) {
+        let (kind, links) = match inst.op {
+            Some(Op::J) => (BranchKind::Direct, false),
+            Some(Op::JAL) => (BranchKind::Direct, true),
+            Some(Op::JR) if inst.rs == 31 => (BranchKind::Return, false),
+            Some(Op::JR) => (BranchKind::Indirect, false),
+            Some(Op::JALR) => (BranchKind::Indirect, true),
+            Some(Op::BLTZAL) | Some(Op::BGEZAL) => (BranchKind::Conditional, true),
+            _ => (BranchKind::Conditional, false),
+        };
+        let fall_through = address.wrapping_add(8);
+        let taken = kind != BranchKind::Conditional || next_pc != fall_through;
+        let target = if taken { next_pc } else { inst.branch_target(address) };
+        let branch = Branch { address, kind, links, taken, target };
+
+        self.branches += 1;
+        self.instructions.insert(address, inst.word);
+
+        for tracked in &mut self.tracked {
+            let correct = tracked.predictor.predict(address, kind).matches(&branch);
+            tracked.predictor.update(&branch);
+
+            tracked.correct += correct as u64;
+            let site = tracked.sites.entry(address).or_default();
+            site.executed += 1;
+            site.taken += taken as u64;
+            site.correct += correct as u64;
+        }
+    }
+
+    //print the overall accuracy of every predictor and the n most executed branch sites
+    pub fn print_stats(&self, n: usize) {
+        println!("{} branches and jumps executed", self.branches);
+        for tracked in &self.tracked {
+            println!("  {:<32}{:>8.2}% correct", tracked.predictor.name(), percent(tracked.correct, self.branches));
+        }
+
+        let first = match self.tracked.first() {
+            Some(first) if n > 0 => first,
+            _ => return,
+        };
+
+        let mut sites: Vec<(&u32, &SiteStats)> = first.sites.iter().collect();
+        sites.sort_by_key(|(address, site)| (std::cmp::Reverse(site.executed), **address));
+
+        println!("\nAddress     Executed  Taken   {}  Instruction", (1..=self.tracked.len()).map(|i| format!("  P{}  ", i)).collect::<Vec<_>>().join(" "));
+        for (&address, site) in sites.into_iter().take(n) {
+            let accuracies: Vec<String> = self.tracked.iter()
+                .map(|tracked| format!("{:>5.1}%", percent(tracked.sites[&address].correct, site.executed)))
+                .collect();
+            println!("0x{:0>8X}  {:>8}  {:>5.1}%  {}  {}", address, site.executed, percent(site.taken, site.executed), accuracies.join(" "),
+                disassembler::disassemble(self.instructions[&address], address, Style::Mars));
+        }
+        for (index, tracked) in self.tracked.iter().enumerate() {
+            println!("P{} = {}", index + 1, tracked.predictor.name());
+        }
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 { 0.0 } else { 100.0 * part as f64 / whole as f64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_line;
+
+    const LOOP: u32 = 0x0040_0010;
+
+    //a loop closing branch taken 9 times and then falling through, rounds times over
+    fn run_loop(predictors: &mut BranchPredictors, rounds: usize) {
+        let inst = Instruction::decode(assemble_line("bne $t0, $zero, 0x00400000", LOOP).unwrap()[0]);
+        for _ in 0..rounds {
+            for iteration in 0..10 {
+                let next_pc = if iteration < 9 { 0x0040_0000 } else { LOOP + 8 };
+                predictors.observe(LOOP, &inst, next_pc);
+            }
+        }
+    }
+
+    fn correct(predictors: &BranchPredictors) -> Vec<u64> {
+        predictors.tracked.iter().map(|tracked| tracked.correct).collect()
+    }
+
+    #[test]
+    fn loop_accuracy() {
+        let mut predictors = BranchPredictors::standard();
+        run_loop(&mut predictors, 5);
+        assert_eq!(predictors.branches, 50);
+
+        //static only gets the exits right, bimodal and the BTB only the first iteration and the exits wrong
+        let correct = correct(&predictors);
+        assert_eq!(correct[0], 5);
+        assert_eq!(correct[1], 44);
+        assert_eq!(correct[3], 44);
+        assert_eq!(predictors.tracked[1].sites[&LOOP].taken, 45);
+    }
+
+    #[test]
+    fn gshare_learns_the_exit() {
+        let mut predictors = BranchPredictors::new(vec![Box::new(Bimodal::new(1024)), Box::new(Gshare::new(10))]);
+        run_loop(&mut predictors, 50);
+
+        //with 10 branches of history the exit is predictable, bimodal keeps missing it
+        let before = correct(&predictors);
+        run_loop(&mut predictors, 1);
+        let after = correct(&predictors);
+        assert_eq!(after[0] - before[0], 9);
+        assert_eq!(after[1] - before[1], 10);
+    }
+}
@@ -0,0 +1,300 @@
+// Instruction and data cache simulator.
+//
+// Only tags are modelled: the data itself always comes from RAM, so the caches never change what
+// a program does, just how many cycles it would take. Every miss (and every write back of a dirty
+// line) costs a configurable penalty that ends up in the cycle count.
+
+// which line of a set to throw out on a miss
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    WriteBack,      //writes allocate lines and only reach memory when a dirty line is evicted
+    WriteThrough,   //writes go to memory right away through a write buffer and don't allocate
+}
+
+#[derive(Clone, Copy)]
+pub struct CacheConfig {
+    pub size: u32,          //total bytes
+    pub ways: u32,
+    pub line: u32,          //bytes per line
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    pub miss_penalty: u32,  //cycles to fetch a line from (or write one back to) memory
+}
+
+impl CacheConfig {
+    //what a microAptiv typically gets: 16KB, 4 way, 16 byte lines, LRU, write back
+    pub fn micro_aptiv() -> CacheConfig {
+        CacheConfig { size: 16 * 1024, ways: 4, line: 16, replacement: Replacement::Lru, write_policy: WritePolicy::WriteBack, miss_penalty: 20 }
+    }
+
+    //parse "size ways line [lru/fifo/random] [wb/wt] [penalty]" on top of the defaults
+    pub fn parse(words: &[&str]) -> Result<CacheConfig, String> {
+        let mut config = CacheConfig::micro_aptiv();
+        let number = |word: &str| word.parse::<u32>().map_err(|_| format!("'{}' is not a number", word));
+
+        for (index, word) in words.iter().enumerate() {
+            match (index, word.to_lowercase().as_str()) {
+                (0, _) => config.size = number(word)?,
+                (1, _) => config.ways = number(word)?,
+                (2, _) => config.line = number(word)?,
+                (_, "lru") => config.replacement = Replacement::Lru,
+                (_, "fifo") => config.replacement = Replacement::Fifo,
+                (_, "random") => config.replacement = Replacement::Random,
+                (_, "wb") => config.write_policy = WritePolicy::WriteBack,
+                (_, "wt") => config.write_policy = WritePolicy::WriteThrough,
+                (_, _) => config.miss_penalty = number(word)?,
+            }
+        }
+
+        if !config.line.is_power_of_two() || config.line < 4 {
+            return Err("the line size has to be a power of two of at least 4 bytes".to_string());
+        }
+        let set_bytes = match config.ways.checked_mul(config.line) {
+            Some(bytes) if config.ways > 0 => bytes,
+            _ => return Err("there has to be at least one way, and ways * line size has to fit in 32 bits".to_string()),
+        };
+        if !config.size.is_multiple_of(set_bytes) || !(config.size / set_bytes).is_power_of_two() {
+            return Err("size / (ways * line size) has to be a power of two".to_string());
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    tag: u32,
+    valid: bool,
+    dirty: bool,
+    stamp: u64,     //last use for LRU, time of the fill for FIFO
+}
+
+#[derive(Default)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub read_misses: u64,
+    pub write_misses: u64,
+    pub write_backs: u64,       //dirty lines written back on eviction
+    pub memory_writes: u64,     //writes passed straight through to memory
+}
+
+pub struct Cache {
+    pub config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    time: u64,
+    random: u32,    //xorshift state for random replacement
+    pub stats: CacheStats,
+}
+
+impl Cache {
+    //construct an empty (all lines invalid) cache
+    pub fn new(config: CacheConfig) -> Cache {
+        let sets = config.size / (config.ways * config.line);
+        let empty = Line { tag: 0, valid: false, dirty: false, stamp: 0 };
+
+        Cache {
+            config,
+            sets: vec![vec![empty; config.ways as usize]; sets as usize],
+            time: 0,
+            random: 0x2545_F491,
+            stats: CacheStats::default(),
+        }
+    }
+
+    //look up an address, returns the stall cycles of a miss or None on a hit
+    pub fn access(&mut self, address: u32, write: bool) -> Option<u32> {
+        self.time += 1;
+        if write { self.stats.writes += 1 } else { self.stats.reads += 1 }
+
+        let line_number = address / self.config.line;
+        let set_count = self.sets.len() as u32;
+        let (index, tag) = ((line_number % set_count) as usize, line_number / set_count);
+        let write_through = self.config.write_policy == WritePolicy::WriteThrough;
+
+        if write && write_through {
+            self.stats.memory_writes += 1;
+        }
+
+        //hit
+        let time = self.time;
+        let replacement = self.config.replacement;
+        if let Some(line) = self.sets[index].iter_mut().find(|line| line.valid && line.tag == tag) {
+            if replacement == Replacement::Lru {
+                line.stamp = time;
+            }
+            line.dirty |= write && !write_through;
+            return None;
+        }
+
+        //miss
+        if write { self.stats.write_misses += 1 } else { self.stats.read_misses += 1 }
+
+        //write through caches don't allocate on writes, the write buffer takes care of them
+        if write && write_through {
+            return Some(0);
+        }
+
+        let victim = self.victim(index);
+        let line = &mut self.sets[index][victim];
+        let mut penalty = self.config.miss_penalty;
+        if line.valid && line.dirty {
+            self.stats.write_backs += 1;
+            penalty += self.config.miss_penalty;
+        }
+        *line = Line { tag, valid: true, dirty: write, stamp: time };
+
+        Some(penalty)
+    }
+
+    //pick the way to replace in a set, empty ways first
+    fn victim(&mut self, index: usize) -> usize {
+        let set = &self.sets[index];
+        if let Some(way) = set.iter().position(|line| !line.valid) {
+            return way;
+        }
+
+        match self.config.replacement {
+            Replacement::Lru | Replacement::Fifo => (0..set.len()).min_by_key(|&way| set[way].stamp).unwrap_or(0),
+            Replacement::Random => {
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 17;
+                self.random ^= self.random << 5;
+                self.random as usize % set.len()
+            }
+        }
+    }
+
+    //print the configuration and hit/miss statistics
+    pub fn print_stats(&self, name: &str) {
+        let config = &self.config;
+        let stats = &self.stats;
+        let accesses = stats.reads + stats.writes;
+        let misses = stats.read_misses + stats.write_misses;
+
+        println!("{}: {} bytes, {} way, {} byte lines, {}, {}, {} cycle miss penalty", name, config.size, config.ways, config.line,
+            match config.replacement { Replacement::Lru => "LRU", Replacement::Fifo => "FIFO", Replacement::Random => "random" },
+            match config.write_policy { WritePolicy::WriteBack => "write back", WritePolicy::WriteThrough => "write through" },
+            config.miss_penalty);
+        println!("  Reads:\t{}\t({} misses)", stats.reads, stats.read_misses);
+        println!("  Writes:\t{}\t({} misses)", stats.writes, stats.write_misses);
+        if accesses > 0 {
+            println!("  Hit rate:\t{:.2}%", 100.0 * (accesses - misses) as f64 / accesses as f64);
+        }
+        match config.write_policy {
+            WritePolicy::WriteBack => println!("  Write backs:\t{}", stats.write_backs),
+            WritePolicy::WriteThrough => println!("  Memory writes:\t{}", stats.memory_writes),
+        }
+    }
+}
+
+// the instruction and the data cache
+pub struct Caches {
+    pub instruction: Cache,
+    pub data: Cache,
+    pub fetches: u64,       //instructions fetched since the caches were turned on
+    pub stall_cycles: u64,  //all miss penalties so far
+    pending: u64,           //miss penalties the pipeline model hasn't accounted for yet
+}
+
+impl Caches {
+    pub fn new(instruction: CacheConfig, data: CacheConfig) -> Caches {
+        Caches { instruction: Cache::new(instruction), data: Cache::new(data), fetches: 0, stall_cycles: 0, pending: 0 }
+    }
+
+    //the accesses return the stall cycles of a miss or None on a hit
+    pub fn fetch(&mut self, address: u32) -> Option<u64> {
+        self.fetches += 1;
+        let penalty = self.instruction.access(address, false);
+        self.stall(penalty)
+    }
+
+    pub fn load(&mut self, address: u32) -> Option<u64> {
+        let penalty = self.data.access(address, false);
+        self.stall(penalty)
+    }
+
+    pub fn store(&mut self, address: u32) -> Option<u64> {
+        let penalty = self.data.access(address, true);
+        self.stall(penalty)
+    }
+
+    fn stall(&mut self, penalty: Option<u32>) -> Option<u64> {
+        let cycles = penalty? as u64;
+        self.stall_cycles += cycles;
+        self.pending += cycles;
+        Some(cycles)
+    }
+
+    //hand out the stall cycles collected since the last call
+    pub fn take_pending(&mut self) -> u64 {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //two sets of two 16 byte lines, so addresses 32 bytes apart share a set
+    fn empty(replacement: Replacement, write_policy: WritePolicy) -> Cache {
+        Cache::new(CacheConfig { size: 64, ways: 2, line: 16, replacement, write_policy, miss_penalty: 10 })
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let mut cache = empty(Replacement::Lru, WritePolicy::WriteBack);
+        assert_eq!(cache.access(0x100, false), Some(10));
+        assert_eq!(cache.access(0x10C, false), None);      //same line
+        assert_eq!(cache.access(0x110, false), Some(10));  //next line, other set
+        assert_eq!(cache.access(0x100, true), None);
+        assert_eq!((cache.stats.reads, cache.stats.writes), (3, 1));
+        assert_eq!((cache.stats.read_misses, cache.stats.write_misses), (2, 0));
+    }
+
+    #[test]
+    fn lru_and_fifo() {
+        for (replacement, survivor) in [(Replacement::Lru, 0x000), (Replacement::Fifo, 0x020)] {
+            let mut cache = empty(replacement, WritePolicy::WriteBack);
+            cache.access(0x000, false);
+            cache.access(0x020, false);
+            cache.access(0x000, false);     //only LRU cares that this line was used again
+            cache.access(0x040, false);     //evicts one of them
+            assert_eq!(cache.access(survivor, false), None);
+        }
+    }
+
+    #[test]
+    fn write_back_and_write_through() {
+        let mut cache = empty(Replacement::Lru, WritePolicy::WriteBack);
+        assert_eq!(cache.access(0x000, true), Some(10));
+        cache.access(0x020, false);
+        assert_eq!(cache.access(0x040, false), Some(20));  //the dirty line goes back to memory first
+        assert_eq!(cache.stats.write_backs, 1);
+
+        let mut cache = empty(Replacement::Lru, WritePolicy::WriteThrough);
+        assert_eq!(cache.access(0x000, true), Some(0));    //no allocation on a write miss
+        assert_eq!(cache.access(0x000, false), Some(10));
+        assert_eq!(cache.access(0x000, true), None);
+        assert_eq!((cache.stats.memory_writes, cache.stats.write_backs), (2, 0));
+    }
+
+    #[test]
+    fn parse() {
+        let config = CacheConfig::parse(&["1024", "2", "32", "fifo", "wt", "7"]).unwrap();
+        assert_eq!((config.size, config.ways, config.line, config.miss_penalty), (1024, 2, 32, 7));
+        assert!(config.replacement == Replacement::Fifo && config.write_policy == WritePolicy::WriteThrough);
+
+        assert!(CacheConfig::parse(&["1024", "2", "24"]).is_err());
+        assert!(CacheConfig::parse(&["1024", "0", "32"]).is_err());
+        assert!(CacheConfig::parse(&["1024", "3", "32"]).is_err());
+        assert!(CacheConfig::parse(&["1024", "2147483648", "16"]).is_err());
+    }
+}
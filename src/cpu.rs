@@ -1,437 +1,1795 @@
-use crate::ram::RAM;
-use crate::exceptionprocessor::ExceptionProcessor;
-
-use std::convert::TryInto;
-
-pub struct CPU {
-    GPR: [u32; 32],     //register number 0 - 31
-    HI: u32,            //register number 32
-    LO: u32,            //register number 33
-    PC: u32,            //register number 34
-
-    MEM: RAM,
-    //TODO: add FPU
-
-    //TODO: implement the Exception coprocessor properly
-    CP0: ExceptionProcessor
-}
-
-impl CPU {
-    //construct a new cpu
-    pub fn new(ram: RAM) -> CPU {
-        CPU {GPR: [0; 32], HI: 0, LO: 0, PC: 0, MEM: ram, CP0: ExceptionProcessor::new()}
-    }
-
-    //do a clock cycle
-    pub fn clock(&mut self) {
-        //fetch next instruction word
-        let word: u32 = self.MEM.read_word(self.PC);
-        println!("Fetching next instruction from address {:#X}", self.PC);
-
-        //split it into opcode and arguments
-        let opcode: u8 = ((word & 0xFC00_0000) >> 26).try_into().unwrap();    //6 bits long
-        let rs: u8 = ((word & 0x03E0_0000) >> 21).try_into().unwrap();        //5 bits long
-        let rt: u8 = ((word & 0x001F_0000) >> 16).try_into().unwrap();        //5 bits long
-        let imm: u16 = ((word & 0x0000_FFFF)).try_into().unwrap();            //16 bits long
-
-        //SPECIAL opcodes
-        if opcode == 0x00 {
-            //hack the immediate field down into a third register & the new opcode
-            let rd: u8 = ((imm & 0xF800) >> 11).try_into().unwrap();
-            let special_opcode: u8 = ((imm & 0x003F)).try_into().unwrap();
-
-            //debug printing
-            println!("Found Opcode 0x00 {:#X} with registers {}, {}, {}", special_opcode, rs, rt, rd);
-
-            match special_opcode {
-                0x0A => self.MOVZ(rs, rt, rd),
-                0x0B => self.MOVN(rs, rt, rd),
-                0x24 => self.AND(rs, rt, rd),
-                0x2A => self.SLT(rs, rt, rd),
-                0x08 => self.JR(rs),
-                0x20 => self.ADD(rs, rt, rd),
-                0x21 => self.ADDU(rs, rt, rd),
-                0x22 => self.SUB(rs, rt, rd),
-                0x23 => self.SUBU(rs, rt, rd),
-                0x25 => self.OR(rs, rt, rd),
-                0x26 => self.XOR(rs, rt, rd),
-                0x27 => self.NOR(rs, rt, rd),
-                0x0C => self.SYSCALL(),
-                _ => ()
-            }
-        } 
-        //another set of opcodes, SPECIAL2
-        else if opcode == 0x1C {
-            //same as with opcode 0x00
-            let rd: u8 = ((imm & 0xF800) >> 11).try_into().unwrap();
-            let special_opcode: u8 = ((imm & 0x003F)).try_into().unwrap();
-
-            //debug printing
-            println!("Found Opcode 0x1C {:#X} with registers {}, {}, {}", special_opcode, rs, rt, rd);
-
-            match special_opcode {
-                0x1C => self.CLZ(rs, rt, rd),
-                0x21 => self.CLO(rs, rt, rd),
-                _ => ()
-            }
-        }
-        //normal opcodes here
-        else {
-            //debug printing
-            println!("Found Opcode {:#X} with registers {}, {} and immediate {}", opcode, rs, rt, imm);
-
-            match opcode {
-                0x03 => self.JAL((word & 0x03FF_FFFF).try_into().unwrap()),
-                0x07 => self.BGTZ(rs, imm),
-                0x08 => self.ADDI(rs, rt, imm),
-                0x09 => self.ADDIU(rs, rt, imm),
-                0x0A => self.SLTI(rs, rt, imm),
-                0x0B => self.SLTIU(rs, rt, imm),
-                0x0C => self.ANDI(rs, rt, imm),
-                0x0D => self.ORI(rs, rt, imm),
-                0x0E => self.XORI(rs, rt, imm),
-                0x0F => self.LUI(rt, imm),
-                0x20 => self.LB(rs, rt, imm),
-                0x21 => self.LH(rs, rt, imm),
-                0x23 => self.LW(rs, rt, imm),
-                0x2B => self.SW(rs, rt, imm),
-                _ => ()
-            }
-        }
-
-        //increment the program counter by 32 bit (4 * 8 addressable bit)
-        self.PC += 4;
-    }
-
-    //print all kinds of information about the CPU
-    pub fn print_reg(&self, format_hex: bool) {
-        println!("\t----- PROCESSOR STATE -----\t");
-        
-        if format_hex {
-            println!("Registers:");
-
-            for i in 1..=31 {
-                println!("${:0>2}: 0x{:0>8X}", i, self.GPR[i]);
-            }
-        }
-        else {
-            println!("Registers:");
-
-            for i in 1..=31 {
-                println!("${:0>2}: {:0>8}", i, self.GPR[i]);
-            }
-        }
-        
-        println!("\nHI/LO: {}/{}\n
-                  \rProgram Counter: {:#X}",
-                self.HI, self.LO, self.PC);
-    }
-    
-    //print a portion of the main memory
-    pub fn print_mem(&self, start: u32, end: u32) {
-        self.MEM.print_mem(start, end);
-    }
-
-    //print the current instruction +- an offset in whole instructions in binary
-    pub fn print_instruction(&self, offset: i16) {
-        println!("{:0>32b}", self.MEM.read_word(self.PC.wrapping_add((offset * 4)as u32)));
-    }
-
-    //reset the cpu to a known state
-    pub fn reset(&mut self) {
-        self.GPR = [0; 32];         //null all registers - not needed but nice
-        self.PC = 0x0040_0000;      //.text segment base address
-        self.GPR[29] = 0x7fffeffc;  //stack pointer $sp base address
-
-    }
-
-    //read data from a register
-    fn read_reg(&self, number: u8) -> u32 {
-        match number {
-            0       => 0,   //register 0 is hardwired to logic 0
-            1..=31   => self.GPR[number as usize],
-            32      => self.HI,
-            33      => self.LO,
-            34      => self.PC,
-            _       => 0 //error handle later?
-        }
-    }
-
-    //write data to a register
-    fn write_reg(&mut self, number: u8, value: u32) {
-        match number {
-            1..=31   => self.GPR[number as usize] = value,
-            34      => self.PC = value,
-            _       => ()
-        }
-    }
-
-    ///////////////
-    //
-    //
-    // INSTRUCTIONS
-    // 
-    // 
-    ///////////////
-
-    #[allow(non_snake_case)]
-    fn AND(&mut self, rs: u8, rt: u8, rd: u8) {
-        self.write_reg(rd, self.read_reg(rs) & self.read_reg(rt))
-    }
-    
-    #[allow(non_snake_case)]
-    fn ANDI(&mut self, rs: u8, rt: u8, imm: u16) {
-        self.write_reg(rt, self.read_reg(rs) & ((imm as u32) << 16))
-    }
-
-    #[allow(non_snake_case)]
-    fn OR(&mut self, rs: u8, rt: u8, rd: u8) {
-        self.write_reg(rd, self.read_reg(rs) | self.read_reg(rt));
-    }
-
-    #[allow(non_snake_case)]
-    fn ORI(&mut self, rs: u8, rt: u8, imm: u16) {
-        self.write_reg(rt, self.read_reg(rs) | (imm as u32));
-    }
-
-    #[allow(non_snake_case)]
-    fn NOR(&mut self, rs: u8, rt: u8, rd: u8) {
-        self.write_reg(rd, !(self.read_reg(rs) | self.read_reg(rt)));
-    }
-
-    #[allow(non_snake_case)]
-    fn XOR(&mut self, rs: u8, rt: u8, rd: u8) {
-        self.write_reg(rd, self.read_reg(rs) ^ self.read_reg(rt));
-    }
-
-    #[allow(non_snake_case)]
-    fn XORI(&mut self, rs: u8, rt: u8, imm: u16) {
-        self.write_reg(rt, self.read_reg(rs) ^ (imm as u32));
-    }
-
-    #[allow(non_snake_case)]
-    fn SLT(&mut self, rs: u8, rt: u8, rd: u8) {
-        self.write_reg(rd, if self.read_reg(rs) < self.read_reg(rt) { 1 } else { 0 });
-    }
-
-    #[allow(non_snake_case)]
-    fn SLTI(&mut self, rs: u8, rt: u8, imm: u16) {
-        //sign extend the immediate
-        let signed_imm = imm as i16 as i32;
-        self.write_reg(rt, if (self.read_reg(rs) as i32) < (signed_imm) { 1 } else { 0 });
-    }
-
-    #[allow(non_snake_case)]
-    fn JR(&mut self, rs: u8) {
-        self.write_reg(34, self.read_reg(rs) - 4);
-    }
-
-    #[allow(non_snake_case)]
-    fn ADDU(&mut self, rs: u8, rt: u8, rd: u8) {
-        let (result, _overflow_flag) = self.read_reg(rs).overflowing_add(self.read_reg(rt));
-
-        self.write_reg(rd, result);
-    }
-
-    #[allow(non_snake_case)]
-    fn ADDIU(&mut self, rs: u8, rt: u8, imm: u16) {
-        //sign extend the immediate
-        let signed_imm = imm as i16 as i32;
-
-        let (result, _overflow_flag) = self.read_reg(rs).overflowing_add(signed_imm as u32);
-
-        self.write_reg(rt, result);
-    }
-
-    #[allow(non_snake_case)]
-    fn CLZ(&mut self, rs: u8, _rt: u8, rd: u8) {
-        //in the original design rt and rd have to be equal!
-        self.write_reg(rd, self.read_reg(rs).count_ones());
-    }
-
-    #[allow(non_snake_case)]
-    fn CLO(&mut self, rs: u8, _rt: u8, rd: u8) {
-        //in the original design rt and rd have to be equal!
-        self.write_reg(rd, self.read_reg(rs).count_zeros());
-    }
-
-    #[allow(non_snake_case)]
-    fn LUI(&mut self, rt: u8, imm: u16) {
-        self.write_reg(rt, (imm as u32) << 16);
-    }
-
-    #[allow(non_snake_case)]
-    fn SYSCALL(&mut self) {
-        println!("System call registered! Too bad I don't handle exceptions ye(e)t."); //TODO: implement this
-    }
-    
-    #[allow(non_snake_case)]
-    fn LB(&mut self, base: u8, rt: u8, imm: u16) {
-        //sign extend the immediate
-        let signed_imm = imm as i16 as i32;
-        //compute the address as a sum of i32, then cast back to u32
-        let address = ((self.read_reg(base) as i32) + signed_imm) as u32;
-
-        //read a byte as u8, then cast to i8 and i32 to sign extend to i32, then back to u32 to write it into a register
-        self.write_reg(rt, self.MEM.read_byte(address) as i8 as i32 as u32);
-    }
-
-    #[allow(non_snake_case)]
-    fn LH(&mut self, base: u8, rt: u8, imm: u16) {
-        //sign extend the immediate
-        let signed_imm = imm as i16 as i32;
-        //compute the address as a sum of i32, then cast back to u32
-        let address = ((self.read_reg(base) as i32) + signed_imm) as u32;
-
-        //TODO: throw an exception if the address isn't aligned properly, LSB != 0 => Address Error exception
-        if address % 2 != 0 {
-            self.CP0.throw_exception();
-        }
-
-        //read a byte as u16, then cast it to i16 and i32 to sign extend to i32, then back to u32 to write it into a register
-        self.write_reg(rt, self.MEM.read_half(address) as i16 as i32 as u32);
-    }
-
-    #[allow(non_snake_case)]
-    fn LW(&mut self, base: u8, rt: u8, imm: u16) { 
-        //sign extend the immediate
-        let signed_imm = imm as i16 as i32;
-        //compute the address as a sum of i32, then cast back to u32
-        let address = ((self.read_reg(base) as i32) + signed_imm) as u32;
-
-        //TODO: throw an exception if the address isn't aligned properly, 2 LSB != 0 => Address Error exception
-        if address % 4 != 0 {
-            self.CP0.throw_exception();
-        }
-
-        //read a word and write it into a register
-        self.write_reg(rt, self.MEM.read_word(address));
-    }
-
-    #[allow(non_snake_case)]
-    fn BGTZ(&mut self, rs: u8, imm: u16) {
-        //execute the branch delay slot
-        println!("Executing branch delay slot:");
-        self.PC += 4;
-        self.clock();
-
-        //interpret the immediate as signed and lshift it by 2 because instructions are 4-aligned
-        let signed_imm: i32 = ((imm as i16) << 2) as i32; 
-
-        //if the register value is > 0 add the offset to the PC
-        if self.read_reg(rs) > 0 {
-            //debug prints!
-            println!("Attempting to add {} to the current PC {}", signed_imm, self.PC);
-
-            //off by two instructions, because of the branch delay slot and the PC increment at the end of clock()
-            self.PC = ((self.PC as i32 + signed_imm) - 8) as u32;
-        }
-    }
-
-    #[allow(non_snake_case)]
-    fn ADD(&mut self, rs: u8, rt: u8, rd: u8) {
-        let (result, overflow_flag) = (self.read_reg(rs) as i32).overflowing_add(self.read_reg(rt) as i32);
-
-        if overflow_flag {
-            //TODO: Call CP0 that we have an Integer Overflow exception
-            self.CP0.throw_exception();
-        }
-        else {
-            self.write_reg(rd, result as u32);
-        }
-    }
-
-    #[allow(non_snake_case)]
-    fn ADDI(&mut self, rs: u8, rt: u8, imm: u16) {
-        let signed_imm = imm as i16 as i32;
-
-        let (result, overflow_flag) = (self.read_reg(rs) as i32).overflowing_add(signed_imm as i32);
-
-        if overflow_flag {
-            //TODO: Call CP0 that we have an Integer Overflow exception
-            self.CP0.throw_exception();
-        }
-        else {
-            self.write_reg(rt, result as u32);
-        }
-    }
-
-    #[allow(non_snake_case)]
-    fn SUB(&mut self, rs: u8, rt: u8, rd: u8) {
-        let (result, overflow_flag) = (self.read_reg(rs) as i32).overflowing_sub(self.read_reg(rt) as i32);
-
-        if overflow_flag {
-            //TODO: Call CP0 that we have an Integer Overflow exception
-            self.CP0.throw_exception();
-        }
-        else {
-            self.write_reg(rd, result as u32);
-        }
-    }
-
-    #[allow(non_snake_case)]
-    fn SUBU(&mut self, rs: u8, rt: u8, rd: u8) {
-        let (result, _overflow_flag) = (self.read_reg(rs) as i32).overflowing_sub(self.read_reg(rt) as i32);
-
-        self.write_reg(rd, result as u32);
-
-    }
-
-    #[allow(non_snake_case)]
-    fn JAL(&mut self, instr_index: u32) {
-        //execute the branch delay slot
-        println!("Executing branch delay slot:");
-        self.PC += 4;
-        self.clock();
-
-        //store the return address in the $ra register
-        self.write_reg(31, self.PC + 4);
-
-        //isolate the upper 2 bits of the PC
-        let pc_part = self.PC & 0xC000_000;
-
-        //left shift the index as instructions are 4 aligned
-        let instr_index = instr_index << 2;
-
-        //update the program counter to the new value - 8 to account for the PC increment in clock() and the branch delay slot
-        self.PC = pc_part & instr_index; 
-    }
-
-    #[allow(non_snake_case)]
-    fn SW(&mut self, base: u8, rt: u8, offset: u16) {
-         //sign extend the offset
-        let signed_offset = offset as i16 as i32;
-        //compute the address as a sum of i32, then cast back to u32
-        let address = ((self.read_reg(base) as i32) + signed_offset) as u32;
-
-        //TODO: throw an exception if the address isn't aligned properly, 2 LSB != 0 => Address Error exception
-        //there are also all kinds of other exceptions that can occur here but who the hell knows what TLB Refill means
-        if address % 4 != 0 {
-            self.CP0.throw_exception();
-        }
-
-        //store the contents of rt in memory
-        self.MEM.write_word(address, self.read_reg(rt));
-    }
-
-    #[allow(non_snake_case)]
-    fn MOVN(&mut self, rs: u8, rt: u8, rd: u8) {
-        if self.read_reg(rt) != 0 {
-            self.write_reg(rd, self.read_reg(rs));
-        }
-    }
-
-    #[allow(non_snake_case)]
-    fn MOVZ(&mut self, rs: u8, rt: u8, rd: u8) {
-        if self.read_reg(rt) == 0 {
-            self.write_reg(rd, self.read_reg(rs));
-        }
-    }
-
-    #[allow(non_snake_case)]
-    fn SLTIU(&mut self, rs: u8, rt: u8, imm: u16) {
-        let signed_imm = imm as i16 as i32;
-        //sign extend the immediate first, but then do an unsigned comparison
-        self.write_reg(rt, if self.read_reg(rs) < (signed_imm as u32) { 1 } else { 0 })
-    }
-}
\ No newline at end of file
+use crate::ram::RAM;
+use crate::exceptionprocessor::{Event, Exception, ExceptionProcessor};
+use crate::mmu::{Access, Fault, Translation};
+use crate::mpu::{Mpu, Region, MPU_REGIONS};
+use crate::disassembler::{self, Style};
+
+use crate::decodecache::DecodeCache;
+use crate::pipeline::Pipeline;
+use crate::tomasulo::Tomasulo;
+use crate::cache::{Cache, CacheConfig, Caches};
+use crate::branchpredictor::BranchPredictors;
+use crate::timing::{Class, Cost, Profile, Timing};
+use crate::eic::InterruptController;
+use crate::defaulthandler::{Action, DefaultHandler};
+use crate::instruction::{Instruction, Op, REGISTER_NAMES};
+
+pub struct CPU {
+    pub(crate) GPR: [u32; 32],     //register number 0 - 31
+    pub(crate) HI: u32,            //register number 32
+    pub(crate) LO: u32,            //register number 33
+    pub(crate) PC: u32,            //register number 34
+    pub(crate) next_PC: u32,       //where to go after PC, differs from PC + 4 in a branch delay slot
+    executing: u32,                 //address of the instruction being executed, PC has moved on already
+    pub(crate) delay_slot: u32,    //address of the delay slot of the last branch or jump
+
+    pub(crate) MEM: RAM,
+    //TODO: add FPU
+
+    //TODO: implement the Exception coprocessor properly
+    CP0: ExceptionProcessor,
+
+    decoded: DecodeCache,   //instructions decoded so far, invalidated by writes to their page
+    pipeline: Option<Pipeline>,     //cycle level timing model, clock advances it instead of executing directly
+    pipelined: bool,                //the pipeline model is on (it's taken out of pipeline while it runs)
+    tomasulo: Option<Tomasulo>,     //out-of-order model, executes instructions only as they commit
+    out_of_order: bool,             //the out-of-order model is on, it does its own latencies
+    caches: Option<Caches>,         //I- and D-cache timing model
+    predictors: Option<BranchPredictors>,   //branch predictors watching every branch and jump
+    timing: Timing,         //issue and latency of every instruction class
+    pub trace: bool,        //print every executed instruction
+    default_handler: DefaultHandler,    //takes exceptions the program has no handler for
+    pub(crate) stopped: bool,   //the program ran into an exception it can't go on from, reset to run it again
+    pub instructions: u64,  //number of instructions executed since the last reset
+    extra_cycles: u64,      //cycles spent on top of one per instruction: stalls, cache misses, multi cycle issue
+}
+
+// an instruction implementation as a plain function pointer, so decoded instructions can be threaded together
+pub type Handler = fn(&mut CPU, Instruction);
+
+impl CPU {
+    //construct a new cpu that translates addresses one way or another
+    pub fn new(ram: RAM, translation: Translation) -> CPU {
+        CPU {GPR: [0; 32], HI: 0, LO: 0, PC: 0, next_PC: 4, executing: 0, delay_slot: u32::MAX, MEM: ram, CP0: ExceptionProcessor::new(translation), decoded: DecodeCache::new(), pipeline: None, tomasulo: None, out_of_order: false, caches: None, predictors: None, pipelined: false, timing: Timing::new(Profile::ideal()), trace: true, default_handler: DefaultHandler::new(), stopped: false, instructions: 0, extra_cycles: 0}
+    }
+
+    //do a clock cycle
+    pub fn clock(&mut self) {
+        if self.stopped {
+            return;
+        }
+        self.poll_interrupts();
+        //the built-in handler may have stopped the program at the interrupt
+        if self.stopped {
+            return;
+        }
+
+        //in pipeline mode a cycle doesn't necessarily start a new instruction
+        if let Some(mut pipeline) = self.pipeline.take() {
+            pipeline.cycle(self);
+            self.pipeline = Some(pipeline);
+            return;
+        }
+        if let Some(mut tomasulo) = self.tomasulo.take() {
+            tomasulo.cycle(self);
+            self.tomasulo = Some(tomasulo);
+            return;
+        }
+
+        //fetch the next instruction, decoded already if we've seen it before
+        let inst = self.fetch(self.PC);
+
+        //debug printing
+        if self.trace {
+            println!("Fetching next instruction from address {:#X}", self.PC);
+            println!("Executing {}", disassembler::disassemble(inst.word, self.PC, Style::Mars));
+        }
+
+        self.step(CPU::handler(inst.op), inst);
+    }
+
+    //fetch and decode the instruction at an address. If the fetch can't be translated this decodes
+    //whatever is there, step raises the exception before it could run
+    pub(crate) fn fetch(&mut self, address: u32) -> Instruction {
+        let physical = self.physical(address);
+        self.decoded.fetch(&self.MEM, physical)
+    }
+
+    //translate an access of 1, 2 or 4 bytes, which has to be aligned to its size. Misaligned ones are
+    //address errors before anything else is checked
+    #[inline]
+    fn translate(&self, address: u32, size: u32, access: Access) -> Result<u32, Fault> {
+        if address & (size - 1) != 0 {
+            return Err(Fault { exception: access.exception(Exception::AddressLoad, Exception::AddressStore), refill: false });
+        }
+        self.CP0.translate(address, access)
+    }
+
+    //the physical address an instruction fetch from an address goes to, itself if it would fail
+    pub(crate) fn physical(&self, address: u32) -> u32 {
+        self.CP0.translate(address, Access::Fetch).unwrap_or(address)
+    }
+
+    //can compiled code starting at an address run? It doesn't check its fetches, so only if the
+    //first one works and there is no MPU region boundary it could run across. It doesn't tell the
+    //performance counters or the timing profile what it did either, so not while they count
+    #[cfg(feature = "jit")]
+    pub(crate) fn may_run_native(&mut self, address: u32) -> bool {
+        self.translate(address, 4, Access::Fetch).is_ok() && self.CP0.mpu().is_none() && !self.CP0.counting() && self.timing.is_ideal()
+    }
+
+    //execute an instruction that sits at the current PC
+    pub(crate) fn step(&mut self, handler: Handler, inst: Instruction) {
+        //skip all the bookkeeping when there's nothing watching, it is most of the cost of a step
+        let observed = self.caches.is_some() || self.predictors.is_some() || self.CP0.counting() || !self.timing.is_ideal();
+
+        //the fetch itself can fail, from a misaligned PC, in user mode or with a TLB. Then nothing
+        //was executed, so nothing gets counted or timed either
+        let address = self.PC;
+        let fetched = self.translate(address, 4, Access::Fetch);
+        if observed && fetched.is_ok() {
+            self.before_step(&inst);
+        }
+
+        //advance first, so branches can overwrite next_PC to take effect after their delay slot
+        self.executing = address;
+        self.PC = self.next_PC;
+        self.next_PC = self.next_PC.wrapping_add(4);
+
+        if let Err(fault) = fetched {
+            return self.fault(fault, address);
+        }
+
+        self.instructions += 1;
+        handler(self, inst);
+
+        if inst.has_delay_slot() {
+            self.delay_slot = address.wrapping_add(4);
+        }
+
+        if observed {
+            self.after_step(address, &inst);
+        }
+    }
+
+    //fetch and issue costs of an instruction that is about to execute
+    fn before_step(&mut self, inst: &Instruction) {
+        //like the D-cache it is physically indexed and tagged
+        let physical = self.physical(self.PC);
+        if let Some(caches) = &mut self.caches {
+            if let Some(penalty) = caches.fetch(physical) {
+                self.CP0.event(Event::ICacheMisses, 1);
+                self.elapse(penalty, true);
+            }
+        }
+
+        if self.out_of_order {
+            return;
+        }
+
+        let (stall, issue) = self.timing.account(inst, self.cycles(), self.pipelined);
+        if stall > 0 {
+            self.elapse(stall, true);
+        }
+        if issue > 0 {
+            self.elapse(issue, false);
+        }
+    }
+
+    //count an executed instruction and show branches to the predictors
+    fn after_step(&mut self, address: u32, inst: &Instruction) {
+        self.CP0.event(Event::Cycles, 1);
+        self.CP0.event(Event::Instructions, 1);
+        if inst.has_delay_slot() {
+            self.CP0.event(Event::Branches, 1);
+            if let Some(predictors) = &mut self.predictors {
+                predictors.observe(address, inst, self.next_PC);
+            }
+        }
+    }
+
+    //look up the implementation of an operation
+    pub fn handler(op: Option<Op>) -> Handler {
+        match op {
+            //SPECIAL
+            Some(Op::SLL) => |cpu, i| cpu.SLL(i.rt, i.rd, i.sa),
+            Some(Op::SRL) => |cpu, i| cpu.SRL(i.rt, i.rd, i.sa),
+            Some(Op::ROTR) => |cpu, i| cpu.ROTR(i.rt, i.rd, i.sa),
+            Some(Op::SRA) => |cpu, i| cpu.SRA(i.rt, i.rd, i.sa),
+            Some(Op::SLLV) => |cpu, i| cpu.SLLV(i.rs, i.rt, i.rd),
+            Some(Op::SRLV) => |cpu, i| cpu.SRLV(i.rs, i.rt, i.rd),
+            Some(Op::ROTRV) => |cpu, i| cpu.ROTRV(i.rs, i.rt, i.rd),
+            Some(Op::SRAV) => |cpu, i| cpu.SRAV(i.rs, i.rt, i.rd),
+            Some(Op::JR) => |cpu, i| cpu.JR(i.rs),
+            Some(Op::JALR) => |cpu, i| cpu.JALR(i.rs, i.rd),
+            Some(Op::MOVZ) => |cpu, i| cpu.MOVZ(i.rs, i.rt, i.rd),
+            Some(Op::MOVN) => |cpu, i| cpu.MOVN(i.rs, i.rt, i.rd),
+            Some(Op::SYSCALL) => |cpu, _| cpu.SYSCALL(),
+            Some(Op::BREAK) => |cpu, _| cpu.BREAK(),
+            Some(Op::MFHI) => |cpu, i| cpu.MFHI(i.rd),
+            Some(Op::MTHI) => |cpu, i| cpu.MTHI(i.rs),
+            Some(Op::MFLO) => |cpu, i| cpu.MFLO(i.rd),
+            Some(Op::MTLO) => |cpu, i| cpu.MTLO(i.rs),
+            Some(Op::MULT) => |cpu, i| cpu.MULT(i.rs, i.rt),
+            Some(Op::MULTU) => |cpu, i| cpu.MULTU(i.rs, i.rt),
+            Some(Op::DIV) => |cpu, i| cpu.DIV(i.rs, i.rt),
+            Some(Op::DIVU) => |cpu, i| cpu.DIVU(i.rs, i.rt),
+            Some(Op::ADD) => |cpu, i| cpu.ADD(i.rs, i.rt, i.rd),
+            Some(Op::ADDU) => |cpu, i| cpu.ADDU(i.rs, i.rt, i.rd),
+            Some(Op::SUB) => |cpu, i| cpu.SUB(i.rs, i.rt, i.rd),
+            Some(Op::SUBU) => |cpu, i| cpu.SUBU(i.rs, i.rt, i.rd),
+            Some(Op::AND) => |cpu, i| cpu.AND(i.rs, i.rt, i.rd),
+            Some(Op::OR) => |cpu, i| cpu.OR(i.rs, i.rt, i.rd),
+            Some(Op::XOR) => |cpu, i| cpu.XOR(i.rs, i.rt, i.rd),
+            Some(Op::NOR) => |cpu, i| cpu.NOR(i.rs, i.rt, i.rd),
+            Some(Op::SLT) => |cpu, i| cpu.SLT(i.rs, i.rt, i.rd),
+            Some(Op::SLTU) => |cpu, i| cpu.SLTU(i.rs, i.rt, i.rd),
+            Some(Op::TGE) => |cpu, i| cpu.trap((cpu.read_reg(i.rs) as i32) >= (cpu.read_reg(i.rt) as i32)),
+            Some(Op::TGEU) => |cpu, i| cpu.trap(cpu.read_reg(i.rs) >= cpu.read_reg(i.rt)),
+            Some(Op::TLT) => |cpu, i| cpu.trap((cpu.read_reg(i.rs) as i32) < (cpu.read_reg(i.rt) as i32)),
+            Some(Op::TLTU) => |cpu, i| cpu.trap(cpu.read_reg(i.rs) < cpu.read_reg(i.rt)),
+            Some(Op::TEQ) => |cpu, i| cpu.trap(cpu.read_reg(i.rs) == cpu.read_reg(i.rt)),
+            Some(Op::TNE) => |cpu, i| cpu.trap(cpu.read_reg(i.rs) != cpu.read_reg(i.rt)),
+
+            //REGIMM
+            Some(Op::BLTZ) => |cpu, i| cpu.BLTZ(i.rs, i.imm),
+            Some(Op::BGEZ) => |cpu, i| cpu.BGEZ(i.rs, i.imm),
+            Some(Op::BLTZAL) => |cpu, i| cpu.BLTZAL(i.rs, i.imm),
+            Some(Op::BGEZAL) => |cpu, i| cpu.BGEZAL(i.rs, i.imm),
+            Some(Op::SYNCI) => |cpu, i| cpu.SYNCI(i.rs, i.imm),
+
+            //normal opcodes
+            Some(Op::J) => |cpu, i| cpu.J(i.target),
+            Some(Op::JAL) => |cpu, i| cpu.JAL(i.target),
+            Some(Op::BEQ) => |cpu, i| cpu.BEQ(i.rs, i.rt, i.imm),
+            Some(Op::BNE) => |cpu, i| cpu.BNE(i.rs, i.rt, i.imm),
+            Some(Op::BLEZ) => |cpu, i| cpu.BLEZ(i.rs, i.imm),
+            Some(Op::BGTZ) => |cpu, i| cpu.BGTZ(i.rs, i.imm),
+            Some(Op::ADDI) => |cpu, i| cpu.ADDI(i.rs, i.rt, i.imm),
+            Some(Op::ADDIU) => |cpu, i| cpu.ADDIU(i.rs, i.rt, i.imm),
+            Some(Op::SLTI) => |cpu, i| cpu.SLTI(i.rs, i.rt, i.imm),
+            Some(Op::SLTIU) => |cpu, i| cpu.SLTIU(i.rs, i.rt, i.imm),
+            Some(Op::ANDI) => |cpu, i| cpu.ANDI(i.rs, i.rt, i.imm),
+            Some(Op::ORI) => |cpu, i| cpu.ORI(i.rs, i.rt, i.imm),
+            Some(Op::XORI) => |cpu, i| cpu.XORI(i.rs, i.rt, i.imm),
+            Some(Op::LUI) => |cpu, i| cpu.LUI(i.rt, i.imm),
+
+            //SPECIAL2
+            Some(Op::MADD) => |cpu, i| cpu.MADD(i.rs, i.rt),
+            Some(Op::MADDU) => |cpu, i| cpu.MADDU(i.rs, i.rt),
+            Some(Op::MUL) => |cpu, i| cpu.MUL(i.rs, i.rt, i.rd),
+            Some(Op::MSUB) => |cpu, i| cpu.MSUB(i.rs, i.rt),
+            Some(Op::MSUBU) => |cpu, i| cpu.MSUBU(i.rs, i.rt),
+            Some(Op::CLZ) => |cpu, i| cpu.CLZ(i.rs, i.rt, i.rd),
+            Some(Op::CLO) => |cpu, i| cpu.CLO(i.rs, i.rt, i.rd),
+
+            //SPECIAL3
+            Some(Op::EXT) => |cpu, i| cpu.EXT(i.rs, i.rt, i.rd, i.sa),
+            Some(Op::INS) => |cpu, i| cpu.INS(i.rs, i.rt, i.rd, i.sa),
+            Some(Op::WSBH) => |cpu, i| cpu.WSBH(i.rt, i.rd),
+            Some(Op::SEB) => |cpu, i| cpu.SEB(i.rt, i.rd),
+            Some(Op::SEH) => |cpu, i| cpu.SEH(i.rt, i.rd),
+
+            //loads and stores
+            Some(Op::LB) => |cpu, i| cpu.LB(i.rs, i.rt, i.imm),
+            Some(Op::LH) => |cpu, i| cpu.LH(i.rs, i.rt, i.imm),
+            Some(Op::LWL) => |cpu, i| cpu.LWL(i.rs, i.rt, i.imm),
+            Some(Op::LW) => |cpu, i| cpu.LW(i.rs, i.rt, i.imm),
+            Some(Op::LBU) => |cpu, i| cpu.LBU(i.rs, i.rt, i.imm),
+            Some(Op::LHU) => |cpu, i| cpu.LHU(i.rs, i.rt, i.imm),
+            Some(Op::LWR) => |cpu, i| cpu.LWR(i.rs, i.rt, i.imm),
+            Some(Op::SB) => |cpu, i| cpu.SB(i.rs, i.rt, i.imm),
+            Some(Op::SH) => |cpu, i| cpu.SH(i.rs, i.rt, i.imm),
+            Some(Op::SWL) => |cpu, i| cpu.SWL(i.rs, i.rt, i.imm),
+            Some(Op::SW) => |cpu, i| cpu.SW(i.rs, i.rt, i.imm),
+            Some(Op::SWR) => |cpu, i| cpu.SWR(i.rs, i.rt, i.imm),
+            Some(Op::LL) => |cpu, i| cpu.LW(i.rs, i.rt, i.imm),
+            Some(Op::SC) => |cpu, i| cpu.SC(i.rs, i.rt, i.imm),
+
+            //COP0, privileged unless Status.CU0 hands it to user code
+            Some(Op::MFC0) => |cpu, i| if cpu.privileged() { cpu.MFC0(i.rt, i.rd, (i.word & 0x7) as u8) },
+            Some(Op::MTC0) => |cpu, i| if cpu.privileged() { cpu.MTC0(i.rt, i.rd, (i.word & 0x7) as u8) },
+            Some(Op::ERET) => |cpu, _| if cpu.privileged() { cpu.ERET() },
+            Some(Op::DI) => |cpu, i| if cpu.privileged() { cpu.DI(i.rt) },
+            Some(Op::EI) => |cpu, i| if cpu.privileged() { cpu.EI(i.rt) },
+            Some(Op::TLBR) => |cpu, _| if cpu.privileged() { cpu.TLBR() },
+            Some(Op::TLBWI) => |cpu, _| if cpu.privileged() { cpu.TLBW(false) },
+            Some(Op::TLBWR) => |cpu, _| if cpu.privileged() { cpu.TLBW(true) },
+            Some(Op::TLBP) => |cpu, _| if cpu.privileged() { cpu.TLBP() },
+            Some(Op::WAIT) => |cpu, _| { cpu.privileged(); },
+
+            //cache maintenance, the only cache we have is the decode cache
+            Some(Op::CACHE) => |cpu, i| if cpu.privileged() { cpu.SYNCI(i.rs, i.imm) },
+
+            //words that aren't instructions at all
+            None => |cpu, _| cpu.exception(Exception::ReservedInstruction, None),
+
+            //SYNC and PREF don't do anything for us, everything else isn't implemented yet
+            _ => |_, _| ()
+        }
+    }
+
+    //print all kinds of information about the CPU
+    pub fn print_reg(&self, format_hex: bool) {
+        println!("\t----- PROCESSOR STATE -----\t");
+        
+        if format_hex {
+            println!("Registers:");
+
+            for i in 1..=31 {
+                println!("${:0>2}: 0x{:0>8X}", i, self.GPR[i]);
+            }
+        }
+        else {
+            println!("Registers:");
+
+            for i in 1..=31 {
+                println!("${:0>2}: {:0>8}", i, self.GPR[i]);
+            }
+        }
+        
+        println!("\nHI/LO: {}/{}\n
+                  \rProgram Counter: {:#X}",
+                self.HI, self.LO, self.PC);
+    }
+    
+    //where the program would find a virtual address right now, None if it can't get there.
+    //The REPL looks at and patches memory through this, so it sees what the program sees
+    fn inspect(&self, address: u32, access: Access) -> Option<u32> {
+        self.CP0.translate(address, access).ok()
+    }
+
+    //print a portion of the main memory
+    pub fn print_mem(&self, start: u32, end: u32) {
+        println!("\t----- MEMORY CONTENTS -----\t");
+        if start <= end {
+            println!("Address:\t\tValue");
+            for address in (start..=end).step_by(4) {
+                match self.inspect(address, Access::Load) {
+                    Some(physical) => println!("0x{:0>8X}:\t\t0x{:0>8X}", address, self.MEM.read_word(physical)),
+                    None => println!("0x{:0>8X}:\t\tunmapped", address),
+                }
+            }
+        }
+        else {
+            println!("Please specify a non-negative range")
+        }
+    }
+
+    //print the current instruction +- an offset in whole instructions in binary
+    pub fn print_instruction(&self, offset: i16) {
+        let address = self.PC.wrapping_add((offset * 4)as u32);
+        match self.inspect(address, Access::Fetch) {
+            Some(physical) => println!("{:0>32b}", self.MEM.read_word(physical)),
+            None => println!("0x{:0>8X} is unmapped", address),
+        }
+    }
+
+    //disassemble n instructions before and after the PC
+    pub fn print_disassembly(&self, n: u32, style: Style) {
+        //more than that doesn't fit any scrollback, and n * 4 can't overflow
+        let n = n.min(1024);
+        let start = self.PC.wrapping_sub(n * 4);
+
+        for i in 0..=(2 * n) {
+            let address = start.wrapping_add(i * 4);
+            let marker = if address == self.PC { ">" } else { " " };
+
+            match self.inspect(address, Access::Fetch) {
+                Some(physical) => {
+                    let word = self.MEM.read_word(physical);
+                    println!("{} 0x{:0>8X}:  {:0>8x}  {}", marker, address, word, disassembler::disassemble(word, address, style));
+                }
+                None => println!("{} 0x{:0>8X}:  unmapped", marker, address),
+            }
+        }
+    }
+
+    //overwrite instructions in memory, starting at address
+    pub fn patch_instructions(&mut self, address: u32, words: &[u32]) {
+        for (index, word) in words.iter().enumerate() {
+            let address = address.wrapping_add(4 * index as u32);
+            let physical = match self.inspect(address, Access::Fetch) {
+                Some(physical) => physical,
+                None => return println!("0x{:0>8X} is unmapped, nothing patched from there on", address),
+            };
+            self.MEM.write_word(physical, *word);
+            println!("0x{:0>8X}:  {:0>8x}  {}", address, word, disassembler::disassemble(*word, address, Style::Mars));
+        }
+    }
+
+    //switch between the pipeline model and plain functional execution
+    pub fn toggle_pipeline(&mut self) {
+        //cache misses from before don't belong to the new pipeline
+        self.take_memory_stalls();
+        self.pipeline = match self.pipeline {
+            Some(_) => None,
+            None => Some(Pipeline::new()),
+        };
+        self.pipelined = self.pipeline.is_some();
+        println!("Pipeline mode {}", if self.pipeline.is_some() { "on" } else { "off" });
+        if self.pipelined && self.out_of_order {
+            self.toggle_out_of_order();
+        }
+    }
+
+    //switch the out-of-order model on or off, it takes over from the pipeline model
+    pub fn toggle_out_of_order(&mut self) {
+        self.take_memory_stalls();
+        self.tomasulo = match self.tomasulo {
+            Some(_) => None,
+            None => Some(Tomasulo::new(self.PC)),
+        };
+        self.out_of_order = self.tomasulo.is_some();
+        println!("Out-of-order mode {}", if self.out_of_order { "on" } else { "off" });
+        if self.out_of_order && self.pipelined {
+            self.toggle_pipeline();
+        }
+    }
+
+    //cycles until the result of an instruction can be used, in the current timing profile
+    pub(crate) fn latency(&self, inst: &Instruction) -> u32 {
+        self.timing.latency(inst)
+    }
+
+    //cycles since construction, they drive CP0 Count
+    pub fn cycles(&self) -> u64 {
+        self.instructions + self.extra_cycles
+    }
+
+    //switch to one of the built in timing profiles
+    pub fn set_timing_profile(&mut self, name: &str) {
+        match Profile::named(name) {
+            Some(profile) => self.timing = Timing::new(profile),
+            None => println!("Unknown timing profile '{}', try ideal, m4k, m4k-small or microaptiv", name),
+        }
+    }
+
+    //change issue and latency of one instruction class in the current profile
+    pub fn set_timing(&mut self, class: &str, issue: u32, latency: u32) {
+        let class = match Class::parse(class) {
+            Some(class) => class,
+            None => return println!("Unknown instruction class '{}', try alu, load, store, branch, mul, madd, div or cop0", class),
+        };
+        let mut profile = std::mem::replace(&mut self.timing, Timing::new(Profile::ideal())).profile;
+        profile.set(class, Cost { issue: issue.max(1), latency: latency.max(1) });
+        self.timing = Timing::new(profile);
+    }
+
+    pub fn print_timing(&self) {
+        self.timing.profile.print();
+        println!("Cycles:\t{}\t(CP0 Count {:#010X})", self.cycles(), self.CP0.read(9, 0, self.cycles()));
+    }
+
+    //is one of the timing models or the branch predictors on, which only work when executing one instruction at a time?
+    pub fn timed(&self) -> bool {
+        self.pipeline.is_some() || self.tomasulo.is_some() || self.caches.is_some() || self.predictors.is_some()
+    }
+
+    //start or stop watching branches with the standard set of predictors
+    pub fn toggle_predictors(&mut self) {
+        self.predictors = match self.predictors {
+            Some(_) => None,
+            None => Some(BranchPredictors::standard()),
+        };
+        println!("Branch predictors {}", if self.predictors.is_some() { "on" } else { "off" });
+    }
+
+    //print overall accuracy of the predictors and the n most executed branches
+    pub fn print_predictor_stats(&self, n: usize) {
+        match &self.predictors {
+            Some(predictors) => predictors.print_stats(n),
+            None => println!("Branch predictors are off, enable them with 'predict'"),
+        }
+    }
+
+    pub fn caches_on(&self) -> bool {
+        self.caches.is_some()
+    }
+
+    //turn the caches on with the microAptiv defaults, or off
+    pub fn toggle_caches(&mut self) {
+        self.caches = match self.caches {
+            Some(_) => None,
+            None => Some(Caches::new(CacheConfig::micro_aptiv(), CacheConfig::micro_aptiv())),
+        };
+        println!("Caches {}", if self.caches.is_some() { "on" } else { "off" });
+    }
+
+    //replace the instruction or data cache with a new, empty one
+    pub fn configure_cache(&mut self, instruction: bool, config: CacheConfig) {
+        let caches = self.caches.get_or_insert_with(|| Caches::new(CacheConfig::micro_aptiv(), CacheConfig::micro_aptiv()));
+        if instruction {
+            caches.instruction = Cache::new(config);
+        }
+        else {
+            caches.data = Cache::new(config);
+        }
+    }
+
+    //print hit and miss statistics of both caches
+    pub fn print_cache_stats(&self) {
+        match &self.caches {
+            Some(caches) => {
+                caches.instruction.print_stats("I-cache");
+                caches.data.print_stats("D-cache");
+                println!("Miss penalties:\t{} cycles", caches.stall_cycles);
+                if self.pipeline.is_none() {
+                    println!("Cycles:\t\t{} (one per instruction plus miss penalties)", self.instructions + caches.stall_cycles);
+                }
+            }
+            None => println!("Caches are off, enable them with 'cache on'"),
+        }
+    }
+
+    //cache miss penalties the pipeline model hasn't stalled for yet
+    pub(crate) fn take_memory_stalls(&mut self) -> u64 {
+        self.caches.as_mut().map_or(0, |caches| caches.take_pending())
+    }
+
+    //translate a load or store of size bytes and let the data cache and performance counters know
+    //about it, returns the physical address or None if it raised an exception instead
+    fn data_access(&mut self, address: u32, size: u32, write: bool) -> Option<u32> {
+        let physical = match self.translate(address, size, if write { Access::Store } else { Access::Load }) {
+            Ok(physical) => physical,
+            Err(fault) => {
+                self.fault(fault, address);
+                return None;
+            }
+        };
+
+        self.CP0.event(if write { Event::Stores } else { Event::Loads }, 1);
+
+        if let Some(caches) = &mut self.caches {
+            let penalty = if write { caches.store(physical) } else { caches.load(physical) };
+            if let Some(penalty) = penalty {
+                self.CP0.event(Event::DCacheMisses, 1);
+                self.elapse(penalty, true);
+            }
+        }
+        Some(physical)
+    }
+
+    //let cycles pass on top of the one every instruction takes,
+    //stalls are cycles in which no instruction could make progress
+    pub(crate) fn elapse(&mut self, cycles: u64, stall: bool) {
+        self.extra_cycles += cycles;
+        self.CP0.event(Event::Cycles, cycles);
+        if stall {
+            self.CP0.event(Event::Stalls, cycles);
+        }
+    }
+
+    //print the cycle count, CPI and stalls of the pipeline or out-of-order model
+    pub fn print_pipeline_stats(&self) {
+        match (&self.pipeline, &self.tomasulo) {
+            (Some(pipeline), _) => pipeline.print_stats(),
+            (_, Some(tomasulo)) => tomasulo.print_stats(),
+            _ => println!("Pipeline and out-of-order mode are off, enable one with 'pipeline' or 'ooo'"),
+        }
+    }
+
+    //print all CP0 registers
+    pub fn print_cp0(&self) {
+        self.CP0.print(self.cycles());
+    }
+
+    //switch the memory protection unit on or off, it starts without regions
+    pub fn set_mpu(&mut self, on: bool) {
+        self.CP0.set_mpu(if on { Some(Mpu::new()) } else { None });
+        println!("MPU {}", if on { "on, add regions with 'mpu N base size kernel user'" } else { "off" });
+    }
+
+    //define or (with None) remove region 0-15 of the memory protection unit
+    pub fn set_mpu_region(&mut self, index: usize, region: Option<Region>) {
+        match self.CP0.mpu() {
+            Some(mpu) if index < MPU_REGIONS => mpu.set_region(index, region),
+            Some(_) => println!("There are only MPU regions 0 to {}", MPU_REGIONS - 1),
+            None => println!("The MPU is off, enter 'mpu on' first"),
+        }
+    }
+
+    //print the MPU regions
+    pub fn print_mpu(&mut self) {
+        match self.CP0.mpu() {
+            Some(mpu) => mpu.print(),
+            None => println!("The MPU is off"),
+        }
+    }
+
+    //print the valid TLB entries
+    pub fn print_tlb(&mut self) {
+        match self.CP0.tlb() {
+            Some(tlb) => tlb.print(),
+            None => println!("There is no TLB, start with the tlb translation mode to get one"),
+        }
+    }
+
+    //reset the cpu to a known state
+    pub fn reset(&mut self) {
+        if self.pipeline.is_some() {
+            self.pipeline = Some(Pipeline::new());
+        }
+        self.GPR = [0; 32];         //null all registers - not needed but nice
+        let translation = self.CP0.translation();
+        self.PC = translation.reset_address();  //.text segment base address or the reset vector
+        self.next_PC = self.PC + 4;
+        self.GPR[29] = 0x7fffeffc;  //stack pointer $sp base address
+        //a reset doesn't unplug the interrupt controller or forget the MPU regions
+        let controller = self.CP0.take_controller();
+        let mpu = self.CP0.take_mpu();
+        self.CP0 = ExceptionProcessor::new(translation);
+        self.CP0.set_controller(controller);
+        self.CP0.set_mpu(mpu);
+        self.delay_slot = u32::MAX;
+        self.stopped = false;
+        if self.tomasulo.is_some() {
+            self.tomasulo = Some(Tomasulo::new(self.PC));
+        }
+        //Count is derived from the cycles, so they start over too, and with them the operand scoreboard
+        self.instructions = 0;
+        self.extra_cycles = 0;
+        let profile = std::mem::replace(&mut self.timing, Timing::new(Profile::ideal())).profile;
+        self.timing = Timing::new(profile);
+    }
+
+    //read data from a register
+    fn read_reg(&self, number: u8) -> u32 {
+        match number {
+            0       => 0,   //register 0 is hardwired to logic 0
+            1..=31   => self.GPR[number as usize],
+            32      => self.HI,
+            33      => self.LO,
+            _       => 0 //error handle later?
+        }
+    }
+
+    //write data to a register
+    fn write_reg(&mut self, number: u8, value: u32) {
+        match number {
+            1..=31   => self.GPR[number as usize] = value,
+            32      => self.HI = value,
+            33      => self.LO = value,
+            _       => ()
+        }
+    }
+
+    //take a branch: the offset is relative to the delay slot, which the PC already points to
+    fn branch(&mut self, imm: u16) {
+        //interpret the immediate as signed and lshift it by 2 because instructions are 4-aligned
+        let signed_imm = ((imm as i16) as i32) << 2;
+        self.next_PC = self.PC.wrapping_add(signed_imm as u32);
+    }
+
+    //take a pending interrupt before the instruction at PC
+    pub(crate) fn poll_interrupts(&mut self) {
+        self.CP0.tick(self.cycles());
+        if self.CP0.interrupt_pending() {
+            //an interrupted delay slot restarts with its branch just like an exception in it
+            let vector = self.CP0.exception(Exception::Interrupt, self.PC, self.PC == self.delay_slot, None);
+            self.enter(vector);
+        }
+    }
+
+    //assert or deassert one of the six hardware interrupt lines, for devices and embedding code.
+    //The interrupt is taken before the next instruction if Status allows it
+    pub fn set_interrupt_line(&mut self, line: u8, asserted: bool) {
+        let lines = self.CP0.line_count();
+        if line >= lines {
+            return println!("There are only interrupt lines 0 to {}", lines - 1);
+        }
+        self.CP0.set_line(line, asserted);
+    }
+
+    //attach an external interrupt controller, which puts the core in EIC mode, or detach it
+    pub fn set_interrupt_controller(&mut self, controller: Option<Box<dyn InterruptController>>) {
+        match &controller {
+            Some(controller) => println!("EIC mode on, {}", controller.name()),
+            None => println!("EIC mode off"),
+        }
+        self.CP0.set_controller(controller);
+    }
+
+    //change the priority level of one of the external interrupt controller's sources
+    pub fn set_interrupt_priority(&mut self, source: u8, level: u8) {
+        match self.CP0.controller() {
+            Some(controller) if source < 64 => controller.set_priority(source, level),
+            Some(_) => println!("There are only controller sources 0 to 63"),
+            None => println!("No interrupt controller attached, enter 'eic on' first"),
+        }
+    }
+
+    //instructions that can run before the timer interrupt might have to be taken, at least one
+    pub(crate) fn instructions_to_interrupt(&self) -> u64 {
+        //every instruction takes at least a cycle, so this never overshoots
+        self.CP0.cycles_to_interrupt(self.cycles()).max(1)
+    }
+
+    //abandon the instruction being executed and continue in the exception handler
+    fn exception(&mut self, exception: Exception, bad_address: Option<u32>) {
+        let address = self.executing;
+        let vector = self.CP0.exception(exception, address, address == self.delay_slot, bad_address);
+        self.enter(vector);
+    }
+
+    //abandon the instruction being executed because an address it uses couldn't be translated
+    fn fault(&mut self, fault: Fault, bad_address: u32) {
+        let address = self.executing;
+        let vector = self.CP0.fault(fault, address, address == self.delay_slot, bad_address);
+        self.enter(vector);
+    }
+
+    //continue in the exception handler at a vector, or in the built-in one if there is nothing there
+    fn enter(&mut self, vector: u32) {
+        //where the instruction would have continued, the branch target if it sat in a delay slot
+        let after = self.PC;
+        self.jump_to(vector);
+        if !self.handler_at(vector) {
+            self.default_exception(after);
+        }
+    }
+
+    //does a handler start at a vector? Nothing but zeroes (nops) for 8 instructions means there is none,
+    //which is what a program without .ktext leaves there. A real handler starting with 8 nops would be
+    //taken for no handler at all, but nothing sensible does that
+    fn handler_at(&self, vector: u32) -> bool {
+        (0..8).any(|i| self.MEM.read_word(self.physical(vector.wrapping_add(4 * i))) != 0)
+    }
+
+    //what the built-in exception handler does: decode Cause, say what happened where and skip the
+    //instruction or stop the program. Skipping continues where the instruction would have gone
+    //(after), which for a delay slot is wherever its branch went and not EPC + 4
+    fn default_exception(&mut self, after: u32) {
+        let cycles = self.cycles();
+        let exception = match Exception::from_cause(self.CP0.read(13, 0, cycles)) {
+            Some(exception) => exception,
+            None => return,
+        };
+        let epc = self.CP0.read(14, 0, cycles);
+        let bad_address = self.CP0.read(8, 0, cycles);
+        let fetch_failed = exception.has_bad_address() && bad_address == epc;
+
+        let mut message = format!("Runtime exception at {:#010X}: {} ({})", epc, exception.describe(), exception.mnemonic());
+        //name the instruction, unless it couldn't even be fetched. In a delay slot EPC is the branch
+        if exception != Exception::Interrupt && !fetch_failed {
+            let address = self.executing;
+            let inst = self.fetch(address);
+            message += &format!(" in {}", disassembler::disassemble(inst.word, address, Style::Mars));
+            //and the operands that overflowed
+            if exception == Exception::Overflow {
+                let operands = if inst.op == Some(Op::ADDI) { vec![inst.rs] } else { vec![inst.rs, inst.rt] };
+                let values: Vec<String> = operands.iter().map(|&reg| format!("${} = {}", REGISTER_NAMES[reg as usize], self.read_reg(reg) as i32)).collect();
+                message += &format!(" ({})", values.join(", "));
+            }
+        }
+        if exception.has_bad_address() {
+            message += &format!(", BadVAddr {:#010X}", bad_address);
+        }
+
+        //there is no instruction to skip if the fetch failed, the next one would fail just the same
+        match self.default_handler.action(exception) {
+            Action::Skip if !fetch_failed => {
+                println!("{}, skipped", message);
+                //an interrupt hit before the instruction at EPC, which still has to run (a branch
+                //included, if the interrupt hit its delay slot)
+                let target = self.CP0.resume(exception != Exception::Interrupt);
+                self.jump_to(if exception == Exception::Interrupt { target } else { after });
+            }
+            _ => {
+                println!("{}, program stopped", message);
+                self.stopped = true;
+                self.jump_to(epc);
+            }
+        }
+    }
+
+    //change what the built-in exception handler does about an exception code
+    pub fn set_default_action(&mut self, code: u32, action: Action) {
+        match Exception::from_code(code) {
+            Some(exception) => self.default_handler.set_action(exception, action),
+            None => println!("There is no exception with code {}", code),
+        }
+    }
+
+    //print what the built-in exception handler does about every exception
+    pub fn print_default_handler(&self) {
+        self.default_handler.print();
+    }
+
+    //continue at an address right away, without a delay slot
+    fn jump_to(&mut self, address: u32) {
+        self.PC = address;
+        self.next_PC = address.wrapping_add(4);
+        self.delay_slot = u32::MAX;     //whatever comes next wasn't reached through a branch
+    }
+
+    //raise a coprocessor unusable exception if coprocessor 0 is off limits, returns whether it is usable
+    fn privileged(&mut self) -> bool {
+        let usable = self.CP0.usable();
+        if !usable {
+            self.exception(Exception::CoprocessorUnusable, None);
+        }
+        usable
+    }
+
+    //raise a trap exception if the condition of a trap instruction holds
+    fn trap(&mut self, condition: bool) {
+        if condition {
+            self.exception(Exception::Trap, None);
+        }
+    }
+
+    //compute base + sign extended offset for loads and stores
+    fn effective_address(&self, base: u8, offset: u16) -> u32 {
+        self.read_reg(base).wrapping_add(offset as i16 as i32 as u32)
+    }
+
+    ///////////////
+    //
+    //
+    // INSTRUCTIONS
+    // 
+    // 
+    ///////////////
+
+    #[allow(non_snake_case)]
+    fn AND(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, self.read_reg(rs) & self.read_reg(rt))
+    }
+    
+    #[allow(non_snake_case)]
+    fn ANDI(&mut self, rs: u8, rt: u8, imm: u16) {
+        self.write_reg(rt, self.read_reg(rs) & (imm as u32))
+    }
+
+    #[allow(non_snake_case)]
+    fn OR(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, self.read_reg(rs) | self.read_reg(rt));
+    }
+
+    #[allow(non_snake_case)]
+    fn ORI(&mut self, rs: u8, rt: u8, imm: u16) {
+        self.write_reg(rt, self.read_reg(rs) | (imm as u32));
+    }
+
+    #[allow(non_snake_case)]
+    fn NOR(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, !(self.read_reg(rs) | self.read_reg(rt)));
+    }
+
+    #[allow(non_snake_case)]
+    fn XOR(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, self.read_reg(rs) ^ self.read_reg(rt));
+    }
+
+    #[allow(non_snake_case)]
+    fn XORI(&mut self, rs: u8, rt: u8, imm: u16) {
+        self.write_reg(rt, self.read_reg(rs) ^ (imm as u32));
+    }
+
+    #[allow(non_snake_case)]
+    fn SLT(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, if (self.read_reg(rs) as i32) < (self.read_reg(rt) as i32) { 1 } else { 0 });
+    }
+
+    #[allow(non_snake_case)]
+    fn SLTI(&mut self, rs: u8, rt: u8, imm: u16) {
+        //sign extend the immediate
+        let signed_imm = imm as i16 as i32;
+        self.write_reg(rt, if (self.read_reg(rs) as i32) < (signed_imm) { 1 } else { 0 });
+    }
+
+    #[allow(non_snake_case)]
+    fn JR(&mut self, rs: u8) {
+        self.next_PC = self.read_reg(rs);
+    }
+
+    #[allow(non_snake_case)]
+    fn ADDU(&mut self, rs: u8, rt: u8, rd: u8) {
+        let (result, _overflow_flag) = self.read_reg(rs).overflowing_add(self.read_reg(rt));
+
+        self.write_reg(rd, result);
+    }
+
+    #[allow(non_snake_case)]
+    fn ADDIU(&mut self, rs: u8, rt: u8, imm: u16) {
+        //sign extend the immediate
+        let signed_imm = imm as i16 as i32;
+
+        let (result, _overflow_flag) = self.read_reg(rs).overflowing_add(signed_imm as u32);
+
+        self.write_reg(rt, result);
+    }
+
+    #[allow(non_snake_case)]
+    fn CLZ(&mut self, rs: u8, _rt: u8, rd: u8) {
+        //in the original design rt and rd have to be equal!
+        self.write_reg(rd, self.read_reg(rs).leading_zeros());
+    }
+
+    #[allow(non_snake_case)]
+    fn CLO(&mut self, rs: u8, _rt: u8, rd: u8) {
+        //in the original design rt and rd have to be equal!
+        self.write_reg(rd, self.read_reg(rs).leading_ones());
+    }
+
+    #[allow(non_snake_case)]
+    fn LUI(&mut self, rt: u8, imm: u16) {
+        self.write_reg(rt, (imm as u32) << 16);
+    }
+
+    #[allow(non_snake_case)]
+    fn SYSCALL(&mut self) {
+        self.exception(Exception::Syscall, None);
+    }
+
+    #[allow(non_snake_case)]
+    fn BREAK(&mut self) {
+        self.exception(Exception::Breakpoint, None);
+    }
+
+    #[allow(non_snake_case)]
+    fn DI(&mut self, rt: u8) {
+        let status = self.CP0.set_interrupts(false);
+        self.write_reg(rt, status);
+    }
+
+    #[allow(non_snake_case)]
+    fn EI(&mut self, rt: u8) {
+        let status = self.CP0.set_interrupts(true);
+        self.write_reg(rt, status);
+    }
+
+    #[allow(non_snake_case)]
+    fn ERET(&mut self) {
+        let target = self.CP0.eret();
+        self.jump_to(target);
+    }
+    
+    #[allow(non_snake_case)]
+    fn LB(&mut self, base: u8, rt: u8, imm: u16) {
+        let address = self.effective_address(base, imm);
+        let physical = match self.data_access(address, 1, false) {
+            Some(physical) => physical,
+            None => return,
+        };
+
+        //read a byte as u8, then cast to i8 and i32 to sign extend to i32, then back to u32 to write it into a register
+        self.write_reg(rt, self.MEM.read_byte(physical) as i8 as i32 as u32);
+    }
+
+    #[allow(non_snake_case)]
+    fn LH(&mut self, base: u8, rt: u8, imm: u16) {
+        let address = self.effective_address(base, imm);
+        let physical = match self.data_access(address, 2, false) {
+            Some(physical) => physical,
+            None => return,
+        };
+
+        //read a byte as u16, then cast it to i16 and i32 to sign extend to i32, then back to u32 to write it into a register
+        self.write_reg(rt, self.MEM.read_half(physical) as i16 as i32 as u32);
+    }
+
+    #[allow(non_snake_case)]
+    fn LW(&mut self, base: u8, rt: u8, imm: u16) { 
+        let address = self.effective_address(base, imm);
+        let physical = match self.data_access(address, 4, false) {
+            Some(physical) => physical,
+            None => return,
+        };
+
+        //read a word and write it into a register
+        self.write_reg(rt, self.MEM.read_word(physical));
+    }
+
+    #[allow(non_snake_case)]
+    fn BGTZ(&mut self, rs: u8, imm: u16) {
+        if (self.read_reg(rs) as i32) > 0 {
+            self.branch(imm);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn ADD(&mut self, rs: u8, rt: u8, rd: u8) {
+        let (result, overflow_flag) = (self.read_reg(rs) as i32).overflowing_add(self.read_reg(rt) as i32);
+
+        if overflow_flag {
+            self.exception(Exception::Overflow, None);
+        }
+        else {
+            self.write_reg(rd, result as u32);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn ADDI(&mut self, rs: u8, rt: u8, imm: u16) {
+        let signed_imm = imm as i16 as i32;
+
+        let (result, overflow_flag) = (self.read_reg(rs) as i32).overflowing_add(signed_imm);
+
+        if overflow_flag {
+            self.exception(Exception::Overflow, None);
+        }
+        else {
+            self.write_reg(rt, result as u32);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn SUB(&mut self, rs: u8, rt: u8, rd: u8) {
+        let (result, overflow_flag) = (self.read_reg(rs) as i32).overflowing_sub(self.read_reg(rt) as i32);
+
+        if overflow_flag {
+            self.exception(Exception::Overflow, None);
+        }
+        else {
+            self.write_reg(rd, result as u32);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn SUBU(&mut self, rs: u8, rt: u8, rd: u8) {
+        let (result, _overflow_flag) = (self.read_reg(rs) as i32).overflowing_sub(self.read_reg(rt) as i32);
+
+        self.write_reg(rd, result as u32);
+
+    }
+
+    #[allow(non_snake_case)]
+    fn JAL(&mut self, instr_index: u32) {
+        //return to the instruction after the branch delay slot
+        self.write_reg(31, self.PC.wrapping_add(4));
+        self.J(instr_index);
+    }
+
+    #[allow(non_snake_case)]
+    fn SW(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 4, true) {
+            Some(physical) => physical,
+            None => return,
+        };
+
+        //store the contents of rt in memory
+        self.MEM.write_word(physical, self.read_reg(rt));
+    }
+
+    #[allow(non_snake_case)]
+    fn MFC0(&mut self, rt: u8, rd: u8, sel: u8) {
+        self.write_reg(rt, self.CP0.read(rd, sel, self.cycles()));
+    }
+
+    #[allow(non_snake_case)]
+    fn MTC0(&mut self, rt: u8, rd: u8, sel: u8) {
+        self.CP0.write(rd, sel, self.read_reg(rt), self.cycles());
+    }
+
+    #[allow(non_snake_case)]
+    fn TLBR(&mut self) {
+        match self.CP0.tlb() {
+            Some(tlb) => tlb.read_entry(),
+            None => self.exception(Exception::ReservedInstruction, None),
+        }
+    }
+
+    //tlbwi and tlbwr
+    #[allow(non_snake_case)]
+    fn TLBW(&mut self, random: bool) {
+        let cycles = self.cycles();
+        match self.CP0.tlb() {
+            Some(tlb) => tlb.write_entry(random, cycles),
+            None => self.exception(Exception::ReservedInstruction, None),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn TLBP(&mut self) {
+        match self.CP0.tlb() {
+            Some(tlb) => tlb.probe(),
+            None => self.exception(Exception::ReservedInstruction, None),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn SYNCI(&mut self, base: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = self.physical(address);
+        self.decoded.invalidate(physical);
+    }
+
+    #[allow(non_snake_case)]
+    fn MOVN(&mut self, rs: u8, rt: u8, rd: u8) {
+        if self.read_reg(rt) != 0 {
+            self.write_reg(rd, self.read_reg(rs));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn MOVZ(&mut self, rs: u8, rt: u8, rd: u8) {
+        if self.read_reg(rt) == 0 {
+            self.write_reg(rd, self.read_reg(rs));
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn SLTIU(&mut self, rs: u8, rt: u8, imm: u16) {
+        let signed_imm = imm as i16 as i32;
+        //sign extend the immediate first, but then do an unsigned comparison
+        self.write_reg(rt, if self.read_reg(rs) < (signed_imm as u32) { 1 } else { 0 })
+    }
+
+    #[allow(non_snake_case)]
+    fn SLTU(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, if self.read_reg(rs) < self.read_reg(rt) { 1 } else { 0 });
+    }
+
+    #[allow(non_snake_case)]
+    fn SLL(&mut self, rt: u8, rd: u8, sa: u8) {
+        self.write_reg(rd, self.read_reg(rt) << sa);
+    }
+
+    #[allow(non_snake_case)]
+    fn SRL(&mut self, rt: u8, rd: u8, sa: u8) {
+        self.write_reg(rd, self.read_reg(rt) >> sa);
+    }
+
+    #[allow(non_snake_case)]
+    fn ROTR(&mut self, rt: u8, rd: u8, sa: u8) {
+        self.write_reg(rd, self.read_reg(rt).rotate_right(sa as u32));
+    }
+
+    #[allow(non_snake_case)]
+    fn SRA(&mut self, rt: u8, rd: u8, sa: u8) {
+        self.write_reg(rd, ((self.read_reg(rt) as i32) >> sa) as u32);
+    }
+
+    #[allow(non_snake_case)]
+    fn SLLV(&mut self, rs: u8, rt: u8, rd: u8) {
+        //only the lower 5 bits of rs count
+        self.write_reg(rd, self.read_reg(rt) << (self.read_reg(rs) & 0x1F));
+    }
+
+    #[allow(non_snake_case)]
+    fn SRLV(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, self.read_reg(rt) >> (self.read_reg(rs) & 0x1F));
+    }
+
+    #[allow(non_snake_case)]
+    fn ROTRV(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, self.read_reg(rt).rotate_right(self.read_reg(rs) & 0x1F));
+    }
+
+    #[allow(non_snake_case)]
+    fn SRAV(&mut self, rs: u8, rt: u8, rd: u8) {
+        self.write_reg(rd, ((self.read_reg(rt) as i32) >> (self.read_reg(rs) & 0x1F)) as u32);
+    }
+
+    #[allow(non_snake_case)]
+    fn JALR(&mut self, rs: u8, rd: u8) {
+        //read the target first in case rs and rd are the same register
+        let target = self.read_reg(rs);
+        self.write_reg(rd, self.PC.wrapping_add(4));
+        self.next_PC = target;
+    }
+
+    #[allow(non_snake_case)]
+    fn J(&mut self, instr_index: u32) {
+        //the upper 4 bits come from the address of the delay slot
+        self.next_PC = (self.PC & 0xF000_0000) | (instr_index << 2);
+    }
+
+    #[allow(non_snake_case)]
+    fn BEQ(&mut self, rs: u8, rt: u8, imm: u16) {
+        if self.read_reg(rs) == self.read_reg(rt) {
+            self.branch(imm);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn BNE(&mut self, rs: u8, rt: u8, imm: u16) {
+        if self.read_reg(rs) != self.read_reg(rt) {
+            self.branch(imm);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn BLEZ(&mut self, rs: u8, imm: u16) {
+        if (self.read_reg(rs) as i32) <= 0 {
+            self.branch(imm);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn BLTZ(&mut self, rs: u8, imm: u16) {
+        if (self.read_reg(rs) as i32) < 0 {
+            self.branch(imm);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn BGEZ(&mut self, rs: u8, imm: u16) {
+        if (self.read_reg(rs) as i32) >= 0 {
+            self.branch(imm);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn BLTZAL(&mut self, rs: u8, imm: u16) {
+        //the link happens whether the branch is taken or not
+        let taken = (self.read_reg(rs) as i32) < 0;
+        self.write_reg(31, self.PC.wrapping_add(4));
+        if taken {
+            self.branch(imm);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn BGEZAL(&mut self, rs: u8, imm: u16) {
+        let taken = (self.read_reg(rs) as i32) >= 0;
+        self.write_reg(31, self.PC.wrapping_add(4));
+        if taken {
+            self.branch(imm);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn MFHI(&mut self, rd: u8) {
+        self.write_reg(rd, self.HI);
+    }
+
+    #[allow(non_snake_case)]
+    fn MTHI(&mut self, rs: u8) {
+        self.write_reg(32, self.read_reg(rs));
+    }
+
+    #[allow(non_snake_case)]
+    fn MFLO(&mut self, rd: u8) {
+        self.write_reg(rd, self.LO);
+    }
+
+    #[allow(non_snake_case)]
+    fn MTLO(&mut self, rs: u8) {
+        self.write_reg(33, self.read_reg(rs));
+    }
+
+    //split a 64 bit result into HI and LO
+    fn write_hilo(&mut self, value: u64) {
+        self.HI = (value >> 32) as u32;
+        self.LO = value as u32;
+    }
+
+    fn read_hilo(&self) -> u64 {
+        ((self.HI as u64) << 32) | self.LO as u64
+    }
+
+    #[allow(non_snake_case)]
+    fn MULT(&mut self, rs: u8, rt: u8) {
+        let product = (self.read_reg(rs) as i32 as i64) * (self.read_reg(rt) as i32 as i64);
+        self.write_hilo(product as u64);
+    }
+
+    #[allow(non_snake_case)]
+    fn MULTU(&mut self, rs: u8, rt: u8) {
+        let product = (self.read_reg(rs) as u64) * (self.read_reg(rt) as u64);
+        self.write_hilo(product);
+    }
+
+    #[allow(non_snake_case)]
+    fn DIV(&mut self, rs: u8, rt: u8) {
+        let (dividend, divisor) = (self.read_reg(rs) as i32, self.read_reg(rt) as i32);
+
+        //the result of dividing by zero is unpredictable, leave HI and LO alone
+        if divisor != 0 {
+            self.LO = dividend.wrapping_div(divisor) as u32;
+            self.HI = dividend.wrapping_rem(divisor) as u32;
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn DIVU(&mut self, rs: u8, rt: u8) {
+        let (dividend, divisor) = (self.read_reg(rs), self.read_reg(rt));
+
+        if let (Some(quotient), Some(remainder)) = (dividend.checked_div(divisor), dividend.checked_rem(divisor)) {
+            self.LO = quotient;
+            self.HI = remainder;
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn MADD(&mut self, rs: u8, rt: u8) {
+        let product = (self.read_reg(rs) as i32 as i64) * (self.read_reg(rt) as i32 as i64);
+        self.write_hilo((self.read_hilo() as i64).wrapping_add(product) as u64);
+    }
+
+    #[allow(non_snake_case)]
+    fn MADDU(&mut self, rs: u8, rt: u8) {
+        let product = (self.read_reg(rs) as u64) * (self.read_reg(rt) as u64);
+        self.write_hilo(self.read_hilo().wrapping_add(product));
+    }
+
+    #[allow(non_snake_case)]
+    fn MSUB(&mut self, rs: u8, rt: u8) {
+        let product = (self.read_reg(rs) as i32 as i64) * (self.read_reg(rt) as i32 as i64);
+        self.write_hilo((self.read_hilo() as i64).wrapping_sub(product) as u64);
+    }
+
+    #[allow(non_snake_case)]
+    fn MSUBU(&mut self, rs: u8, rt: u8) {
+        let product = (self.read_reg(rs) as u64) * (self.read_reg(rt) as u64);
+        self.write_hilo(self.read_hilo().wrapping_sub(product));
+    }
+
+    #[allow(non_snake_case)]
+    fn MUL(&mut self, rs: u8, rt: u8, rd: u8) {
+        //only the lower 32 bits are kept, HI and LO are left alone
+        self.write_reg(rd, self.read_reg(rs).wrapping_mul(self.read_reg(rt)));
+    }
+
+    #[allow(non_snake_case)]
+    fn EXT(&mut self, rs: u8, rt: u8, msbd: u8, lsb: u8) {
+        //rd holds size - 1, sa the position of the lowest bit
+        let mask = (((1u64 << (msbd as u64 + 1)) - 1) as u32) << lsb;
+        self.write_reg(rt, (self.read_reg(rs) & mask) >> lsb);
+    }
+
+    #[allow(non_snake_case)]
+    fn INS(&mut self, rs: u8, rt: u8, msb: u8, lsb: u8) {
+        //rd holds the position of the highest bit, sa the position of the lowest bit
+        if msb < lsb {
+            return;
+        }
+        let mask = (((1u64 << (msb - lsb + 1) as u64) - 1) as u32) << lsb;
+        self.write_reg(rt, (self.read_reg(rt) & !mask) | ((self.read_reg(rs) << lsb) & mask));
+    }
+
+    #[allow(non_snake_case)]
+    fn WSBH(&mut self, rt: u8, rd: u8) {
+        //swap the bytes within each half
+        let value = self.read_reg(rt);
+        self.write_reg(rd, ((value & 0x00FF_00FF) << 8) | ((value & 0xFF00_FF00) >> 8));
+    }
+
+    #[allow(non_snake_case)]
+    fn SEB(&mut self, rt: u8, rd: u8) {
+        self.write_reg(rd, self.read_reg(rt) as u8 as i8 as i32 as u32);
+    }
+
+    #[allow(non_snake_case)]
+    fn SEH(&mut self, rt: u8, rd: u8) {
+        self.write_reg(rd, self.read_reg(rt) as u16 as i16 as i32 as u32);
+    }
+
+    #[allow(non_snake_case)]
+    fn LBU(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 1, false) {
+            Some(physical) => physical,
+            None => return,
+        };
+        self.write_reg(rt, self.MEM.read_byte(physical) as u32);
+    }
+
+    #[allow(non_snake_case)]
+    fn LHU(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 2, false) {
+            Some(physical) => physical,
+            None => return,
+        };
+        self.write_reg(rt, self.MEM.read_half(physical) as u32);
+    }
+
+    #[allow(non_snake_case)]
+    fn SB(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 1, true) {
+            Some(physical) => physical,
+            None => return,
+        };
+        self.MEM.write_byte(physical, self.read_reg(rt) as u8);
+    }
+
+    #[allow(non_snake_case)]
+    fn SH(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 2, true) {
+            Some(physical) => physical,
+            None => return,
+        };
+        self.MEM.write_half(physical, self.read_reg(rt) as u16);
+    }
+
+    // The unaligned loads and stores work on the aligned word containing the address.
+    // We are little endian, so the "left" (most significant) part of a word is at the highest address.
+
+    #[allow(non_snake_case)]
+    fn LWL(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 1, false) {
+            Some(physical) => physical,
+            None => return,
+        };
+        let word = self.MEM.read_word(physical & !3);
+        let shift = (3 - (address & 3)) * 8;
+
+        let kept = self.read_reg(rt) & ((1u64 << shift) - 1) as u32;
+        self.write_reg(rt, kept | (word << shift));
+    }
+
+    #[allow(non_snake_case)]
+    fn LWR(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 1, false) {
+            Some(physical) => physical,
+            None => return,
+        };
+        let word = self.MEM.read_word(physical & !3);
+        let shift = (address & 3) * 8;
+
+        let kept = self.read_reg(rt) & !(0xFFFF_FFFF >> shift);
+        self.write_reg(rt, kept | (word >> shift));
+    }
+
+    #[allow(non_snake_case)]
+    fn SWL(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 1, true) {
+            Some(physical) => physical,
+            None => return,
+        };
+        let word = self.MEM.read_word(physical & !3);
+        let shift = (3 - (address & 3)) * 8;
+
+        let kept = word & !(0xFFFF_FFFF >> shift);
+        self.MEM.write_word(physical & !3, kept | (self.read_reg(rt) >> shift));
+    }
+
+    #[allow(non_snake_case)]
+    fn SWR(&mut self, base: u8, rt: u8, offset: u16) {
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 1, true) {
+            Some(physical) => physical,
+            None => return,
+        };
+        let word = self.MEM.read_word(physical & !3);
+        let shift = (address & 3) * 8;
+
+        let kept = word & ((1u64 << shift) - 1) as u32;
+        self.MEM.write_word(physical & !3, kept | (self.read_reg(rt) << shift));
+    }
+
+    #[allow(non_snake_case)]
+    fn SC(&mut self, base: u8, rt: u8, offset: u16) {
+        //there is only one CPU, so the store always succeeds, unless it raises an exception
+        let address = self.effective_address(base, offset);
+        let physical = match self.data_access(address, 4, true) {
+            Some(physical) => physical,
+            None => return,
+        };
+        self.MEM.write_word(physical, self.read_reg(rt));
+        self.write_reg(rt, 1);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::assembler::assemble_line;
+    use crate::eic::PriorityController;
+    use crate::mpu::{READ, WRITE};
+
+    pub(crate) const TEXT: u32 = 0x0040_0000;
+    pub(crate) const VECTOR: u32 = 0x8000_0180;
+
+    //write instructions to memory one after the other
+    pub(crate) fn write(cpu: &mut CPU, address: u32, lines: &[&str]) {
+        let mut address = address;
+        for line in lines {
+            for word in assemble_line(line, address).unwrap() {
+                cpu.MEM.write_word(address, word);
+                address += 4;
+            }
+        }
+    }
+
+    //a machine with a program at the .text base, reset and not tracing
+    pub(crate) fn cpu(program: &[&str]) -> CPU {
+        let mut cpu = CPU::new(RAM::new(), Translation::Identity);
+        cpu.trace = false;
+        write(&mut cpu, TEXT, program);
+        cpu.reset();
+        cpu
+    }
+
+    pub(crate) fn cp0(cpu: &CPU, register: u8) -> u32 {
+        cpu.CP0.read(register, 0, cpu.cycles())
+    }
+
+    pub(crate) fn run(cpu: &mut CPU, n: usize) {
+        for _ in 0..n {
+            cpu.clock();
+        }
+    }
+
+    #[test]
+    fn fault_in_delay_slot() {
+        let mut cpu = cpu(&["beq $zero, $zero, 0x00400008", "lw $t0, 1($zero)", "nop"]);
+        //anything at the vector counts as a handler
+        write(&mut cpu, VECTOR, &["nop", "eret"]);
+        run(&mut cpu, 2);
+
+        assert_eq!(cpu.PC, VECTOR);
+        assert_eq!(cp0(&cpu, 14), TEXT, "EPC points at the branch");
+        assert_eq!(cp0(&cpu, 13) >> 31, 1, "Cause.BD is set");
+        assert_eq!(Exception::from_cause(cp0(&cpu, 13)), Some(Exception::AddressLoad));
+        assert_eq!(cp0(&cpu, 8), 1, "BadVAddr is the misaligned address");
+        assert_eq!(cp0(&cpu, 12) & 0b10, 0b10, "Status.EXL is set");
+    }
+
+    #[test]
+    fn eret_returns_to_epc() {
+        let mut cpu = cpu(&["syscall", "addiu $s0, $zero, 1"]);
+        //step EPC past the syscall, like a real handler would
+        write(&mut cpu, VECTOR, &["mfc0 $k0, $14", "addiu $k0, $k0, 4", "mtc0 $k0, $14", "eret"]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.PC, VECTOR);
+        assert_eq!(cp0(&cpu, 14), TEXT);
+
+        run(&mut cpu, 4);
+        assert_eq!(cpu.PC, TEXT + 4);
+        assert_eq!(cp0(&cpu, 12) & 0b10, 0, "Status.EXL is clear again");
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.read_reg(16), 1);
+    }
+
+    #[test]
+    fn default_handler_skips() {
+        let mut cpu = cpu(&["syscall", "addiu $s0, $zero, 1"]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.PC, TEXT + 4);
+        assert!(!cpu.stopped);
+        assert_eq!(cp0(&cpu, 12) & 0b10, 0);
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.read_reg(16), 1);
+    }
+
+    #[test]
+    fn default_handler_skips_to_branch_target() {
+        let mut cpu = cpu(&["beq $zero, $zero, 0x0040000c", "syscall", "addiu $s0, $zero, 1", "addiu $s1, $zero, 1"]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.PC, TEXT + 12, "the delay slot is skipped, the branch still taken");
+
+        run(&mut cpu, 1);
+        assert_eq!((cpu.read_reg(16), cpu.read_reg(17)), (0, 1));
+    }
+
+    #[test]
+    fn default_handler_halts() {
+        let mut cpu = cpu(&["nop", "lw $t0, 1($zero)", "addiu $s0, $zero, 1"]);
+        run(&mut cpu, 3);
+        assert!(cpu.stopped);
+        assert_eq!(cpu.PC, TEXT + 4);
+        assert_eq!(cpu.read_reg(16), 0);
+    }
+
+    #[test]
+    fn default_handler_halts_at_interrupt() {
+        //IE and IM2, the first hardware interrupt line
+        let mut cpu = cpu(&["addiu $t0, $zero, 0x0401", "mtc0 $t0, $12", "addiu $s0, $zero, 1"]);
+        run(&mut cpu, 2);
+        cpu.set_interrupt_line(0, true);
+        run(&mut cpu, 1);
+        assert!(cpu.stopped);
+        assert_eq!(cpu.PC, TEXT + 8);
+        assert_eq!(cpu.read_reg(16), 0, "the interrupted instruction doesn't run");
+    }
+
+    #[test]
+    fn perf_counters_count_per_mode() {
+        //counter 0 counts instructions in kernel mode, counter 1 at exception level
+        let mut cpu = cpu(&["addiu $t0, $zero, 0x22", "mtc0 $t0, $25, 0", "addiu $t0, $zero, 0x21", "mtc0 $t0, $25, 2", "syscall", "nop"]);
+        write(&mut cpu, VECTOR, &["mfc0 $k0, $14", "addiu $k0, $k0, 4", "mtc0 $k0, $14", "eret"]);
+        run(&mut cpu, 10);
+        assert_eq!(cpu.PC, TEXT + 24);
+        assert_eq!(cpu.CP0.read(25, 1, cpu.cycles()), 4, "the main program after enabling the counter");
+        assert_eq!(cpu.CP0.read(25, 3, cpu.cycles()), 4, "the exception handler");
+    }
+
+    #[test]
+    fn perf_counter_overflow_raises_pci() {
+        //count instructions in kernel mode with the interrupt enabled, two short of bit 31
+        let mut cpu = cpu(&["lui $t0, 0x7fff", "ori $t0, $t0, 0xfffe", "mtc0 $t0, $25, 1", "addiu $t0, $zero, 0x32", "mtc0 $t0, $25, 0", "nop", "nop"]);
+        run(&mut cpu, 6);
+        assert_eq!(cp0(&cpu, 13) & (1 << 26), 0);
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.CP0.read(25, 1, cpu.cycles()), 0x8000_0000);
+        assert_eq!(cp0(&cpu, 13) & (1 << 26), 1 << 26, "Cause.PCI is set");
+        assert_eq!(cp0(&cpu, 13) & (1 << 15), 1 << 15, "and pending on IP7");
+
+        //clearing the counter takes the request back
+        cpu.CP0.write(25, 1, 0, cpu.cycles());
+        assert_eq!(cp0(&cpu, 13) & (1 << 26 | 1 << 15), 0);
+    }
+
+    #[test]
+    fn perf_counters_select_events() {
+        //counter 0 counts loads, counter 1 branches
+        let mut cpu = cpu(&["addiu $t0, $zero, 0x62", "mtc0 $t0, $25, 0", "addiu $t0, $zero, 0x42", "mtc0 $t0, $25, 2",
+            "lw $t1, 0($sp)", "sw $t1, 4($sp)", "beq $zero, $zero, 0x00400020", "lw $t1, 8($sp)", "lw $t1, 12($sp)"]);
+        run(&mut cpu, 9);
+        assert_eq!(cpu.CP0.read(25, 1, cpu.cycles()), 3);
+        assert_eq!(cpu.CP0.read(25, 3, cpu.cycles()), 1);
+        assert_eq!(cpu.CP0.read(25, 0, cpu.cycles()), 0x8000_0062, "PerfCtl0.M says there is a second counter");
+        assert_eq!(cpu.CP0.read(25, 2, cpu.cycles()), 0x42);
+    }
+
+    #[test]
+    fn timer_interrupt() {
+        //Compare = 5 is reached after 10 cycles, writing it again acknowledges the interrupt
+        let mut program = vec!["addiu $t0, $zero, 5", "mtc0 $t0, $11"];
+        program.extend(["nop"; 10]);
+        program.push("mtc0 $t0, $11");
+        let mut cpu = cpu(&program);
+
+        run(&mut cpu, 9);
+        assert_eq!(cp0(&cpu, 13) & (1 << 15), 0);
+        run(&mut cpu, 3);
+        assert_eq!(cp0(&cpu, 9), 6);
+        assert_eq!(cp0(&cpu, 13) & (1 << 30 | 1 << 15), 1 << 30 | 1 << 15, "Cause.TI and IP7 are set");
+        assert!(!cpu.stopped, "interrupts are disabled");
+
+        run(&mut cpu, 1);
+        assert_eq!(cp0(&cpu, 13) & (1 << 30 | 1 << 15), 0);
+    }
+
+    #[test]
+    fn reset_restarts_count() {
+        let mut cpu = cpu(&["nop"; 20]);
+        run(&mut cpu, 20);
+        assert_eq!(cp0(&cpu, 9), 10);
+
+        cpu.reset();
+        assert_eq!(cp0(&cpu, 9), 0);
+        run(&mut cpu, 4);
+        assert_eq!(cp0(&cpu, 9), 2);
+    }
+
+    #[test]
+    fn eic_vectors() {
+        for (spacing, vs) in [(0, 0), (32, 1), (64, 2), (512, 16)] {
+            //Cause.IV, IntCtl.VS and then Status.IE
+            let intctl = format!("addiu $t0, $zero, {}", vs << 5);
+            let mut cpu = cpu(&["lui $t0, 0x0080", "mtc0 $t0, $13", &intctl, "mtc0 $t0, $12, 1", "addiu $t0, $zero, 1", "mtc0 $t0, $12", "nop"]);
+            cpu.set_interrupt_controller(Some(Box::new(PriorityController::new())));
+            cpu.set_interrupt_priority(20, 5);
+            cpu.set_interrupt_priority(30, 3);
+            let handler = 0x8000_0200 + 20 * spacing;
+            write(&mut cpu, handler, &["nop", "eret"]);
+
+            run(&mut cpu, 6);
+            cpu.set_interrupt_line(30, true);
+            cpu.set_interrupt_line(20, true);
+            //the interrupt is taken at the start of the clock, which goes on with the handler's first instruction
+            run(&mut cpu, 1);
+            assert_eq!(cpu.PC, handler + 4, "source 20 has the higher level, VS = {}", vs);
+            assert_eq!((cp0(&cpu, 13) >> 10) & 0x3F, 5, "Cause.RIPL is its level");
+            assert_eq!(cp0(&cpu, 14), TEXT + 24);
+        }
+    }
+
+    #[test]
+    fn user_mode_kernel_address() {
+        //Status.UM, then a load from kseg0
+        let mut cpu = cpu(&["addiu $t0, $zero, 0x10", "mtc0 $t0, $12", "lui $t1, 0x8000", "lw $t2, 0($t1)"]);
+        write(&mut cpu, VECTOR, &["nop", "eret"]);
+        run(&mut cpu, 4);
+        assert_eq!(cpu.PC, VECTOR);
+        assert_eq!(Exception::from_cause(cp0(&cpu, 13)), Some(Exception::AddressLoad));
+        assert_eq!(cp0(&cpu, 8), 0x8000_0000);
+        assert_eq!(cp0(&cpu, 14), TEXT + 12);
+    }
+
+    #[test]
+    fn user_mode_coprocessor_unusable() {
+        //coprocessor 0 is off limits in user mode too, unless Status.CU0 allows it
+        for (status, allowed) in [("addiu $t0, $zero, 0x10", false), ("lui $t0, 0x1000", true)] {
+            let cu0 = if allowed { "ori $t0, $t0, 0x10" } else { "nop" };
+            let mut cpu = cpu(&[status, cu0, "mtc0 $t0, $12", "mfc0 $t1, $12"]);
+            write(&mut cpu, VECTOR, &["nop", "eret"]);
+            run(&mut cpu, 4);
+            if allowed {
+                assert_eq!((cpu.PC, cpu.GPR[9]), (TEXT + 16, 0x1000_0010));
+            } else {
+                assert_eq!(cpu.PC, VECTOR);
+                assert_eq!(Exception::from_cause(cp0(&cpu, 13)), Some(Exception::CoprocessorUnusable));
+                assert_eq!(cp0(&cpu, 14), TEXT + 12);
+                assert_eq!(cpu.GPR[9], 0);
+            }
+        }
+    }
+
+    #[test]
+    fn mpu_exceptions() {
+        for (access, exception) in [("lw $t1, 4($t0)", Exception::ReadInhibit), ("sw $t1, 4($t0)", Exception::TlbModified)] {
+            let mut cpu = cpu(&["lui $t0, 0x1001", access]);
+            write(&mut cpu, VECTOR, &["nop", "eret"]);
+            cpu.set_mpu(true);
+            cpu.set_mpu_region(0, Some(Region { base: 0x1001_0000, size: 0x1000, kernel: 0, user: 0 }));
+            run(&mut cpu, 2);
+            assert_eq!(cpu.PC, VECTOR);
+            assert_eq!(Exception::from_cause(cp0(&cpu, 13)), Some(exception));
+            assert_eq!((cp0(&cpu, 8), cp0(&cpu, 14)), (0x1001_0004, TEXT + 4));
+        }
+    }
+
+    #[test]
+    fn mpu_execute_inhibit() {
+        let mut cpu = cpu(&["nop", "nop", "nop"]);
+        write(&mut cpu, VECTOR, &["nop", "eret"]);
+        cpu.set_mpu(true);
+        cpu.set_mpu_region(3, Some(Region { base: TEXT + 8, size: 4, kernel: READ | WRITE, user: 0 }));
+        run(&mut cpu, 3);
+        assert_eq!(cpu.PC, VECTOR);
+        assert_eq!(Exception::from_cause(cp0(&cpu, 13)), Some(Exception::ExecuteInhibit));
+        assert_eq!((cp0(&cpu, 8), cp0(&cpu, 14)), (TEXT + 8, TEXT + 8));
+    }
+
+    #[test]
+    fn failed_fetch_is_not_counted() {
+        let mut cpu = cpu(&["lui $t0, 0x0040", "ori $t0, $t0, 2", "jr $t0", "nop"]);
+        write(&mut cpu, VECTOR, &["nop", "eret"]);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.PC, VECTOR);
+        assert_eq!(Exception::from_cause(cp0(&cpu, 13)), Some(Exception::AddressLoad));
+        assert_eq!((cp0(&cpu, 8), cp0(&cpu, 14)), (TEXT + 2, TEXT + 2));
+        assert_eq!(cpu.instructions, 4);
+    }
+
+    #[test]
+    fn overflow_leaves_destination_alone() {
+        for inst in ["add $t2, $t0, $t1", "addi $t2, $t0, 1", "sub $t2, $t3, $t1"] {
+            //t0 = 0x7FFFFFFF, t1 = 1, t3 = 0x80000000 and t2 = 5 before the overflow
+            let mut cpu = cpu(&["lui $t0, 0x7fff", "ori $t0, $t0, 0xffff", "addiu $t1, $zero, 1", "lui $t3, 0x8000", "addiu $t2, $zero, 5", inst]);
+            write(&mut cpu, VECTOR, &["nop", "eret"]);
+            run(&mut cpu, 6);
+            assert_eq!(cpu.PC, VECTOR, "{}", inst);
+            assert_eq!(Exception::from_cause(cp0(&cpu, 13)), Some(Exception::Overflow));
+            assert_eq!(cp0(&cpu, 14), TEXT + 20);
+            assert_eq!(cpu.GPR[10], 5, "{} doesn't write its destination", inst);
+        }
+    }
+
+    #[test]
+    fn overflow_in_delay_slot() {
+        //EPC is the branch
+        let mut cpu = cpu(&["lui $t0, 0x7fff", "ori $t0, $t0, 0xffff", "beq $zero, $zero, 0x00400014", "addi $t2, $t0, 1", "nop", "nop"]);
+        write(&mut cpu, VECTOR, &["nop", "eret"]);
+        run(&mut cpu, 4);
+        assert_eq!(Exception::from_cause(cp0(&cpu, 13)), Some(Exception::Overflow));
+        assert_eq!((cp0(&cpu, 14), cp0(&cpu, 13) >> 31), (TEXT + 8, 1));
+    }
+
+    #[test]
+    fn unsigned_arithmetic_wraps() {
+        let mut cpu = cpu(&["lui $t0, 0x7fff", "ori $t0, $t0, 0xffff", "addiu $t1, $zero, 1", "addu $t2, $t0, $t1", "addiu $t3, $t0, 1"]);
+        run(&mut cpu, 5);
+        assert_eq!((cpu.GPR[10], cpu.GPR[11]), (0x8000_0000, 0x8000_0000));
+        assert_eq!(cpu.PC, TEXT + 20);
+    }
+
+    #[test]
+    fn store_to_code_invalidates_decoded_instructions() {
+        //overwrite addiu $s0, $zero, 1 with addiu $s0, $zero, 2 after it ran once, then run it again
+        let mut cpu = cpu(&["lui $t0, 0x0040", "lui $t1, 0x2410", "ori $t1, $t1, 2", "addiu $s0, $zero, 1",
+            "sw $t1, 12($t0)", "j 0x0040000c", "nop"]);
+        run(&mut cpu, 4);
+        assert_eq!(cpu.GPR[16], 1);
+
+        run(&mut cpu, 4);
+        assert_eq!(cpu.PC, TEXT + 16);
+        assert_eq!(cpu.GPR[16], 2);
+    }
+}
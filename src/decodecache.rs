@@ -0,0 +1,56 @@
+use crate::instruction::Instruction;
+use crate::ram::{RAM, PAGE_BITS};
+
+const PAGE_WORDS: usize = 1 << (PAGE_BITS - 2);
+
+// decoded instructions of one page, valid as long as the page's version in RAM doesn't change
+struct CachedPage {
+    version: u32,
+    entries: Vec<Option<Instruction>>,
+}
+
+pub struct DecodeCache {
+    pages: Vec<Option<Box<CachedPage>>>,    //indexed by page number
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl DecodeCache {
+    //construct a new, empty cache
+    pub fn new() -> DecodeCache {
+        let mut pages = Vec::new();
+        pages.resize_with(1 << (32 - PAGE_BITS), || None);
+        DecodeCache { pages, hits: 0, misses: 0 }
+    }
+
+    //get the decoded instruction at an address, decoding it if it isn't cached yet
+    pub fn fetch(&mut self, ram: &RAM, address: u32) -> Instruction {
+        let page_number = (address >> PAGE_BITS) as usize;
+        let index = ((address as usize) >> 2) & (PAGE_WORDS - 1);
+        let version = ram.page_version(address);
+
+        //throw away pages that have been written to since they were decoded
+        let page = match &mut self.pages[page_number] {
+            Some(page) if page.version == version => page,
+            slot => slot.insert(Box::new(CachedPage { version, entries: vec![None; PAGE_WORDS] })),
+        };
+
+        match page.entries[index] {
+            Some(instruction) => {
+                self.hits += 1;
+                instruction
+            }
+            None => {
+                self.misses += 1;
+                let instruction = Instruction::decode(ram.read_word(address));
+                page.entries[index] = Some(instruction);
+                instruction
+            }
+        }
+    }
+
+    //forget everything decoded from the page containing address (SYNCI, CACHE)
+    pub fn invalidate(&mut self, address: u32) {
+        self.pages[(address >> PAGE_BITS) as usize] = None;
+    }
+}
@@ -0,0 +1,67 @@
+// The built-in exception handler, for programs that don't bring their own.
+//
+// When there is nothing at the exception vector rem handles the exception on the host side: it
+// prints which exception happened where and then either carries on after the instruction or stops
+// the program, picked per exception code. After an instruction in a delay slot means wherever its
+// branch went. By default things a program does on purpose (syscall, break, trap) are skipped and
+// everything that means it went wrong stops it.
+
+use crate::exceptionprocessor::Exception;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Skip,   //print the exception and continue after the instruction, or where an interrupt hit
+    Halt,   //print the exception and stop the program at the instruction
+}
+
+impl Action {
+    //parse skip or halt
+    pub fn parse(word: &str) -> Result<Action, String> {
+        match word {
+            "skip" => Ok(Action::Skip),
+            "halt" => Ok(Action::Halt),
+            _ => Err(format!("'{}' is no action, use skip or halt", word)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Action::Skip => "skip",
+            Action::Halt => "halt",
+        }
+    }
+}
+
+pub struct DefaultHandler {
+    actions: [Action; 32],  //indexed by ExcCode
+}
+
+impl DefaultHandler {
+    //construct a handler with the default actions
+    pub fn new() -> DefaultHandler {
+        let mut actions = [Action::Halt; 32];
+        for exception in [Exception::Syscall, Exception::Breakpoint, Exception::Trap] {
+            actions[exception as usize] = Action::Skip;
+        }
+        DefaultHandler { actions }
+    }
+
+    //what to do about an exception
+    pub fn action(&self, exception: Exception) -> Action {
+        self.actions[exception as usize]
+    }
+
+    //change what to do about an exception
+    pub fn set_action(&mut self, exception: Exception, action: Action) {
+        self.actions[exception as usize] = action;
+    }
+
+    //print the action for every exception
+    pub fn print(&self) {
+        println!("\t----- DEFAULT EXCEPTION HANDLER -----\t");
+        println!("Code\tName\tAction\tException");
+        for exception in Exception::ALL {
+            println!("{:>2}\t{}\t{}\t{}", exception as u32, exception.mnemonic(), self.action(exception).name(), exception.describe());
+        }
+    }
+}
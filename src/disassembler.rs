@@ -0,0 +1,194 @@
+use crate::instruction::{self, Instruction, Op, Syntax, REGISTER_NAMES};
+
+// the flavour of assembly to print
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Gnu,    //like objdump -d: bare register names, hex targets and the usual aliases (move, li, b, ...)
+    Mars,   //like the MARS text segment window: $-prefixed names, 0x-padded targets, no aliases
+}
+
+impl Style {
+    //print a register in the style's notation
+    fn reg(self, number: u8) -> String {
+        match self {
+            Style::Gnu if number == 30 => "s8".to_string(),    //objdump calls the frame pointer s8
+            Style::Gnu => REGISTER_NAMES[number as usize].to_string(),
+            Style::Mars => format!("${}", REGISTER_NAMES[number as usize]),
+        }
+    }
+
+    //separator between operands
+    fn sep(self) -> &'static str {
+        match self {
+            Style::Gnu => ",",
+            Style::Mars => ", ",
+        }
+    }
+
+    //print a resolved branch or jump target
+    fn target(self, address: u32) -> String {
+        match self {
+            Style::Gnu => format!("{:x}", address),
+            Style::Mars => format!("0x{:08x}", address),
+        }
+    }
+
+    //print an unsigned (zero extended) immediate
+    fn uimm(self, imm: u16) -> String {
+        match self {
+            Style::Gnu => format!("0x{:x}", imm),
+            Style::Mars => format!("0x{:08x}", imm),
+        }
+    }
+
+    //glue mnemonic and operands together
+    fn line(self, mnemonic: &str, operands: &[String]) -> String {
+        if operands.is_empty() {
+            return mnemonic.to_string();
+        }
+
+        match self {
+            Style::Gnu => format!("{}\t{}", mnemonic, operands.join(self.sep())),
+            Style::Mars => format!("{} {}", mnemonic, operands.join(self.sep())),
+        }
+    }
+}
+
+// disassemble one instruction word located at `address` (needed to resolve branch targets)
+pub fn disassemble(word: u32, address: u32, style: Style) -> String {
+    //the canonical nop is the same everywhere
+    if word == 0 {
+        return "nop".to_string();
+    }
+
+    let info = match instruction::lookup(word) {
+        Some(info) => info,
+        None => return style.line(".word", &[format!("0x{:08x}", word)]),
+    };
+
+    let inst = Instruction::decode(word);
+
+    if style == Style::Gnu {
+        if let Some(alias) = gnu_alias(&inst, address) {
+            return alias;
+        }
+    }
+
+    let reg = |number: u8| style.reg(number);
+    let offset_base = || format!("{}({})", inst.simm(), reg(inst.rs));
+
+    let operands: Vec<String> = match info.syntax {
+        Syntax::None => vec![],
+        Syntax::Code => {
+            let code = (word >> 6) & 0xF_FFFF;
+            if code != 0 { vec![code.to_string()] } else { vec![] }
+        }
+        Syntax::RdRsRt => vec![reg(inst.rd), reg(inst.rs), reg(inst.rt)],
+        Syntax::RdRtRs => vec![reg(inst.rd), reg(inst.rt), reg(inst.rs)],
+        Syntax::RdRtSa => vec![reg(inst.rd), reg(inst.rt), inst.sa.to_string()],
+        Syntax::RdRs => vec![reg(inst.rd), reg(inst.rs)],
+        Syntax::RdRt => vec![reg(inst.rd), reg(inst.rt)],
+        Syntax::RsRt => vec![reg(inst.rs), reg(inst.rt)],
+        Syntax::Rd => vec![reg(inst.rd)],
+        Syntax::Rs => vec![reg(inst.rs)],
+        Syntax::RdRsJalr => vec![reg(inst.rd), reg(inst.rs)],
+        Syntax::RtRsImm => vec![reg(inst.rt), reg(inst.rs), inst.simm().to_string()],
+        Syntax::RtRsUimm => vec![reg(inst.rt), reg(inst.rs), style.uimm(inst.imm)],
+        Syntax::RtUimm => vec![reg(inst.rt), style.uimm(inst.imm)],
+        Syntax::RsRtBranch => vec![reg(inst.rs), reg(inst.rt), style.target(inst.branch_target(address))],
+        Syntax::RsBranch => vec![reg(inst.rs), style.target(inst.branch_target(address))],
+        Syntax::Jump => vec![style.target(inst.jump_target(address))],
+        Syntax::RtMem => vec![reg(inst.rt), offset_base()],
+        Syntax::HintMem => vec![inst.rt.to_string(), offset_base()],
+        Syntax::Mem => vec![offset_base()],
+        Syntax::Cop0 => {
+            let mut operands = vec![reg(inst.rt), format!("${}", inst.rd)];
+            let sel = word & 0x7;
+            if sel != 0 {
+                operands.push(sel.to_string());
+            }
+            operands
+        }
+        Syntax::RtOpt => if inst.rt != 0 { vec![reg(inst.rt)] } else { vec![] },
+        Syntax::ExtIns => {
+            //ext stores size - 1 in rd, ins stores the most significant bit
+            let size = if info.op == Op::EXT { inst.rd as i32 + 1 } else { inst.rd as i32 + 1 - inst.sa as i32 };
+            vec![reg(inst.rt), reg(inst.rs), inst.sa.to_string(), size.to_string()]
+        }
+    };
+
+    style.line(info.name, &operands)
+}
+
+// objdump prints some common idioms under their pseudo instruction names
+fn gnu_alias(inst: &Instruction, address: u32) -> Option<String> {
+    let style = Style::Gnu;
+    let reg = |number: u8| style.reg(number);
+    let target = |inst: &Instruction| style.target(inst.branch_target(address));
+
+    let (mnemonic, operands) = match inst.op? {
+        Op::ADDU | Op::OR if inst.rt == 0 => ("move", vec![reg(inst.rd), reg(inst.rs)]),
+        Op::SUBU if inst.rs == 0 => ("negu", vec![reg(inst.rd), reg(inst.rt)]),
+        Op::NOR if inst.rt == 0 => ("not", vec![reg(inst.rd), reg(inst.rs)]),
+        Op::ADDIU if inst.rs == 0 => ("li", vec![reg(inst.rt), inst.simm().to_string()]),
+        Op::ORI if inst.rs == 0 => ("li", vec![reg(inst.rt), style.uimm(inst.imm)]),
+        Op::BEQ if inst.rs == 0 && inst.rt == 0 => ("b", vec![target(inst)]),
+        Op::BEQ if inst.rt == 0 => ("beqz", vec![reg(inst.rs), target(inst)]),
+        Op::BNE if inst.rt == 0 => ("bnez", vec![reg(inst.rs), target(inst)]),
+        Op::BGEZAL if inst.rs == 0 => ("bal", vec![target(inst)]),
+        Op::JALR if inst.rd == 31 => ("jalr", vec![reg(inst.rs)]),
+        _ => return None,
+    };
+
+    Some(style.line(mnemonic, &operands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: u32 = 0x0040_0000;
+
+    fn both(word: u32, address: u32) -> (String, String) {
+        (disassemble(word, address, Style::Gnu), disassemble(word, address, Style::Mars))
+    }
+
+    #[test]
+    fn branches() {
+        //beq $t1, $zero, back 5 instructions from the delay slot
+        assert_eq!(both(0x1120_FFFB, TEXT + 16), ("beqz\tt1,400000".to_string(), "beq $t1, $zero, 0x00400000".to_string()));
+        assert_eq!(both(0x1000_0003, TEXT), ("b\t400010".to_string(), "beq $zero, $zero, 0x00400010".to_string()));
+        assert_eq!(both(0x1509_0001, TEXT), ("bne\tt0,t1,400008".to_string(), "bne $t0, $t1, 0x00400008".to_string()));
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        assert_eq!(both(0x8FA9_0008, TEXT), ("lw\tt1,8(sp)".to_string(), "lw $t1, 8($sp)".to_string()));
+        assert_eq!(both(0xAFBF_FFFC, TEXT), ("sw\tra,-4(sp)".to_string(), "sw $ra, -4($sp)".to_string()));
+        assert_eq!(both(0x83DE_0001, TEXT), ("lb\ts8,1(s8)".to_string(), "lb $fp, 1($fp)".to_string()));
+    }
+
+    #[test]
+    fn jumps() {
+        assert_eq!(both(0x0C10_0040, TEXT), ("jal\t400100".to_string(), "jal 0x00400100".to_string()));
+        //the upper 4 bits come from the delay slot
+        assert_eq!(both(0x0800_0000, 0x8FFF_FFFC), ("j\t90000000".to_string(), "j 0x90000000".to_string()));
+        assert_eq!(both(0x03E0_0008, TEXT), ("jr\tra".to_string(), "jr $ra".to_string()));
+        assert_eq!(both(0x0100_F809, TEXT), ("jalr\tt0".to_string(), "jalr $ra, $t0".to_string()));
+    }
+
+    #[test]
+    fn specials() {
+        assert_eq!(both(0x0080_1021, TEXT), ("move\tv0,a0".to_string(), "addu $v0, $a0, $zero".to_string()));
+        assert_eq!(both(0x0009_4080, TEXT), ("sll\tt0,t1,2".to_string(), "sll $t0, $t1, 2".to_string()));
+        assert_eq!(both(0x0000_000C, TEXT), ("syscall".to_string(), "syscall".to_string()));
+        assert_eq!(both(0x0000_000D | (7 << 6), TEXT), ("break\t7".to_string(), "break 7".to_string()));
+        assert_eq!(both(0x4008_6000, TEXT), ("mfc0\tt0,$12".to_string(), "mfc0 $t0, $12".to_string()));
+        assert_eq!(both(0, TEXT), ("nop".to_string(), "nop".to_string()));
+    }
+
+    #[test]
+    fn unknown_words() {
+        assert_eq!(both(0xFC00_0000, TEXT), (".word\t0xfc000000".to_string(), ".word 0xfc000000".to_string()));
+    }
+}
@@ -0,0 +1,64 @@
+// External interrupt controllers for EIC mode.
+//
+// With a controller attached the core stops prioritizing interrupts itself: the controller looks
+// at the requests of the devices and the core's own interrupts, picks the one that should be
+// handled next and presents its priority level and vector number. The core takes it as soon as
+// the level is above the one in Status.IPL.
+
+// the core's own interrupt requests handed to the controller, as bits of the core argument
+pub const CORE_TIMER: u8 = 1 << 0;
+pub const CORE_SOFTWARE0: u8 = 1 << 1;
+pub const CORE_SOFTWARE1: u8 = 1 << 2;
+pub const CORE_PERFORMANCE: u8 = 1 << 3;
+
+pub trait InterruptController {
+    fn name(&self) -> String;
+    //a device asserts or deasserts one of the request sources
+    fn set_source(&mut self, source: u8, asserted: bool);
+    //change the priority level (1-63) of a source
+    fn set_priority(&mut self, source: u8, level: u8);
+    //the interrupt to present to the core as (priority level, vector number), if any
+    fn request(&self, core: u8) -> Option<(u8, u16)>;
+}
+
+// a controller after the PIC32 EVIC: 64 sources with a vector each, the core timer is source 0
+// and the core software interrupts are sources 1 and 2. Of the sources with the highest level
+// the lowest numbered one wins.
+pub struct PriorityController {
+    levels: [u8; 64],
+    asserted: u64,
+}
+
+impl PriorityController {
+    //construct a controller with every source at level 1 and nothing asserted
+    pub fn new() -> PriorityController {
+        PriorityController { levels: [1; 64], asserted: 0 }
+    }
+}
+
+impl InterruptController for PriorityController {
+    fn name(&self) -> String {
+        "priority controller (64 sources)".to_string()
+    }
+
+    fn set_source(&mut self, source: u8, asserted: bool) {
+        let bit = 1 << (source % 64);
+        self.asserted = if asserted { self.asserted | bit } else { self.asserted & !bit };
+    }
+
+    fn set_priority(&mut self, source: u8, level: u8) {
+        self.levels[source as usize % 64] = level.clamp(1, 63);
+    }
+
+    fn request(&self, core: u8) -> Option<(u8, u16)> {
+        let core = (core & CORE_TIMER != 0) as u64
+            | ((core & CORE_SOFTWARE0 != 0) as u64) << 1
+            | ((core & CORE_SOFTWARE1 != 0) as u64) << 2;
+        let asserted = self.asserted | core;
+
+        (0..64u16)
+            .filter(|&source| asserted & (1 << source) != 0)
+            .max_by_key(|&source| (self.levels[source as usize], std::cmp::Reverse(source)))
+            .map(|source| (self.levels[source as usize], source))
+    }
+}
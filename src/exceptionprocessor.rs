@@ -1,27 +1,627 @@
-use crate::cpu::CPU;
-
-pub struct ExceptionProcessor {
-    BadVAddr: u32,  //Memory address where exception occured
-    Status: u32,    //Interrupt mask, enable bits and status when exception occured
-    Cause: u32,     //Type of exception and pending interrupt bits
-    EPC: u32        //Address of instruction that caused exception
-
-                    //Note: the exception handler itself usually resides in 0x8000_0080
-}
-
-impl ExceptionProcessor {
-    //construct a new ExceptionProcessor
-    pub fn new() -> ExceptionProcessor {
-        ExceptionProcessor {BadVAddr: 0, Status: 0, Cause: 0, EPC: 0}
-    }
-
-    pub fn throw_exception(&self) {
-        println!("Oh no! An exception occured and I simply cannot vibe with it.");
-    }
-
-    pub fn syscall(&self, cpu: &mut CPU) {
-        let syscall_number = cpu.GPR[2]; //read the syscall number from $v0
-
-
-    }
-}
\ No newline at end of file
+use crate::eic::{self, InterruptController};
+use crate::mmu::{self, Access, Fault, Tlb, Translation, TLB_ENTRIES};
+use crate::mpu::Mpu;
+
+// things the performance counters can count, numbered like their EventSel value
+// (both counters take the same events here, on real cores the two halves differ a bit)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Cycles = 0,
+    Instructions = 1,   //instructions completed
+    Branches = 2,       //branches and jumps
+    Loads = 3,
+    Stores = 4,
+    ICacheMisses = 9,   //only with the cache model on
+    DCacheMisses = 10,  //only with the cache model on
+    Stalls = 18,        //pipeline and cache miss stall cycles
+}
+
+// what caused an exception, numbered like Cause.ExcCode
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exception {
+    Interrupt = 0,
+    TlbModified = 1,            //Mod: a store to a page that isn't dirty, or to an MPU region without write permission
+    TlbLoad = 2,                //TLBL: no valid TLB entry for a load or fetch (a refill if there is none at all)
+    TlbStore = 3,               //TLBS: no valid TLB entry for a store
+    AddressLoad = 4,            //AdEL: misaligned load or fetch, or one from kernel space in user mode
+    AddressStore = 5,           //AdES: misaligned store, or one to kernel space in user mode
+    Syscall = 8,
+    Breakpoint = 9,
+    ReservedInstruction = 10,   //an instruction word we can't decode
+    CoprocessorUnusable = 11,   //CpU: a coprocessor 0 instruction in user mode without Status.CU0
+    Overflow = 12,              //signed overflow in add, addi or sub
+    Trap = 13,                  //a trap instruction's condition was true
+    ReadInhibit = 19,           //TLBRI: a load from an MPU region without read permission
+    ExecuteInhibit = 20,        //TLBXI: a fetch from an MPU region without execute permission
+}
+
+impl Exception {
+    //all exceptions we raise, in ExcCode order
+    pub const ALL: [Exception; 14] = [Exception::Interrupt, Exception::TlbModified, Exception::TlbLoad, Exception::TlbStore,
+        Exception::AddressLoad, Exception::AddressStore, Exception::Syscall, Exception::Breakpoint, Exception::ReservedInstruction,
+        Exception::CoprocessorUnusable, Exception::Overflow, Exception::Trap, Exception::ReadInhibit, Exception::ExecuteInhibit];
+
+    //the exception with an ExcCode, if we raise it at all
+    pub fn from_code(code: u32) -> Option<Exception> {
+        Exception::ALL.iter().copied().find(|&exception| exception as u32 == code)
+    }
+
+    //the exception Cause.ExcCode says was taken last
+    pub fn from_cause(cause: u32) -> Option<Exception> {
+        Exception::from_code((cause & CAUSE_EXCCODE_MASK) >> CAUSE_EXCCODE_SHIFT)
+    }
+
+    //the mnemonic the MIPS manuals use for it
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::Interrupt => "Int",
+            Exception::TlbModified => "Mod",
+            Exception::TlbLoad => "TLBL",
+            Exception::TlbStore => "TLBS",
+            Exception::AddressLoad => "AdEL",
+            Exception::AddressStore => "AdES",
+            Exception::Syscall => "Sys",
+            Exception::Breakpoint => "Bp",
+            Exception::ReservedInstruction => "RI",
+            Exception::CoprocessorUnusable => "CpU",
+            Exception::Overflow => "Ov",
+            Exception::Trap => "Tr",
+            Exception::ReadInhibit => "TLBRI",
+            Exception::ExecuteInhibit => "TLBXI",
+        }
+    }
+
+    //what went wrong, in words
+    pub fn describe(self) -> &'static str {
+        match self {
+            Exception::Interrupt => "interrupt",
+            Exception::TlbModified => "store to a write protected page",
+            Exception::TlbLoad => "TLB miss on load or fetch",
+            Exception::TlbStore => "TLB miss on store",
+            Exception::AddressLoad => "address error on load or fetch",
+            Exception::AddressStore => "address error on store",
+            Exception::Syscall => "syscall",
+            Exception::Breakpoint => "breakpoint",
+            Exception::ReservedInstruction => "reserved instruction",
+            Exception::CoprocessorUnusable => "coprocessor unusable",
+            Exception::Overflow => "arithmetic overflow",
+            Exception::Trap => "trap",
+            Exception::ReadInhibit => "load from a read protected region",
+            Exception::ExecuteInhibit => "fetch from an execute protected region",
+        }
+    }
+
+    //does BadVAddr hold the address it happened at?
+    pub fn has_bad_address(self) -> bool {
+        !matches!(self, Exception::Interrupt | Exception::Syscall | Exception::Breakpoint | Exception::ReservedInstruction
+            | Exception::CoprocessorUnusable | Exception::Overflow | Exception::Trap)
+    }
+}
+
+// kseg0, kseg1 and kseg2 start here, user mode only gets to use the addresses below (kuseg)
+const KERNEL_BASE: u32 = 0x8000_0000;
+
+// exception vectors are offsets from EBase, or from the boot base while Status.BEV is set
+const BOOT_EXCEPTION_BASE: u32 = 0xBFC0_0200;
+const GENERAL_OFFSET: u32 = 0x180;
+const INTERRUPT_OFFSET: u32 = 0x200;    //interrupts while Cause.IV is set, vector 0 in vectored modes
+
+// Status fields
+const STATUS_IE: u32 = 1 << 0;          //interrupts enabled
+const STATUS_EXL: u32 = 1 << 1;         //exception level, set on exception entry and cleared by eret
+const STATUS_ERL: u32 = 1 << 2;         //error level, set on reset, eret returns to ErrorEPC while it is set
+const STATUS_UM: u32 = 1 << 4;          //user mode, the M4K's KSU field only has this bit
+const STATUS_CU0: u32 = 1 << 28;        //coprocessor 0 usable in user mode too
+const STATUS_BEV: u32 = 1 << 22;        //boot exception vectors
+const STATUS_IPL_SHIFT: u32 = 10;       //in EIC mode IM7-IM2 are the priority level being handled
+
+// Cause fields
+const CAUSE_BD: u32 = 1 << 31;          //the exception happened in a branch delay slot
+const CAUSE_TI: u32 = 1 << 30;          //timer interrupt pending
+const CAUSE_IV: u32 = 1 << 23;          //interrupts use the special interrupt vector
+const CAUSE_SOFTWARE: u32 = 0x300;      //IP1 and IP0, the software interrupts
+const CAUSE_IP_SHIFT: u32 = 8;
+const CAUSE_RIPL_SHIFT: u32 = 10;       //in EIC mode IP7-IP2 are the level the controller requested
+const CAUSE_RIPL_MASK: u32 = 0x3F << CAUSE_RIPL_SHIFT;
+const CAUSE_EXCCODE_SHIFT: u32 = 2;
+const CAUSE_EXCCODE_MASK: u32 = 0x1F << CAUSE_EXCCODE_SHIFT;
+
+// PerfCtl fields
+const PERFCTL_M: u32 = 1 << 31;         //another PerfCtl/PerfCnt pair follows
+const PERFCTL_EVENT_SHIFT: u32 = 5;
+const PERFCTL_EVENT_MASK: u32 = 0x7F;
+const PERFCTL_IE: u32 = 1 << 4;         //interrupt when bit 31 of the counter gets set
+const PERFCTL_ENABLES: u32 = 0xF;       //count in user, supervisor, kernel and exception mode
+const PERFCTL_EXL: u32 = 1 << 0;
+const PERFCTL_K: u32 = 1 << 1;
+const PERFCTL_U: u32 = 1 << 3;
+const PERFCTL_WRITABLE: u32 = 0xFFF;
+
+const CAUSE_PCI: u32 = 1 << 26;         //performance counter interrupt pending
+
+// the interrupt bits, IP7-IP0 in Cause and the matching mask bits IM7-IM0 in Status
+const INTERRUPTS: u32 = 0xFF << CAUSE_IP_SHIFT;
+
+// IntCtl fields saying which hardware interrupt lines the timer and the performance counters use
+const INTCTL_IPTI_SHIFT: u32 = 29;
+const INTCTL_IPPCI_SHIFT: u32 = 26;
+const INTCTL_VS_SHIFT: u32 = 5;         //vector spacing in units of 32 bytes, 0 for a single vector
+const INTCTL_VS_MASK: u32 = 0x1F;
+
+// Config3 fields
+const CONFIG3_VINT: u32 = 1 << 5;       //vectored interrupts implemented
+const CONFIG3_VEIC: u32 = 1 << 6;       //an external interrupt controller is attached
+const EBASE_BASE: u32 = 0xFFFF_F000;    //the exception base, without CPUNum
+
+// The rest of the register file is modelled after an M4K, a MIPS32 release 2 core with fixed
+// mapping translation and no caches. Software can only change the bits in the writable masks.
+// Reset values are what the boot code would leave behind, since reset starts right in the
+// program at 0x0040_0000 instead of the boot vector: BEV and ERL are cleared. Only with a TLB ERL
+// stays set, so the program can run unmapped from kuseg until it has set up its page tables. With
+// fixed mapping the program brings its own boot code at the reset vector, so it is a real reset
+// with both of them set.
+const PRID: u32 = 0x0001_8701;          //company MIPS, processor M4K, revision 1
+const CONFIG_RESET: [u32; 4] = [
+    0x8000_0000 | (1 << 10) | (3 << 7) | 2, //Config1 follows, release 2, fixed mapping MMU, kseg0 uncached
+    0x8000_0010,                            //Config2 follows, performance counters
+    0x8000_0000,                            //Config3 follows
+    0x0000_2000 | CONFIG3_VINT,             //UserLocal and vectored interrupts implemented
+];
+const CONFIG0_MT_TLB: u32 = 1 << 7;     //MMU type: TLB instead of fixed mapping
+const CONFIG0_MT: u32 = 7 << 7;
+const CONFIG1_MMU_SIZE_SHIFT: u32 = 25; //TLB entries - 1
+const CONFIG0_WRITABLE: u32 = 0x7E00_0007;  //K23, KU and K0 cacheability fields
+const STATUS_WRITABLE: u32 = 0x1840_FF17;   //CU0, RP, BEV, IM7-IM0, UM, ERL, EXL, IE
+const CAUSE_WRITABLE: u32 = 0x0080_0300;    //IV and the software interrupt bits IP1 and IP0
+const INTCTL_RESET: u32 = (7 << INTCTL_IPTI_SHIFT) | (7 << INTCTL_IPPCI_SHIFT);    //timer and performance counter interrupts on IP7
+const INTCTL_WRITABLE: u32 = 0x0000_03E0;   //VS, the vector spacing
+const EBASE_RESET: u32 = 0x8000_0000;
+const EBASE_WRITABLE: u32 = 0x3FFF_F000;    //exception base, CPUNum is read only
+const HWRENA_WRITABLE: u32 = 0x2000_000F;   //rdhwr access to UL, CCRes, CC, SYNCI_Step and CPUNum
+
+// the registers the cp0 command shows, in register number order
+const REGISTERS: &[(u8, u8, &str)] = &[
+    (0, 0, "Index"), (1, 0, "Random"), (2, 0, "EntryLo0"), (3, 0, "EntryLo1"), (4, 0, "Context"),
+    (4, 2, "UserLocal"), (5, 0, "PageMask"), (6, 0, "Wired"), (7, 0, "HWREna"), (8, 0, "BadVAddr"), (9, 0, "Count"), (10, 0, "EntryHi"), (11, 0, "Compare"),
+    (12, 0, "Status"), (12, 1, "IntCtl"), (12, 2, "SRSCtl"), (12, 3, "SRSMap"), (13, 0, "Cause"),
+    (14, 0, "EPC"), (15, 0, "PRId"), (15, 1, "EBase"), (16, 0, "Config"), (16, 1, "Config1"),
+    (16, 2, "Config2"), (16, 3, "Config3"), (17, 0, "LLAddr"), (25, 0, "PerfCtl0"), (25, 1, "PerfCnt0"),
+    (25, 2, "PerfCtl1"), (25, 3, "PerfCnt1"), (30, 0, "ErrorEPC"), (31, 0, "DESAVE"),
+];
+
+pub struct ExceptionProcessor {
+    BadVAddr: u32,  //Memory address where exception occured
+    Status: u32,    //Interrupt mask, enable bits and status when exception occured
+    Cause: u32,     //Type of exception and pending interrupt bits, the hardware ones are added when read
+    EPC: u32,       //Address of instruction that caused exception, or of the branch before it if it sat in a delay slot
+
+                    //Note: the exception handler itself resides in 0x8000_0180
+
+    ErrorEPC: u32,  //Address to return to from the reset or an error
+    UserLocal: u32, //Thread pointer for user code, readable through rdhwr
+    HWREna: u32,    //Which hardware registers user code may read with rdhwr
+    Compare: u32,   //Count value the timer interrupt is raised at
+    IntCtl: u32,    //Interrupt vector spacing and where the timer and performance counter interrupts go
+    EBase: u32,     //Exception base for vectored interrupts
+    Config: [u32; 4],   //What the core implements, mostly read only
+    DESAVE: u32,    //Scratch register for debug handlers
+
+    count_offset: u32,  //Count is half the CPU's cycles plus this, so it never has to be ticked
+    timer_due: u64,     //cycle Count reaches Compare at, u64::MAX while the timer interrupt is pending
+    lines: u32,         //hardware interrupt lines asserted by devices, as IP2-IP7 bits
+
+    PerfCtl: [u32; 2],  //event selection and enables of the two performance counters
+    PerfCnt: [u32; 2],  //the counters themselves
+    counting: bool,     //any counter enabled at all, so events are cheap while nothing counts
+
+    controller: Option<Box<dyn InterruptController>>,   //the external interrupt controller in EIC mode
+    RIPL: u32,          //priority level of the last interrupt taken in EIC mode, shown in Cause
+
+    translation: Translation,   //how addresses are translated, fixed when the machine is built
+    tlb: Option<Tlb>,   //the TLB and its registers in TLB mode
+    mpu: Option<Mpu>,   //the memory protection unit, if it is switched on
+}
+
+impl ExceptionProcessor {
+    //construct a new ExceptionProcessor for a translation mode
+    pub fn new(translation: Translation) -> ExceptionProcessor {
+        let mut config = CONFIG_RESET;
+        let mut status = 0;
+        let tlb = match translation {
+            Translation::Tlb => {
+                config[0] = (config[0] & !CONFIG0_MT) | CONFIG0_MT_TLB;
+                config[1] |= (TLB_ENTRIES - 1) << CONFIG1_MMU_SIZE_SHIFT;
+                status = STATUS_ERL;
+                Some(Tlb::new())
+            }
+            Translation::Fixed => {
+                status = STATUS_ERL | STATUS_BEV;
+                None
+            }
+            Translation::Identity => None,
+        };
+
+        ExceptionProcessor {
+            BadVAddr: 0, Status: status, Cause: 0, EPC: 0, ErrorEPC: 0,
+            UserLocal: 0, HWREna: 0, Compare: 0, IntCtl: INTCTL_RESET, EBase: EBASE_RESET, Config: config, DESAVE: 0,
+            count_offset: 0, timer_due: timer_due(0, 0, 0), lines: 0, PerfCtl: [0; 2], PerfCnt: [0; 2], counting: false,
+            controller: None, RIPL: 0, translation, tlb, mpu: None,
+        }
+    }
+
+    //how addresses are translated
+    pub fn translation(&self) -> Translation {
+        self.translation
+    }
+
+    //switch the memory protection unit on (with no regions) or off
+    pub fn set_mpu(&mut self, mpu: Option<Mpu>) {
+        self.mpu = mpu;
+    }
+
+    //detach the memory protection unit and hand it over
+    pub fn take_mpu(&mut self) -> Option<Mpu> {
+        self.mpu.take()
+    }
+
+    //the memory protection unit, if it is on
+    pub fn mpu(&mut self) -> Option<&mut Mpu> {
+        self.mpu.as_mut()
+    }
+
+    //the TLB, if there is one
+    pub fn tlb(&mut self) -> Option<&mut Tlb> {
+        self.tlb.as_mut()
+    }
+
+    //translate a virtual address for an access in the current mode, inlined since every fetch does it
+    #[inline]
+    pub fn translate(&self, address: u32, access: Access) -> Result<u32, Fault> {
+        let user = self.user_mode();
+        if address >= KERNEL_BASE && user {
+            return Err(Fault { exception: access.exception(Exception::AddressLoad, Exception::AddressStore), refill: false });
+        }
+        if let Some(mpu) = &self.mpu {
+            mpu.check(address, access, user).map_err(|exception| Fault { exception, refill: false })?;
+        }
+
+        //kuseg isn't mapped while ERL is set, so there is a way to boot
+        let unmapped_kuseg = self.Status & STATUS_ERL != 0;
+        match (&self.tlb, self.translation) {
+            (Some(tlb), _) => tlb.translate(address, access, unmapped_kuseg),
+            (None, Translation::Fixed) => Ok(mmu::fixed_mapping(address, unmapped_kuseg)),
+            (None, _) => Ok(address),
+        }
+    }
+
+    //enter exception mode for a failed translation of bad_address, returns the handler address.
+    //TLB refills go to the vector at offset 0 unless they happen inside another handler
+    pub fn fault(&mut self, fault: Fault, address: u32, delay_slot: bool, bad_address: u32) -> u32 {
+        let refill = fault.refill && self.Status & STATUS_EXL == 0;
+        if let (Some(tlb), Exception::TlbModified | Exception::TlbLoad | Exception::TlbStore) = (&mut self.tlb, fault.exception) {
+            tlb.fault(bad_address);
+        }
+
+        let vector = self.exception(fault.exception, address, delay_slot, Some(bad_address));
+        if refill { vector - GENERAL_OFFSET } else { vector }
+    }
+
+    //enter exception mode for an exception caused by the instruction at address,
+    //returns the address of the handler to continue at
+    pub fn exception(&mut self, exception: Exception, address: u32, delay_slot: bool, bad_address: Option<u32>) -> u32 {
+        //an exception inside the handler doesn't overwrite where to return to eventually
+        if self.Status & STATUS_EXL == 0 {
+            if delay_slot {
+                self.EPC = address.wrapping_sub(4);     //restart with the branch, it has to run again anyway
+                self.Cause |= CAUSE_BD;
+            }
+            else {
+                self.EPC = address;
+                self.Cause &= !CAUSE_BD;
+            }
+        }
+
+        self.Cause = (self.Cause & !CAUSE_EXCCODE_MASK) | ((exception as u32) << CAUSE_EXCCODE_SHIFT);
+        if let Some(bad_address) = bad_address {
+            self.BadVAddr = bad_address;
+        }
+
+        //the vector number has to be picked while Status still says which interrupts are enabled
+        let vector = if exception == Exception::Interrupt { self.interrupt_vector() } else { None };
+        self.Status |= STATUS_EXL;
+
+        if self.Status & STATUS_BEV != 0 {
+            return BOOT_EXCEPTION_BASE + if vector.is_some() && self.Cause & CAUSE_IV != 0 { INTERRUPT_OFFSET } else { GENERAL_OFFSET };
+        }
+        let base = self.EBase & EBASE_BASE;
+        match vector {
+            Some(vector) if self.Cause & CAUSE_IV != 0 => base + INTERRUPT_OFFSET + vector * self.vector_spacing(),
+            _ => base + GENERAL_OFFSET,
+        }
+    }
+
+    //the vector number of the interrupt about to be taken: from the controller in EIC mode,
+    //otherwise the highest pending and unmasked IP bit. Also latches the level for Cause.RIPL
+    fn interrupt_vector(&mut self) -> Option<u32> {
+        if let Some(controller) = &self.controller {
+            let (level, vector) = controller.request(self.core_requests())?;
+            self.RIPL = level as u32;
+            return Some(vector as u32);
+        }
+
+        let pending = (self.interrupts(self.Cause) & self.Status & INTERRUPTS) >> CAUSE_IP_SHIFT;
+        Some(31u32.saturating_sub(pending.leading_zeros()))
+    }
+
+    //bytes between two interrupt vectors, 0 puts them all at the same address
+    fn vector_spacing(&self) -> u32 {
+        ((self.IntCtl >> INTCTL_VS_SHIFT) & INTCTL_VS_MASK) << 5
+    }
+
+    //leave exception (or error) mode, returns the address to continue at
+    pub fn eret(&mut self) -> u32 {
+        if self.Status & STATUS_ERL != 0 {
+            self.Status &= !STATUS_ERL;
+            return self.ErrorEPC;
+        }
+
+        self.Status &= !STATUS_EXL;
+        self.EPC
+    }
+
+    //leave exception mode for an exception handled outside of the program, like eret from a
+    //handler would. Returns where to continue: after the instruction at EPC if it is skipped
+    pub fn resume(&mut self, skip: bool) -> u32 {
+        self.Status &= !STATUS_EXL;
+        if skip { self.EPC.wrapping_add(4) } else { self.EPC }
+    }
+
+    //is the CPU in user mode? Only when UM is set and no exception or error is being handled
+    #[inline]
+    pub fn user_mode(&self) -> bool {
+        self.Status & (STATUS_UM | STATUS_EXL | STATUS_ERL) == STATUS_UM
+    }
+
+    //may coprocessor 0 instructions run in the current mode?
+    pub fn usable(&self) -> bool {
+        self.Status & STATUS_CU0 != 0 || !self.user_mode()
+    }
+
+    //disable (di) or enable (ei) interrupts, returns Status from before
+    pub fn set_interrupts(&mut self, enabled: bool) -> u32 {
+        let status = self.Status;
+        self.Status = if enabled { status | STATUS_IE } else { status & !STATUS_IE };
+        status
+    }
+
+    //read a register for MFC0 at a given CPU cycle, unknown registers read as zero
+    pub fn read(&self, register: u8, select: u8, cycles: u64) -> u32 {
+        match (register, select) {
+            (0..=6 | 10, 0) => self.tlb.as_ref().map_or(0, |tlb| tlb.read(register, cycles)),
+            (4, 2) => self.UserLocal,
+            (7, 0) => self.HWREna,
+            (8, 0) => self.BadVAddr,
+            (9, 0) => ((cycles / 2) as u32).wrapping_add(self.count_offset),  //Count runs at half the pipeline clock
+            (11, 0) => self.Compare,
+            (12, 0) => self.Status,
+            (12, 1) => self.IntCtl,
+            (13, 0) => {
+                let cause = self.Cause | self.timer_pending(cycles);
+                match self.controller {
+                    Some(_) => (cause & !CAUSE_RIPL_MASK) | (self.RIPL << CAUSE_RIPL_SHIFT),
+                    None => cause | self.interrupts(cause),
+                }
+            }
+            (14, 0) => self.EPC,
+            (15, 0) => PRID,
+            (15, 1) => self.EBase,
+            (16, 3) if self.controller.is_some() => self.Config[3] | CONFIG3_VEIC,
+            (16, select @ 0..=3) => self.Config[select as usize],
+            (25, 0) => self.PerfCtl[0] | PERFCTL_M,
+            (25, 2) => self.PerfCtl[1],
+            (25, 1) => self.PerfCnt[0],
+            (25, 3) => self.PerfCnt[1],
+            (30, 0) => self.ErrorEPC,
+            (31, 0) => self.DESAVE,
+            _ => 0,     //also SRSCtl and SRSMap (no shadow register sets) and LLAddr
+        }
+    }
+
+    //write a register for MTC0 at a given CPU cycle, read only and unknown registers ignore writes
+    pub fn write(&mut self, register: u8, select: u8, value: u32, cycles: u64) {
+        //only change the writable bits of a register
+        let merge = |old: u32, writable: u32| (old & !writable) | (value & writable);
+
+        match (register, select) {
+            (0..=6 | 10, 0) => if let Some(tlb) = &mut self.tlb { tlb.write(register, value, cycles) },
+            (4, 2) => self.UserLocal = value,
+            (7, 0) => self.HWREna = merge(self.HWREna, HWRENA_WRITABLE),
+            (9, 0) => {
+                self.count_offset = value.wrapping_sub((cycles / 2) as u32);
+                if self.timer_due != u64::MAX {
+                    self.timer_due = timer_due(cycles, self.count_offset, self.Compare);
+                }
+            }
+            (11, 0) => {
+                //writing Compare acknowledges the timer interrupt
+                self.Compare = value;
+                self.Cause &= !CAUSE_TI;
+                self.timer_due = timer_due(cycles, self.count_offset, self.Compare);
+            }
+            (12, 0) => self.Status = merge(self.Status, STATUS_WRITABLE),
+            (12, 1) => self.IntCtl = merge(self.IntCtl, INTCTL_WRITABLE),
+            (13, 0) => self.Cause = merge(self.Cause, CAUSE_WRITABLE),
+            (14, 0) => self.EPC = value,
+            (15, 1) => self.EBase = merge(self.EBase, EBASE_WRITABLE),
+            (16, 0) => self.Config[0] = merge(self.Config[0], CONFIG0_WRITABLE),
+            (25, 0) => self.PerfCtl[0] = value & PERFCTL_WRITABLE,
+            (25, 2) => self.PerfCtl[1] = value & PERFCTL_WRITABLE,
+            (25, 1) => self.PerfCnt[0] = value,
+            (25, 3) => self.PerfCnt[1] = value,
+            (30, 0) => self.ErrorEPC = value,
+            (31, 0) => self.DESAVE = value,
+            _ => (),
+        }
+
+        self.counting = self.PerfCtl.iter().any(|control| control & PERFCTL_ENABLES != 0);
+        self.update_perf_interrupt();
+    }
+
+    //print every implemented register
+    pub fn print(&self, cycles: u64) {
+        println!("\t----- CP0 REGISTERS -----\t");
+        for &(register, select, name) in REGISTERS {
+            if matches!((register, select), (0..=6 | 10, 0)) && self.tlb.is_none() {
+                continue;
+            }
+            println!("{:<10}({:>2},{}): 0x{:0>8X}", name, register, select, self.read(register, select, cycles));
+        }
+    }
+
+    //let time pass up to a CPU cycle, raising the timer interrupt once Count reaches Compare
+    pub fn tick(&mut self, cycles: u64) {
+        if cycles >= self.timer_due {
+            self.Cause |= CAUSE_TI;
+            self.timer_due = u64::MAX;
+        }
+    }
+
+    //is there an interrupt that is unmasked and enabled? In EIC mode the requested level has to
+    //be above the one being handled instead
+    pub fn interrupt_pending(&self) -> bool {
+        if self.Status & (STATUS_IE | STATUS_EXL | STATUS_ERL) != STATUS_IE {
+            return false;
+        }
+        match &self.controller {
+            Some(controller) => {
+                let level = (self.Status >> STATUS_IPL_SHIFT) & 0x3F;
+                controller.request(self.core_requests()).is_some_and(|(requested, _)| requested as u32 > level)
+            }
+            None => self.interrupts(self.Cause) & self.Status & INTERRUPTS != 0,
+        }
+    }
+
+    //cycles until the timer interrupt would be taken, u64::MAX if it is masked or disabled.
+    //In EIC mode the controller decides, so the due cycle is only an estimate that may be early
+    pub fn cycles_to_interrupt(&self, cycles: u64) -> u64 {
+        if self.Status & (STATUS_IE | STATUS_EXL | STATUS_ERL) != STATUS_IE
+            || (self.controller.is_none() && self.Status & self.line(INTCTL_IPTI_SHIFT) == 0) {
+            return u64::MAX;
+        }
+        self.timer_due.saturating_sub(cycles)
+    }
+
+    //the number of interrupt lines devices can use: IP2-IP7, or the controller's sources in EIC mode
+    pub fn line_count(&self) -> u8 {
+        if self.controller.is_some() { 64 } else { 6 }
+    }
+
+    //assert or deassert hardware interrupt line 0-5 (IP2-IP7), or a controller source in EIC mode
+    pub fn set_line(&mut self, line: u8, asserted: bool) {
+        if let Some(controller) = &mut self.controller {
+            return controller.set_source(line, asserted);
+        }
+        let bit = 1 << (CAUSE_IP_SHIFT + 2 + line as u32);
+        self.lines = if asserted { self.lines | bit } else { self.lines & !bit };
+    }
+
+    //attach an external interrupt controller, switching to EIC mode, or detach it with None
+    pub fn set_controller(&mut self, controller: Option<Box<dyn InterruptController>>) {
+        self.controller = controller;
+        self.RIPL = 0;
+    }
+
+    //detach the external interrupt controller and hand it over
+    pub fn take_controller(&mut self) -> Option<Box<dyn InterruptController>> {
+        self.controller.take()
+    }
+
+    //the attached external interrupt controller, if any
+    pub fn controller(&mut self) -> Option<&mut Box<dyn InterruptController>> {
+        self.controller.as_mut()
+    }
+
+    //the interrupts the core raises itself, which go to the controller in EIC mode
+    fn core_requests(&self) -> u8 {
+        let mut core = 0;
+        if self.Cause & CAUSE_TI != 0 { core |= eic::CORE_TIMER; }
+        if self.Cause & (1 << CAUSE_IP_SHIFT) != 0 { core |= eic::CORE_SOFTWARE0; }
+        if self.Cause & (2 << CAUSE_IP_SHIFT) != 0 { core |= eic::CORE_SOFTWARE1; }
+        if self.Cause & CAUSE_PCI != 0 { core |= eic::CORE_PERFORMANCE; }
+        core
+    }
+
+    //TI if the timer went off but tick hasn't been told yet
+    fn timer_pending(&self, cycles: u64) -> u32 {
+        if cycles >= self.timer_due { CAUSE_TI } else { 0 }
+    }
+
+    //the IP bits of Cause: software interrupts, hardware lines and the timer and performance
+    //counter interrupts on the lines IntCtl routes them to
+    fn interrupts(&self, cause: u32) -> u32 {
+        let mut pending = (cause & CAUSE_SOFTWARE) | self.lines;
+        if cause & CAUSE_TI != 0 {
+            pending |= self.line(INTCTL_IPTI_SHIFT);
+        }
+        if cause & CAUSE_PCI != 0 {
+            pending |= self.line(INTCTL_IPPCI_SHIFT);
+        }
+        pending
+    }
+
+    //the Cause bit of the interrupt line an IntCtl field points to
+    fn line(&self, shift: u32) -> u32 {
+        1 << (CAUSE_IP_SHIFT + ((self.IntCtl >> shift) & 7))
+    }
+
+    //is any performance counter enabled?
+    pub fn counting(&self) -> bool {
+        self.counting
+    }
+
+    //let the performance counters know that something happened n times
+    pub fn event(&mut self, event: Event, n: u64) {
+        if !self.counting {
+            return;
+        }
+
+        let mode = self.perf_mode();
+        for counter in 0..2 {
+            let control = self.PerfCtl[counter];
+            if control & mode != 0 && (control >> PERFCTL_EVENT_SHIFT) & PERFCTL_EVENT_MASK == event as u32 {
+                self.PerfCnt[counter] = self.PerfCnt[counter].wrapping_add(n as u32);
+            }
+        }
+
+        self.update_perf_interrupt();
+    }
+
+    //the PerfCtl enable bit of the mode the CPU is in, there is no supervisor mode on the M4K
+    //and the error level right after reset counts as exception level
+    fn perf_mode(&self) -> u32 {
+        if self.Status & (STATUS_EXL | STATUS_ERL) != 0 {
+            PERFCTL_EXL
+        } else if self.Status & STATUS_UM != 0 {
+            PERFCTL_U
+        } else {
+            PERFCTL_K
+        }
+    }
+
+    //the interrupt is pending as long as an enabled counter has its top bit set
+    fn update_perf_interrupt(&mut self) {
+        let overflowed = (0..2).any(|counter| self.PerfCtl[counter] & PERFCTL_IE != 0 && self.PerfCnt[counter] & (1 << 31) != 0);
+        self.Cause = if overflowed { self.Cause | CAUSE_PCI } else { self.Cause & !CAUSE_PCI };
+    }
+}
+
+// the first cycle after now at which Count (half the cycles plus offset) equals compare
+fn timer_due(cycles: u64, offset: u32, compare: u32) -> u64 {
+    let now = cycles / 2;
+    let count = (now as u32).wrapping_add(offset);
+    let ahead = match compare.wrapping_sub(count) {
+        0 => 1 << 32,   //matching right now doesn't count, Count has to get there
+        ahead => ahead as u64,
+    };
+    (now + ahead) * 2
+}
@@ -0,0 +1,346 @@
+// Instruction table shared by the disassembler, the assembler and the CPU.
+//
+// Every instruction is described by a fixed bit pattern, a mask selecting the bits of that
+// pattern which have to match and the operand syntax it uses in assembly. Decoding walks the
+// table and takes the first entry matching the word, so entries with tighter masks
+// (e.g. ROTR, which is SRL with rs = 1) have to come before their looser relatives.
+
+// HI and LO take part in data dependencies like any other register, numbered like in CPU::read_reg
+pub const HI: u8 = 32;
+pub const LO: u8 = 33;
+
+// all operations we know about, named after their mnemonic
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Op {
+    //SPECIAL
+    SLL, SRL, ROTR, SRA, SLLV, SRLV, ROTRV, SRAV,
+    JR, JALR, MOVZ, MOVN, SYSCALL, BREAK, SYNC,
+    MFHI, MTHI, MFLO, MTLO, MULT, MULTU, DIV, DIVU,
+    ADD, ADDU, SUB, SUBU, AND, OR, XOR, NOR, SLT, SLTU,
+    TGE, TGEU, TLT, TLTU, TEQ, TNE,
+    //REGIMM
+    BLTZ, BGEZ, BLTZAL, BGEZAL, SYNCI,
+    //normal opcodes
+    J, JAL, BEQ, BNE, BLEZ, BGTZ,
+    ADDI, ADDIU, SLTI, SLTIU, ANDI, ORI, XORI, LUI,
+    //COP0
+    MFC0, MTC0, DI, EI, ERET, TLBR, TLBWI, TLBWR, TLBP, WAIT,
+    //SPECIAL2
+    MADD, MADDU, MUL, MSUB, MSUBU, CLZ, CLO,
+    //SPECIAL3
+    EXT, INS, WSBH, SEB, SEH,
+    //loads and stores
+    LB, LH, LWL, LW, LBU, LHU, LWR, SB, SH, SWL, SW, SWR, CACHE, LL, PREF, SC,
+}
+
+// the operand layout of an instruction in assembly
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Syntax {
+    None,       //eret
+    Code,       //syscall [code]
+    RdRsRt,     //add rd, rs, rt
+    RdRtRs,     //sllv rd, rt, rs
+    RdRtSa,     //sll rd, rt, sa
+    RdRs,       //clz rd, rs (rt is a copy of rd)
+    RdRt,       //seb rd, rt
+    RsRt,       //mult rs, rt
+    Rd,         //mfhi rd
+    Rs,         //jr rs
+    RdRsJalr,   //jalr [rd,] rs
+    RtRsImm,    //addiu rt, rs, signed imm
+    RtRsUimm,   //ori rt, rs, unsigned imm
+    RtUimm,     //lui rt, unsigned imm
+    RsRtBranch, //beq rs, rt, label
+    RsBranch,   //bgtz rs, label
+    Jump,       //j target
+    RtMem,      //lw rt, offset(base)
+    HintMem,    //cache op, offset(base)
+    Mem,        //synci offset(base)
+    Cop0,       //mfc0 rt, rd[, sel]
+    RtOpt,      //di [rt]
+    ExtIns,     //ext rt, rs, pos, size
+}
+
+impl Syntax {
+    //the operands as they are written in assembly, for error messages
+    pub fn usage(self) -> &'static str {
+        match self {
+            Syntax::None => "no operands",
+            Syntax::Code => "[code]",
+            Syntax::RdRsRt => "rd, rs, rt",
+            Syntax::RdRtRs => "rd, rt, rs",
+            Syntax::RdRtSa => "rd, rt, sa",
+            Syntax::RdRs => "rd, rs",
+            Syntax::RdRt => "rd, rt",
+            Syntax::RsRt => "rs, rt",
+            Syntax::Rd => "rd",
+            Syntax::Rs => "rs",
+            Syntax::RdRsJalr => "[rd,] rs",
+            Syntax::RtRsImm => "rt, rs, signed immediate",
+            Syntax::RtRsUimm => "rt, rs, unsigned immediate",
+            Syntax::RtUimm => "rt, unsigned immediate",
+            Syntax::RsRtBranch => "rs, rt, target",
+            Syntax::RsBranch => "rs, target",
+            Syntax::Jump => "target",
+            Syntax::RtMem => "rt, offset(base)",
+            Syntax::HintMem => "op, offset(base)",
+            Syntax::Mem => "offset(base)",
+            Syntax::Cop0 => "rt, rd[, sel]",
+            Syntax::RtOpt => "[rt]",
+            Syntax::ExtIns => "rt, rs, pos, size",
+        }
+    }
+}
+
+pub struct OpInfo {
+    pub op: Op,
+    pub name: &'static str,
+    pub bits: u32,
+    pub mask: u32,
+    pub syntax: Syntax,
+}
+
+// shorthands for building up the table below
+const fn special(funct: u32) -> u32 { funct }
+const fn regimm(rt: u32) -> u32 { (0x01 << 26) | (rt << 16) }
+const fn opcode(op: u32) -> u32 { op << 26 }
+const fn special2(funct: u32) -> u32 { (0x1C << 26) | funct }
+const fn special3(funct: u32) -> u32 { (0x1F << 26) | funct }
+
+const M_SPECIAL: u32 = 0xFC00_003F;
+const M_REGIMM: u32 = 0xFC1F_0000;
+const M_OPCODE: u32 = 0xFC00_0000;
+
+macro_rules! op {
+    ($op:ident, $name:expr, $bits:expr, $mask:expr, $syntax:ident) => {
+        OpInfo { op: Op::$op, name: $name, bits: $bits, mask: $mask, syntax: Syntax::$syntax }
+    };
+}
+
+pub static OPS: &[OpInfo] = &[
+    //SPECIAL, rotates first as they are shifts with an extra bit set
+    op!(ROTR,    "rotr",    special(0x02) | (1 << 21), M_SPECIAL | 0x03E0_0000, RdRtSa),
+    op!(ROTRV,   "rotrv",   special(0x06) | (1 << 6),  M_SPECIAL | 0x0000_07C0, RdRtRs),
+    op!(SLL,     "sll",     special(0x00), M_SPECIAL, RdRtSa),
+    op!(SRL,     "srl",     special(0x02), M_SPECIAL, RdRtSa),
+    op!(SRA,     "sra",     special(0x03), M_SPECIAL, RdRtSa),
+    op!(SLLV,    "sllv",    special(0x04), M_SPECIAL, RdRtRs),
+    op!(SRLV,    "srlv",    special(0x06), M_SPECIAL, RdRtRs),
+    op!(SRAV,    "srav",    special(0x07), M_SPECIAL, RdRtRs),
+    op!(JR,      "jr",      special(0x08), M_SPECIAL, Rs),
+    op!(JALR,    "jalr",    special(0x09), M_SPECIAL, RdRsJalr),
+    op!(MOVZ,    "movz",    special(0x0A), M_SPECIAL, RdRsRt),
+    op!(MOVN,    "movn",    special(0x0B), M_SPECIAL, RdRsRt),
+    op!(SYSCALL, "syscall", special(0x0C), M_SPECIAL, Code),
+    op!(BREAK,   "break",   special(0x0D), M_SPECIAL, Code),
+    op!(SYNC,    "sync",    special(0x0F), M_SPECIAL, None),
+    op!(MFHI,    "mfhi",    special(0x10), M_SPECIAL, Rd),
+    op!(MTHI,    "mthi",    special(0x11), M_SPECIAL, Rs),
+    op!(MFLO,    "mflo",    special(0x12), M_SPECIAL, Rd),
+    op!(MTLO,    "mtlo",    special(0x13), M_SPECIAL, Rs),
+    op!(MULT,    "mult",    special(0x18), M_SPECIAL, RsRt),
+    op!(MULTU,   "multu",   special(0x19), M_SPECIAL, RsRt),
+    op!(DIV,     "div",     special(0x1A), M_SPECIAL, RsRt),
+    op!(DIVU,    "divu",    special(0x1B), M_SPECIAL, RsRt),
+    op!(ADD,     "add",     special(0x20), M_SPECIAL, RdRsRt),
+    op!(ADDU,    "addu",    special(0x21), M_SPECIAL, RdRsRt),
+    op!(SUB,     "sub",     special(0x22), M_SPECIAL, RdRsRt),
+    op!(SUBU,    "subu",    special(0x23), M_SPECIAL, RdRsRt),
+    op!(AND,     "and",     special(0x24), M_SPECIAL, RdRsRt),
+    op!(OR,      "or",      special(0x25), M_SPECIAL, RdRsRt),
+    op!(XOR,     "xor",     special(0x26), M_SPECIAL, RdRsRt),
+    op!(NOR,     "nor",     special(0x27), M_SPECIAL, RdRsRt),
+    op!(SLT,     "slt",     special(0x2A), M_SPECIAL, RdRsRt),
+    op!(SLTU,    "sltu",    special(0x2B), M_SPECIAL, RdRsRt),
+    op!(TGE,     "tge",     special(0x30), M_SPECIAL, RsRt),
+    op!(TGEU,    "tgeu",    special(0x31), M_SPECIAL, RsRt),
+    op!(TLT,     "tlt",     special(0x32), M_SPECIAL, RsRt),
+    op!(TLTU,    "tltu",    special(0x33), M_SPECIAL, RsRt),
+    op!(TEQ,     "teq",     special(0x34), M_SPECIAL, RsRt),
+    op!(TNE,     "tne",     special(0x36), M_SPECIAL, RsRt),
+
+    //REGIMM
+    op!(BLTZ,    "bltz",    regimm(0x00), M_REGIMM, RsBranch),
+    op!(BGEZ,    "bgez",    regimm(0x01), M_REGIMM, RsBranch),
+    op!(BLTZAL,  "bltzal",  regimm(0x10), M_REGIMM, RsBranch),
+    op!(BGEZAL,  "bgezal",  regimm(0x11), M_REGIMM, RsBranch),
+    op!(SYNCI,   "synci",   regimm(0x1F), M_REGIMM, Mem),
+
+    //normal opcodes
+    op!(J,       "j",       opcode(0x02), M_OPCODE, Jump),
+    op!(JAL,     "jal",     opcode(0x03), M_OPCODE, Jump),
+    op!(BEQ,     "beq",     opcode(0x04), M_OPCODE, RsRtBranch),
+    op!(BNE,     "bne",     opcode(0x05), M_OPCODE, RsRtBranch),
+    op!(BLEZ,    "blez",    opcode(0x06), M_OPCODE, RsBranch),
+    op!(BGTZ,    "bgtz",    opcode(0x07), M_OPCODE, RsBranch),
+    op!(ADDI,    "addi",    opcode(0x08), M_OPCODE, RtRsImm),
+    op!(ADDIU,   "addiu",   opcode(0x09), M_OPCODE, RtRsImm),
+    op!(SLTI,    "slti",    opcode(0x0A), M_OPCODE, RtRsImm),
+    op!(SLTIU,   "sltiu",   opcode(0x0B), M_OPCODE, RtRsImm),
+    op!(ANDI,    "andi",    opcode(0x0C), M_OPCODE, RtRsUimm),
+    op!(ORI,     "ori",     opcode(0x0D), M_OPCODE, RtRsUimm),
+    op!(XORI,    "xori",    opcode(0x0E), M_OPCODE, RtRsUimm),
+    op!(LUI,     "lui",     opcode(0x0F), M_OPCODE, RtUimm),
+
+    //COP0
+    op!(MFC0,    "mfc0",    opcode(0x10), 0xFFE0_07F8, Cop0),
+    op!(MTC0,    "mtc0",    opcode(0x10) | (0x04 << 21), 0xFFE0_07F8, Cop0),
+    op!(DI,      "di",      0x4160_6000, 0xFFE0_FFFF, RtOpt),
+    op!(EI,      "ei",      0x4160_6020, 0xFFE0_FFFF, RtOpt),
+    op!(TLBR,    "tlbr",    0x4200_0001, 0xFFFF_FFFF, None),
+    op!(TLBWI,   "tlbwi",   0x4200_0002, 0xFFFF_FFFF, None),
+    op!(TLBWR,   "tlbwr",   0x4200_0006, 0xFFFF_FFFF, None),
+    op!(TLBP,    "tlbp",    0x4200_0008, 0xFFFF_FFFF, None),
+    op!(ERET,    "eret",    0x4200_0018, 0xFFFF_FFFF, None),
+    op!(WAIT,    "wait",    0x4200_0020, 0xFE00_003F, None),
+
+    //SPECIAL2
+    op!(MADD,    "madd",    special2(0x00), M_SPECIAL, RsRt),
+    op!(MADDU,   "maddu",   special2(0x01), M_SPECIAL, RsRt),
+    op!(MUL,     "mul",     special2(0x02), M_SPECIAL, RdRsRt),
+    op!(MSUB,    "msub",    special2(0x04), M_SPECIAL, RsRt),
+    op!(MSUBU,   "msubu",   special2(0x05), M_SPECIAL, RsRt),
+    op!(CLZ,     "clz",     special2(0x20), M_SPECIAL, RdRs),
+    op!(CLO,     "clo",     special2(0x21), M_SPECIAL, RdRs),
+
+    //SPECIAL3
+    op!(EXT,     "ext",     special3(0x00), M_SPECIAL, ExtIns),
+    op!(INS,     "ins",     special3(0x04), M_SPECIAL, ExtIns),
+    op!(WSBH,    "wsbh",    special3(0x20) | (0x02 << 6), M_SPECIAL | 0x0000_07C0, RdRt),
+    op!(SEB,     "seb",     special3(0x20) | (0x10 << 6), M_SPECIAL | 0x0000_07C0, RdRt),
+    op!(SEH,     "seh",     special3(0x20) | (0x18 << 6), M_SPECIAL | 0x0000_07C0, RdRt),
+
+    //loads and stores
+    op!(LB,      "lb",      opcode(0x20), M_OPCODE, RtMem),
+    op!(LH,      "lh",      opcode(0x21), M_OPCODE, RtMem),
+    op!(LWL,     "lwl",     opcode(0x22), M_OPCODE, RtMem),
+    op!(LW,      "lw",      opcode(0x23), M_OPCODE, RtMem),
+    op!(LBU,     "lbu",     opcode(0x24), M_OPCODE, RtMem),
+    op!(LHU,     "lhu",     opcode(0x25), M_OPCODE, RtMem),
+    op!(LWR,     "lwr",     opcode(0x26), M_OPCODE, RtMem),
+    op!(SB,      "sb",      opcode(0x28), M_OPCODE, RtMem),
+    op!(SH,      "sh",      opcode(0x29), M_OPCODE, RtMem),
+    op!(SWL,     "swl",     opcode(0x2A), M_OPCODE, RtMem),
+    op!(SW,      "sw",      opcode(0x2B), M_OPCODE, RtMem),
+    op!(SWR,     "swr",     opcode(0x2E), M_OPCODE, RtMem),
+    op!(CACHE,   "cache",   opcode(0x2F), M_OPCODE, HintMem),
+    op!(LL,      "ll",      opcode(0x30), M_OPCODE, RtMem),
+    op!(PREF,    "pref",    opcode(0x33), M_OPCODE, HintMem),
+    op!(SC,      "sc",      opcode(0x38), M_OPCODE, RtMem),
+];
+
+// conventional register names, indexed by register number
+pub static REGISTER_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+// a single instruction word split up into all of its possible fields
+#[derive(Clone, Copy, Debug)]
+pub struct Instruction {
+    pub word: u32,
+    pub op: Option<Op>,     //None if the word doesn't decode to anything we know
+    pub rs: u8,
+    pub rt: u8,
+    pub rd: u8,
+    pub sa: u8,
+    pub imm: u16,
+    pub target: u32,        //26 bit jump target index
+}
+
+impl Instruction {
+    //split a word into its fields and look up the operation
+    pub fn decode(word: u32) -> Instruction {
+        Instruction {
+            word,
+            op: lookup(word).map(|info| info.op),
+            rs: ((word >> 21) & 0x1F) as u8,
+            rt: ((word >> 16) & 0x1F) as u8,
+            rd: ((word >> 11) & 0x1F) as u8,
+            sa: ((word >> 6) & 0x1F) as u8,
+            imm: (word & 0xFFFF) as u16,
+            target: word & 0x03FF_FFFF,
+        }
+    }
+
+    //the immediate sign extended to 32 bit
+    pub fn simm(&self) -> i32 {
+        self.imm as i16 as i32
+    }
+
+    //the address a branch at `address` jumps to when taken
+    pub fn branch_target(&self, address: u32) -> u32 {
+        address.wrapping_add(4).wrapping_add((self.simm() << 2) as u32)
+    }
+
+    //the address a J/JAL at `address` jumps to, taken from the region of its delay slot
+    pub fn jump_target(&self, address: u32) -> u32 {
+        (address.wrapping_add(4) & 0xF000_0000) | (self.target << 2)
+    }
+
+    //mtc0 and ei can unmask an interrupt that is pending already, it has to be taken right after them
+    pub fn enables_interrupts(&self) -> bool {
+        matches!(self.op, Some(Op::MTC0) | Some(Op::EI))
+    }
+
+    //branches and jumps, everything that is followed by a delay slot
+    pub fn has_delay_slot(&self) -> bool {
+        matches!(self.op, Some(Op::J) | Some(Op::JAL) | Some(Op::JR) | Some(Op::JALR)
+            | Some(Op::BEQ) | Some(Op::BNE) | Some(Op::BLEZ) | Some(Op::BGTZ)
+            | Some(Op::BLTZ) | Some(Op::BGEZ) | Some(Op::BLTZAL) | Some(Op::BGEZAL))
+    }
+
+    //the registers the instruction reads and writes, HI and LO count as registers 32 and 33
+    pub fn dataflow(&self) -> (Vec<u8>, Vec<u8>) {
+        let (rs, rt, rd) = (self.rs, self.rt, self.rd);
+
+        let (reads, writes) = match self.op {
+            None => (vec![], vec![]),
+
+            //the odd ones out of their syntax groups
+            Some(Op::MULT) | Some(Op::MULTU) | Some(Op::DIV) | Some(Op::DIVU) => (vec![rs, rt], vec![HI, LO]),
+            Some(Op::MADD) | Some(Op::MADDU) | Some(Op::MSUB) | Some(Op::MSUBU) => (vec![rs, rt, HI, LO], vec![HI, LO]),
+            Some(Op::MFHI) => (vec![HI], vec![rd]),
+            Some(Op::MFLO) => (vec![LO], vec![rd]),
+            Some(Op::MTHI) => (vec![rs], vec![HI]),
+            Some(Op::MTLO) => (vec![rs], vec![LO]),
+            Some(Op::MOVZ) | Some(Op::MOVN) => (vec![rs, rt, rd], vec![rd]),
+            Some(Op::JAL) => (vec![], vec![31]),
+            Some(Op::BLTZAL) | Some(Op::BGEZAL) => (vec![rs], vec![31]),
+            Some(Op::SB) | Some(Op::SH) | Some(Op::SW) | Some(Op::SWL) | Some(Op::SWR) => (vec![rs, rt], vec![]),
+            Some(Op::LWL) | Some(Op::LWR) | Some(Op::SC) => (vec![rs, rt], vec![rt]),
+            Some(Op::INS) => (vec![rs, rt], vec![rt]),
+            Some(Op::MFC0) => (vec![], vec![rt]),
+            Some(Op::MTC0) => (vec![rt], vec![]),
+            Some(Op::SYSCALL) => (vec![2, 4, 5, 6, 7], vec![2]),
+
+            //everything else follows from the operands it is written with
+            Some(_) => match lookup(self.word).map(|info| info.syntax) {
+                Some(Syntax::RdRsRt) | Some(Syntax::RdRtRs) => (vec![rs, rt], vec![rd]),
+                Some(Syntax::RdRtSa) | Some(Syntax::RdRt) => (vec![rt], vec![rd]),
+                Some(Syntax::RdRs) | Some(Syntax::RdRsJalr) => (vec![rs], vec![rd]),
+                Some(Syntax::RsRt) | Some(Syntax::RsRtBranch) => (vec![rs, rt], vec![]),
+                Some(Syntax::Rs) | Some(Syntax::RsBranch) => (vec![rs], vec![]),
+                Some(Syntax::RtRsImm) | Some(Syntax::RtRsUimm) | Some(Syntax::RtMem) | Some(Syntax::ExtIns) => (vec![rs], vec![rt]),
+                Some(Syntax::RtUimm) => (vec![], vec![rt]),
+                Some(Syntax::Mem) | Some(Syntax::HintMem) => (vec![rs], vec![]),
+                _ => (vec![], vec![]),
+            },
+        };
+
+        //$zero never causes a hazard
+        (reads.into_iter().filter(|&r| r != 0).collect(), writes.into_iter().filter(|&r| r != 0).collect())
+    }
+}
+
+// find the table entry for a word
+pub fn lookup(word: u32) -> Option<&'static OpInfo> {
+    OPS.iter().find(|info| word & info.mask == info.bits)
+}
+
+// find the table entry for a mnemonic
+pub fn lookup_name(name: &str) -> Option<&'static OpInfo> {
+    OPS.iter().find(|info| info.name == name)
+}
@@ -0,0 +1,478 @@
+// Optional JIT backend, built with `cargo build --features jit`.
+//
+// Blocks the block engine keeps coming back to are compiled to host code with Cranelift. Guest
+// state never leaves the CPU struct: every register is loaded from and stored back to it, so
+// compiled code and the interpreter can hand over to each other after any instruction.
+//
+// Only plain ALU operations and branches are translated. Everything else - loads and stores
+// (and with them MMIO), CP0 accesses, syscalls, anything that can raise an exception - is handed
+// to the interpreter through jit_step, and the compiled code leaves the block as soon as the
+// interpreter didn't continue with the next instruction or the block's code was overwritten.
+
+use crate::blockengine::BlockEngine;
+use crate::cpu::CPU;
+use crate::instruction::{Instruction, Op};
+
+use cranelift_codegen::ir::{condcodes::IntCC, types, AbiParam, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module};
+
+use std::mem::offset_of;
+
+// where the guest state lives inside the CPU struct
+const GPR: i32 = offset_of!(CPU, GPR) as i32;
+const HI: i32 = offset_of!(CPU, HI) as i32;
+const LO: i32 = offset_of!(CPU, LO) as i32;
+const PC: i32 = offset_of!(CPU, PC) as i32;
+const NEXT_PC: i32 = offset_of!(CPU, next_PC) as i32;
+const DELAY_SLOT: i32 = offset_of!(CPU, delay_slot) as i32;
+const INSTRUCTIONS: i32 = offset_of!(CPU, instructions) as i32;
+
+// a compiled block, running it always starts at the top of the block
+pub struct NativeBlock {
+    code: extern "C" fn(*mut CPU),
+    _insts: Box<[Instruction]>,     //handed to jit_step by address, so they have to live as long as the code
+}
+
+impl NativeBlock {
+    pub fn run(&self, cpu: &mut CPU) {
+        (self.code)(cpu)
+    }
+}
+
+pub struct Jit {
+    module: JITModule,
+    context: FunctionBuilderContext,
+    step: FuncId,
+    pub compiled: u64,
+}
+
+impl Jit {
+    //set up Cranelift for the host we're running on
+    pub fn new() -> Result<Jit, String> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|e| e.to_string())?;
+        let isa = cranelift_native::builder()?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| e.to_string())?;
+
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        builder.symbol("jit_step", jit_step as *const u8);
+        let mut module = JITModule::new(builder);
+
+        //u32 jit_step(cpu, instruction, physical block start, block version)
+        let pointer = module.target_config().pointer_type();
+        let mut signature = module.make_signature();
+        signature.params.extend([AbiParam::new(pointer), AbiParam::new(pointer), AbiParam::new(types::I32), AbiParam::new(types::I32)]);
+        signature.returns.push(AbiParam::new(types::I32));
+        let step = module.declare_function("jit_step", Linkage::Import, &signature).map_err(|e| e.to_string())?;
+
+        Ok(Jit { module, context: FunctionBuilderContext::new(), step, compiled: 0 })
+    }
+
+    //compile the instructions of a block starting at start, translated from a physical page at version
+    //(code of blocks that get retranslated stays around, the module never frees anything)
+    pub fn compile(&mut self, start: u32, physical: u32, version: u32, insts: &[Instruction]) -> Option<NativeBlock> {
+        let insts: Box<[Instruction]> = insts.into();
+        let pointer = self.module.target_config().pointer_type();
+
+        let mut context = self.module.make_context();
+        context.func.signature.params.push(AbiParam::new(pointer));
+
+        let mut b = FunctionBuilder::new(&mut context.func, &mut self.context);
+        let step = self.module.declare_func_in_func(self.step, b.func);
+
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+        let cpu = b.block_params(entry)[0];
+        let executed = b.ins().load(types::I64, MemFlags::trusted(), cpu, INSTRUCTIONS);
+
+        //target of a branch whose delay slot comes next
+        let mut pending: Option<Value> = None;
+        //branch target if the last instruction so far was a delay slot
+        let mut after_delay_slot: Option<Value> = None;
+        let mut finished = false;
+
+        for (index, inst) in insts.iter().enumerate() {
+            let address = start.wrapping_add(4 * index as u32);
+            let delay_target = pending.take();
+            after_delay_slot = delay_target;
+
+            if alu(&mut b, cpu, inst) {
+                continue;
+            }
+            //a branch in a delay slot is left to the interpreter, which knows what that means
+            if delay_target.is_none() {
+                if let Some(target) = branch(&mut b, cpu, inst, address) {
+                    //an exception in the delay slot has to know it is in one
+                    let delay_slot = constant(&mut b, address.wrapping_add(4));
+                    b.ins().store(MemFlags::trusted(), delay_slot, cpu, DELAY_SLOT);
+                    pending = Some(target);
+                    continue;
+                }
+            }
+
+            //hand the instruction to the interpreter with the state it would see there
+            let next = delay_target.unwrap_or_else(|| constant(&mut b, address.wrapping_add(4)));
+            let pc = constant(&mut b, address);
+            let count = b.ins().iadd_imm(executed, index as i64);
+            b.ins().store(MemFlags::trusted(), pc, cpu, PC);
+            b.ins().store(MemFlags::trusted(), next, cpu, NEXT_PC);
+            b.ins().store(MemFlags::trusted(), count, cpu, INSTRUCTIONS);
+
+            let inst_pointer = b.ins().iconst(pointer, &insts[index] as *const Instruction as i64);
+            let block_start = constant(&mut b, physical);
+            let block_version = constant(&mut b, version);
+            let call = b.ins().call(step, &[cpu, inst_pointer, block_start, block_version]);
+            let left = b.inst_results(call)[0];
+
+            //after a delay slot the interpreter already went wherever the branch pointed to
+            if delay_target.is_some() {
+                b.ins().return_(&[]);
+                finished = true;
+                break;
+            }
+
+            let exit = b.create_block();
+            let stay = b.create_block();
+            b.ins().brif(left, exit, &[], stay, &[]);
+            b.switch_to_block(exit);
+            b.seal_block(exit);
+            b.ins().return_(&[]);
+            b.switch_to_block(stay);
+            b.seal_block(stay);
+        }
+
+        if !finished {
+            let last = start.wrapping_add(4 * (insts.len() as u32 - 1));
+            let (pc, next) = match (pending, after_delay_slot) {
+                //the block ends on a branch, its delay slot is up next
+                (Some(target), _) => (constant(&mut b, last.wrapping_add(4)), target),
+                //the block ends on a delay slot, continue at the branch target
+                (None, Some(target)) => (target, b.ins().iadd_imm(target, 4)),
+                (None, None) => (constant(&mut b, last.wrapping_add(4)), constant(&mut b, last.wrapping_add(8))),
+            };
+            let count = b.ins().iadd_imm(executed, insts.len() as i64);
+            b.ins().store(MemFlags::trusted(), pc, cpu, PC);
+            b.ins().store(MemFlags::trusted(), next, cpu, NEXT_PC);
+            b.ins().store(MemFlags::trusted(), count, cpu, INSTRUCTIONS);
+            b.ins().return_(&[]);
+        }
+
+        b.finalize();
+
+        let name = format!("block_{:08x}_{}", start, self.compiled);
+        let id = self.module.declare_function(&name, Linkage::Local, &context.func.signature).ok()?;
+        self.module.define_function(id, &mut context).ok()?;
+        self.module.clear_context(&mut context);
+        self.module.finalize_definitions().ok()?;
+        self.compiled += 1;
+
+        //SAFETY: the function was just compiled with exactly this signature
+        let code = unsafe { std::mem::transmute::<*const u8, extern "C" fn(*mut CPU)>(self.module.get_finalized_function(id)) };
+        Some(NativeBlock { code, _insts: insts })
+    }
+}
+
+// run one instruction in the interpreter on behalf of compiled code,
+// returns 1 if the compiled code has to leave the block afterwards
+extern "C" fn jit_step(cpu: *mut CPU, inst: *const Instruction, physical: u32, version: u32) -> u32 {
+    //SAFETY: only ever called from a running NativeBlock, with the cpu it runs on and an instruction it owns
+    let (cpu, inst) = unsafe { (&mut *cpu, *inst) };
+    let expected = cpu.next_PC;
+
+    cpu.step(CPU::handler(inst.op), inst);
+
+    (cpu.PC != expected || cpu.next_PC != expected.wrapping_add(4) || cpu.MEM.page_version(physical) != version) as u32
+}
+
+fn constant(b: &mut FunctionBuilder, value: u32) -> Value {
+    //Cranelift wants the zero extended bits of 32 bit constants
+    b.ins().iconst(types::I32, value as i64)
+}
+
+fn get(b: &mut FunctionBuilder, cpu: Value, number: u8) -> Value {
+    match number {
+        0 => constant(b, 0),
+        _ => b.ins().load(types::I32, MemFlags::trusted(), cpu, GPR + 4 * number as i32),
+    }
+}
+
+fn set(b: &mut FunctionBuilder, cpu: Value, number: u8, value: Value) {
+    if number != 0 {
+        b.ins().store(MemFlags::trusted(), value, cpu, GPR + 4 * number as i32);
+    }
+}
+
+// translate an instruction that only touches registers, returns false if it isn't one of them
+fn alu(b: &mut FunctionBuilder, cpu: Value, i: &Instruction) -> bool {
+    let op = match i.op {
+        Some(op) => op,
+        None => return false,
+    };
+    let simm = i.simm() as u32;
+    let uimm = i.imm as u32;
+
+    //multiplications into HI/LO write two registers
+    if let Op::MULT | Op::MULTU = op {
+        let (s, t) = (get(b, cpu, i.rs), get(b, cpu, i.rt));
+        let (s, t) = if op == Op::MULT { (b.ins().sextend(types::I64, s), b.ins().sextend(types::I64, t)) }
+                     else { (b.ins().uextend(types::I64, s), b.ins().uextend(types::I64, t)) };
+        let product = b.ins().imul(s, t);
+        let low = b.ins().ireduce(types::I32, product);
+        let high = b.ins().ushr_imm(product, 32);
+        let high = b.ins().ireduce(types::I32, high);
+        b.ins().store(MemFlags::trusted(), low, cpu, LO);
+        b.ins().store(MemFlags::trusted(), high, cpu, HI);
+        return true;
+    }
+    if let Op::MTHI | Op::MTLO = op {
+        let s = get(b, cpu, i.rs);
+        b.ins().store(MemFlags::trusted(), s, cpu, if op == Op::MTHI { HI } else { LO });
+        return true;
+    }
+
+    let (destination, value) = match op {
+        Op::SLL => { let t = get(b, cpu, i.rt); (i.rd, b.ins().ishl_imm(t, i.sa as i64)) }
+        Op::SRL => { let t = get(b, cpu, i.rt); (i.rd, b.ins().ushr_imm(t, i.sa as i64)) }
+        Op::SRA => { let t = get(b, cpu, i.rt); (i.rd, b.ins().sshr_imm(t, i.sa as i64)) }
+        Op::ROTR => { let t = get(b, cpu, i.rt); (i.rd, b.ins().rotr_imm(t, i.sa as i64)) }
+
+        //Cranelift takes shift amounts modulo the width, just like MIPS
+        Op::SLLV | Op::SRLV | Op::SRAV | Op::ROTRV => {
+            let (s, t) = (get(b, cpu, i.rs), get(b, cpu, i.rt));
+            let value = match op {
+                Op::SLLV => b.ins().ishl(t, s),
+                Op::SRLV => b.ins().ushr(t, s),
+                Op::SRAV => b.ins().sshr(t, s),
+                _ => b.ins().rotr(t, s),
+            };
+            (i.rd, value)
+        }
+
+        Op::MOVZ | Op::MOVN => {
+            let (s, t, d) = (get(b, cpu, i.rs), get(b, cpu, i.rt), get(b, cpu, i.rd));
+            let condition = if op == Op::MOVZ { IntCC::Equal } else { IntCC::NotEqual };
+            let zero = constant(b, 0);
+            let moved = b.ins().icmp(condition, t, zero);
+            (i.rd, b.ins().select(moved, s, d))
+        }
+
+        Op::MFHI => (i.rd, b.ins().load(types::I32, MemFlags::trusted(), cpu, HI)),
+        Op::MFLO => (i.rd, b.ins().load(types::I32, MemFlags::trusted(), cpu, LO)),
+
+        Op::ADDU | Op::SUBU | Op::AND | Op::OR | Op::XOR | Op::NOR | Op::SLT | Op::SLTU | Op::MUL => {
+            let (s, t) = (get(b, cpu, i.rs), get(b, cpu, i.rt));
+            let value = match op {
+                Op::ADDU => b.ins().iadd(s, t),
+                Op::SUBU => b.ins().isub(s, t),
+                Op::AND => b.ins().band(s, t),
+                Op::OR => b.ins().bor(s, t),
+                Op::XOR => b.ins().bxor(s, t),
+                Op::NOR => { let or = b.ins().bor(s, t); b.ins().bnot(or) }
+                Op::MUL => b.ins().imul(s, t),
+                _ => {
+                    let condition = if op == Op::SLT { IntCC::SignedLessThan } else { IntCC::UnsignedLessThan };
+                    let less = b.ins().icmp(condition, s, t);
+                    b.ins().uextend(types::I32, less)
+                }
+            };
+            (i.rd, value)
+        }
+
+        Op::ADDIU | Op::SLTI | Op::SLTIU | Op::ANDI | Op::ORI | Op::XORI => {
+            let s = get(b, cpu, i.rs);
+            let value = match op {
+                Op::ADDIU => { let imm = constant(b, simm); b.ins().iadd(s, imm) }
+                Op::ANDI => { let imm = constant(b, uimm); b.ins().band(s, imm) }
+                Op::ORI => { let imm = constant(b, uimm); b.ins().bor(s, imm) }
+                Op::XORI => { let imm = constant(b, uimm); b.ins().bxor(s, imm) }
+                _ => {
+                    //both compare against the sign extended immediate
+                    let condition = if op == Op::SLTI { IntCC::SignedLessThan } else { IntCC::UnsignedLessThan };
+                    let imm = constant(b, simm);
+                    let less = b.ins().icmp(condition, s, imm);
+                    b.ins().uextend(types::I32, less)
+                }
+            };
+            (i.rt, value)
+        }
+        Op::LUI => (i.rt, constant(b, uimm << 16)),
+
+        Op::CLZ => { let s = get(b, cpu, i.rs); (i.rd, b.ins().clz(s)) }
+        Op::CLO => { let s = get(b, cpu, i.rs); let inverted = b.ins().bnot(s); (i.rd, b.ins().clz(inverted)) }
+        Op::SEB => { let t = get(b, cpu, i.rt); let byte = b.ins().ireduce(types::I8, t); (i.rd, b.ins().sextend(types::I32, byte)) }
+        Op::SEH => { let t = get(b, cpu, i.rt); let half = b.ins().ireduce(types::I16, t); (i.rd, b.ins().sextend(types::I32, half)) }
+
+        _ => return false,
+    };
+
+    set(b, cpu, destination, value);
+    true
+}
+
+// translate a branch or jump at address, returns where execution continues after the delay slot
+fn branch(b: &mut FunctionBuilder, cpu: Value, i: &Instruction, address: u32) -> Option<Value> {
+    let op = i.op?;
+    let delay_slot = address.wrapping_add(4);
+    let link = |b: &mut FunctionBuilder, number: u8| {
+        let value = constant(b, address.wrapping_add(8));
+        set(b, cpu, number, value);
+    };
+
+    let condition = match op {
+        Op::J | Op::JAL => {
+            if op == Op::JAL {
+                link(b, 31);
+            }
+            return Some(constant(b, i.jump_target(address)));
+        }
+        Op::JR | Op::JALR => {
+            //read the target first in case rs and rd are the same register
+            let target = get(b, cpu, i.rs);
+            if op == Op::JALR {
+                link(b, i.rd);
+            }
+            return Some(target);
+        }
+        Op::BEQ | Op::BNE => {
+            let (s, t) = (get(b, cpu, i.rs), get(b, cpu, i.rt));
+            b.ins().icmp(if op == Op::BEQ { IntCC::Equal } else { IntCC::NotEqual }, s, t)
+        }
+        Op::BLEZ | Op::BGTZ | Op::BLTZ | Op::BGEZ | Op::BLTZAL | Op::BGEZAL => {
+            let s = get(b, cpu, i.rs);
+            let zero = constant(b, 0);
+            let condition = match op {
+                Op::BLEZ => IntCC::SignedLessThanOrEqual,
+                Op::BGTZ => IntCC::SignedGreaterThan,
+                Op::BLTZ | Op::BLTZAL => IntCC::SignedLessThan,
+                _ => IntCC::SignedGreaterThanOrEqual,
+            };
+            let taken = b.ins().icmp(condition, s, zero);
+            //the link happens whether the branch is taken or not
+            if let Op::BLTZAL | Op::BGEZAL = op {
+                link(b, 31);
+            }
+            taken
+        }
+        _ => return None,
+    };
+
+    let target = constant(b, i.branch_target(address));
+    let fall_through = constant(b, delay_slot.wrapping_add(4));
+    Some(b.ins().select(condition, target, fall_through))
+}
+
+// run n instructions on two identical machines, one through the JIT and one single stepping,
+// and compare their registers after every block. Returns false at the first difference.
+pub fn cross_check(jitted: &mut CPU, reference: &mut CPU, n: u64) -> bool {
+    let mut engine = BlockEngine::new();
+    engine.jit_threshold = 1;   //compile everything right away
+    jitted.trace = false;
+    reference.trace = false;
+
+    let mut executed = 0;
+    while executed < n && !jitted.stopped {
+        let block_pc = jitted.PC;
+        let chunk = engine.run(jitted, (n - executed).min(64));
+        //failed fetches don't count as instructions, so clock until as many were executed
+        let last = reference.instructions + chunk;
+        while reference.instructions < last && !reference.stopped {
+            reference.clock();
+        }
+        //the engine takes an interrupt before noticing it ran out of instructions, single stepping
+        //only with the next clock. If the interrupt stopped the program there is no next clock
+        if jitted.stopped && !reference.stopped {
+            reference.poll_interrupts();
+        }
+        executed += chunk;
+
+        let differences = differences(jitted, reference);
+        if !differences.is_empty() {
+            println!("Mismatch after {} instructions, in the code run from {:#010X}:", executed, block_pc);
+            for (name, jit, interpreter) in differences {
+                println!("  {:>5}: JIT {:#010X}  interpreter {:#010X}", name, jit, interpreter);
+            }
+            return false;
+        }
+    }
+
+    println!("JIT and interpreter agree after {} instructions ({} blocks compiled)", executed, engine.compiled());
+    true
+}
+
+fn differences(a: &CPU, b: &CPU) -> Vec<(String, u32, u32)> {
+    let mut differences: Vec<(String, u32, u32)> = (1..32)
+        .filter(|&r| a.GPR[r] != b.GPR[r])
+        .map(|r| (format!("${}", crate::instruction::REGISTER_NAMES[r]), a.GPR[r], b.GPR[r]))
+        .collect();
+
+    for (name, x, y) in [("HI", a.HI, b.HI), ("LO", a.LO, b.LO), ("PC", a.PC, b.PC), ("nPC", a.next_PC, b.next_PC)] {
+        if x != y {
+            differences.push((name.to_string(), x, y));
+        }
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_line;
+    use crate::mmu::Translation;
+    use crate::ram::RAM;
+
+    //sum 1..=100 with loads, stores, multiplies and a delay slot in the loop
+    const PROGRAM: [&str; 14] = [
+        "addiu $t0, $zero, 100",
+        "addiu $t1, $zero, 0",
+        "lui $t3, 0x1001",
+        "addu $t1, $t1, $t0",           //loop
+        "sw $t1, 0($t3)",
+        "lw $t2, 0($t3)",
+        "mult $t2, $t0",
+        "mflo $t4",
+        "sll $t5, $t0, 2",
+        "addiu $t0, $t0, -1",
+        "bne $t0, $zero, 0x0040000c",
+        "xor $t6, $t6, $t5",
+        "j 0x00400030",
+        "nop",
+    ];
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new(RAM::new(), Translation::Identity);
+        for (index, line) in PROGRAM.iter().enumerate() {
+            let address = 0x0040_0000 + 4 * index as u32;
+            cpu.MEM.write_word(address, assemble_line(line, address).unwrap()[0]);
+        }
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        let (mut jitted, mut reference) = (cpu(), cpu());
+        assert!(cross_check(&mut jitted, &mut reference, 2000));
+
+        assert_eq!(jitted.GPR[9], 5050);
+        assert_eq!(jitted.MEM.read_word(0x1001_0000), 5050);
+        assert!(differences(&jitted, &reference).is_empty());
+    }
+
+    #[test]
+    fn keeps_cycles_with_a_timing_profile() {
+        //compiled blocks don't account issue and latency, so they must not run at all
+        let (mut jitted, mut reference) = (cpu(), cpu());
+        jitted.set_timing_profile("m4k");
+        reference.set_timing_profile("m4k");
+        assert!(cross_check(&mut jitted, &mut reference, 2000));
+        assert_eq!(jitted.cycles(), reference.cycles());
+        assert!(jitted.cycles() > 2000);
+    }
+}
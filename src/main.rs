@@ -1,31 +1,66 @@
+//register and instruction names follow the MIPS manuals rather than rust naming
+#![allow(non_snake_case, clippy::upper_case_acronyms)]
+
 pub(crate) mod cpu;
 pub(crate) mod ram;
 pub(crate) mod exceptionprocessor;
+pub(crate) mod mmu;
+pub(crate) mod mpu;
+pub(crate) mod instruction;
+pub(crate) mod disassembler;
+pub(crate) mod assembler;
+pub(crate) mod preprocessor;
+pub(crate) mod decodecache;
+pub(crate) mod blockengine;
+pub(crate) mod pipeline;
+pub(crate) mod tomasulo;
+pub(crate) mod cache;
+pub(crate) mod branchpredictor;
+pub(crate) mod timing;
+pub(crate) mod eic;
+pub(crate) mod defaulthandler;
+#[cfg(feature = "jit")]
+pub(crate) mod jit;
 
 use crate::ram::RAM;
 use crate::cpu::CPU;
+use crate::disassembler::Style;
+use crate::assembler::assemble_line;
+use crate::blockengine::BlockEngine;
+use crate::cache::CacheConfig;
+use crate::eic::PriorityController;
+use crate::mmu::Translation;
+use crate::mpu::Region;
+use crate::defaulthandler::Action;
+use crate::exceptionprocessor::Exception;
 
 use std::io::{self, BufRead, Write};
 
 
 fn main() {
 
-    //initialize the ram
-    let mut ram = RAM::new();
+    let args: Vec<String> = std::env::args().collect();
 
-    //write a nice instruction into it
-    //ram.write_mem(0, 0b001101_00000_00011_0000000011111111);
-
-    //fill the ram with a memory dump from MARS
-    ram.fill_memory("dumps/text_fib.bin".to_string(), "dumps/data_fib.bin".to_string());
-    //TODO: tidy this up
+    //how addresses get translated, optionally given after the program
+    let translation = match translation(&args) {
+        Some(translation) => translation,
+        None => return,
+    };
 
+    //initialize the ram
+    let ram = match load_ram(&args, translation) {
+        Some(ram) => ram,
+        None => return,
+    };
 
     //initialize the cpu
-    let mut cpu = CPU::new(ram);
+    let mut cpu = CPU::new(ram, translation);
 
     cpu.reset();
 
+    //translated code for running without the debug output
+    let mut engine = BlockEngine::new();
+
     let stdin = io::stdin();
 
     //main emulation loop
@@ -37,14 +72,42 @@ fn main() {
         //read one line in one line
         let line = stdin.lock().lines().next().unwrap().unwrap();
         let chunks: Vec<&str> = line.split_whitespace().collect();
+        if chunks.is_empty() {
+            continue;
+        }
 
         //check the command used
         match chunks[0] {   //TODO: clean up the optional argument parsing mess
             "help" => help(), // print help menu
             "clock" => clock(&mut cpu, if chunks.len() == 2 { chunks[1].parse().unwrap() } else { 1 }), //clock the cpu n times
+            "run" => run(&mut cpu, &mut engine, if chunks.len() == 2 { chunks[1].parse().unwrap() } else { 1_000_000 }), //run n instructions as fast as possible
+            "bench" => bench(&args, &cpu, if chunks.len() == 2 { chunks[1].parse().unwrap() } else { 10_000_000 }), //time single stepping against the block engine on fresh machines
             "readregs" => read_regs(&cpu, if chunks.len() == 2 { match chunks[1] { "d" => false, "h" => true, _ => false } } else { true }), // read out all registers in hex or decimal based on the 2nd argument
             "readmem" => read_mem(&cpu, u32::from_str_radix(chunks[1], 16).unwrap(), u32::from_str_radix(chunks[2], 16).unwrap()), //read memory from address to address
             "readinst" => read_inst(&cpu, if chunks.len() == 2 { chunks[1].parse().unwrap() } else { 0 }),
+            "disasm" => disasm(&cpu, if chunks.len() >= 2 { chunks[1].parse().unwrap() } else { 4 }, if chunks.len() == 3 && chunks[2] == "g" { Style::Gnu } else { Style::Mars }),
+            "asm" if chunks.len() >= 3 => asm(&mut cpu, chunks[1], &chunks[2..].join(" ")),
+            "nop" if chunks.len() == 2 => asm(&mut cpu, chunks[1], "nop"),
+            #[cfg(feature = "jit")]
+            "jit" => engine.toggle_jit(), //switch compiling hot blocks to host code on or off
+            #[cfg(feature = "jit")]
+            "jitcheck" => jit_check(&args, if chunks.len() == 2 { chunks[1].parse().unwrap() } else { 1_000_000 }),
+            "pipeline" => cpu.toggle_pipeline(), //switch the five stage pipeline model on or off
+            "ooo" => cpu.toggle_out_of_order(), //switch the out-of-order (Tomasulo) model on or off
+            "cpi" => cpu.print_pipeline_stats(),
+            "cache" => cache(&mut cpu, &chunks[1..]), //turn the caches on/off or configure one of them
+            "cachestats" => cpu.print_cache_stats(),
+            "timing" => timing(&mut cpu, &chunks[1..]), //pick or tweak the instruction timing profile
+            "predict" => cpu.toggle_predictors(), //watch branches with static, bimodal, gshare and BTB + RAS predictors
+            "predictstats" => cpu.print_predictor_stats(if chunks.len() == 2 { chunks[1].parse().unwrap() } else { 10 }),
+            "cp0" => cpu.print_cp0(), //print the coprocessor 0 registers
+            "tlb" => cpu.print_tlb(), //print the valid TLB entries
+            "mpu" => mpu(&mut cpu, &chunks[1..]), //switch the memory protection unit on/off or set up its regions
+            "eic" => eic(&mut cpu, &chunks[1..]), //attach or configure the external interrupt controller
+            "irq" => irq(&mut cpu, &chunks[1..]), //assert or deassert a hardware interrupt line
+            "exceptions" => exceptions(&mut cpu, &chunks[1..]), //print or change what happens to exceptions the program has no handler for
+            "overflow" if chunks.len() == 2 => overflow(&mut cpu, chunks[1]), //shortcut for what happens to overflows without a handler
+            "trace" => cpu.trace = !cpu.trace, //toggle printing every executed instruction
             "reset" => cpu.reset(), //reset the cpu
             "quit" | "q" => break, //quits the program
 
@@ -53,23 +116,232 @@ fn main() {
     }
 }
 
+// the translation mode from the command line, identity if none is given
+fn translation(args: &[String]) -> Option<Translation> {
+    match args.get(2).map(|name| Translation::parse(name)) {
+        None => Some(Translation::Identity),
+        Some(Ok(translation)) => Some(translation),
+        Some(Err(error)) => {
+            println!("{}", error);
+            None
+        }
+    }
+}
+
+// a fresh RAM with the program from the command line in it
+fn load_ram(args: &[String], translation: Translation) -> Option<RAM> {
+    let mut ram = RAM::new();
+
+    //write a nice instruction into it
+    //ram.write_mem(0, 0b001101_00000_00011_0000000011111111);
+
+    //either assemble the source file given on the command line or fill the ram with a memory dump from MARS
+    if args.len() >= 2 {
+        match assembler::assemble_file(&args[1]) {
+            Ok(program) => ram.load_program(&program, translation),
+            Err(error) => {
+                println!("{}", error);
+                return None;
+            }
+        }
+    }
+    else {
+        ram.fill_memory("dumps/text_fib.bin".to_string(), "dumps/data_fib.bin".to_string());
+        //TODO: tidy this up
+    }
+
+    Some(ram)
+}
+
 fn help() {
     println!("Please enter one of the following commands:\n
                 \rhelp\t\t\tPrints this help menu\n
                 \rclock [n]\t\t\tClocks the CPU n-times\n
+                \rrun [n]\t\t\tRuns n instructions (default 1000000) in the block engine, single stepping if trace or a timing model is on\n
+                \rbench [n]\t\t\tTimes n instructions (default 10000000) of the program from the start, single stepped and in the block engine\n
+                \rtrace\t\t\tToggles printing every executed instruction\n
                 \rreadregs [d/H]\t\t\tPrints out all the CPU's registers in [d]ecimal or [h]ex\n
                 \rreadmem A B\t\t\tPrints out memory contents from 0xA to 0xB\n
                 \rreadinst [o]\t\t\tPrints an instruction in binary at offset o (default 0)\n
-                \rreset\t\t\tResets the CPU\n
+                \rdisasm [n] [g]\t\t\tDisassembles n instructions before and after the PC (default 4, at most 1024) in MARS or [g]NU syntax\n
+                \rasm A instruction\t\t\tAssembles an instruction (pseudo instructions too) and writes it to hex address A\n
+                \rnop A\t\t\tOverwrites the instruction at hex address A with a nop\n
+                \rpipeline\t\t\tToggles the five stage pipeline model, clock then advances one cycle\n
+                \rooo\t\t\tToggles the out-of-order (Tomasulo) model, clock then advances one cycle and trace prints its tables\n
+                \rcpi\t\t\tPrints cycles, CPI and stalls of the pipeline or out-of-order model\n
+                \rcache on/off\t\t\tToggles the I- and D-cache model (16KB, 4 way, 16 byte lines, LRU, write back)\n
+                \rcache i/d S W L [lru/fifo/random] [wb/wt] [P]\t\t\tConfigures a cache: S bytes, W ways, L byte lines, P cycle miss penalty\n
+                \rcachestats\t\t\tPrints hit/miss statistics and the cycle count of the caches\n
+                \rpredict\t\t\tToggles the branch predictors (static not-taken, bimodal, gshare, BTB + RAS)\n
+                \rpredictstats [n]\t\t\tPrints predictor accuracy overall and for the n most executed branches (default 10)\n
+                \rtiming [profile]\t\t\tPrints or selects the timing profile (ideal, m4k, m4k-small, microaptiv) and the cycle count\n
+                \rtiming C I L\t\t\tSets issue I and latency L cycles of instruction class C (alu, load, store, branch, mul, madd, div, cop0)\n
+                \rcp0\t\t\tPrints the coprocessor 0 registers\n
+                \rtlb\t\t\tPrints the valid TLB entries (run 'rem program.asm tlb' to get a TLB, or 'rem program.asm fmt' for fixed mapping from 0xBFC00000)\n
+                \rirq L on/off\t\t\tAsserts or deasserts hardware interrupt line L (0-5, IP2-IP7), or controller source L (3-63) in EIC mode\n
+                \reic on/off\t\t\tAttaches or detaches an external interrupt controller (timer is source 0, software interrupts 1 and 2)\n
+                \reic S L\t\t\tSets the priority level L (1-63) of controller source S\n
+                \rmpu [on/off]\t\t\tPrints the MPU regions or switches the memory protection unit on or off\n
+                \rmpu N base size K U\t\t\tSets MPU region N (0-15) to hex base and size with kernel and user permissions K and U (like rwx, r-x or -)\n
+                \rmpu N off\t\t\tRemoves MPU region N\n
+                \rexceptions\t\t\tPrints what the built-in handler does about each exception when nothing is at the vector (its first 8 words are zero)\n
+                \rexceptions C skip/halt\t\t\tMakes the built-in handler skip the instruction or stop the program on exception code C\n
+                \roverflow stop/trap\t\t\tSame as 'exceptions 12 halt' (the default) or 'exceptions 12 skip' for overflows without a handler\n");
+    #[cfg(feature = "jit")]
+    println!("\rjit\t\t\tToggles compiling hot blocks to host code in run\n
+                \rjitcheck [n]\t\t\tRuns n instructions (default 1000000) of the program from the start through the JIT and single stepping and compares them\n");
+    println!("\rreset\t\t\tResets the CPU\n
                 \rquit / q\t\t\tQuits the program");
 }
 
 fn clock(cpu: &mut CPU, n: u16) {
+    if stopped(cpu) {
+        return;
+    }
     for _ in 0..n {
         cpu.clock();
     }
 }
 
+fn run(cpu: &mut CPU, engine: &mut BlockEngine, n: u64) {
+    if stopped(cpu) {
+        return;
+    }
+    //the block engine doesn't print anything or model timing, so fall back to single stepping
+    if cpu.trace || cpu.timed() {
+        for _ in 0..n {
+            cpu.clock();
+        }
+    }
+    else {
+        engine.run(cpu, n);
+    }
+}
+
+// a stopped program doesn't go on, tell the user instead of doing nothing
+fn stopped(cpu: &CPU) -> bool {
+    if cpu.stopped {
+        println!("The program has stopped, reset to run it again");
+    }
+    cpu.stopped
+}
+
+// two machines with the program from the command line loaded and reset, for comparing ways to run it
+fn fresh_pair(args: &[String]) -> Option<(CPU, CPU)> {
+    let translation = translation(args)?;
+    let (mut a, mut b) = match (load_ram(args, translation), load_ram(args, translation)) {
+        (Some(a), Some(b)) => (CPU::new(a, translation), CPU::new(b, translation)),
+        _ => return None,
+    };
+    a.reset();
+    b.reset();
+    Some((a, b))
+}
+
+// time the program from the start on two fresh machines, one single stepping and one in the block engine
+fn bench(args: &[String], cpu: &CPU, n: u64) {
+    if stopped(cpu) {
+        return;
+    }
+    if cpu.timed() {
+        println!("The block engine doesn't model timing, switch the timing models off to compare it");
+        return;
+    }
+    if let Some((mut stepped, mut threaded)) = fresh_pair(args) {
+        BlockEngine::benchmark(&mut stepped, &mut threaded, n);
+    }
+}
+
+// run the program from the start on two fresh machines, one through the JIT and one single stepping
+#[cfg(feature = "jit")]
+fn jit_check(args: &[String], n: u64) {
+    if let Some((mut jitted, mut reference)) = fresh_pair(args) {
+        jit::cross_check(&mut jitted, &mut reference, n);
+    }
+}
+
+fn cache(cpu: &mut CPU, args: &[&str]) {
+    match args {
+        ["on"] | ["off"] => if (args[0] == "on") != cpu.caches_on() { cpu.toggle_caches() },
+        [which @ ("i" | "d"), config @ ..] if !config.is_empty() => match CacheConfig::parse(config) {
+            Ok(config) => cpu.configure_cache(*which == "i", config),
+            Err(error) => println!("Invalid cache configuration: {}", error),
+        },
+        _ => println!("Usage: cache on/off, or cache i/d size ways line [lru/fifo/random] [wb/wt] [penalty]"),
+    }
+}
+
+fn timing(cpu: &mut CPU, args: &[&str]) {
+    match args {
+        [] => cpu.print_timing(),
+        [profile] => cpu.set_timing_profile(profile),
+        [class, issue, latency] => match (issue.parse(), latency.parse()) {
+            (Ok(issue), Ok(latency)) => cpu.set_timing(class, issue, latency),
+            _ => println!("Issue and latency have to be numbers"),
+        },
+        _ => println!("Usage: timing, timing profile or timing class issue latency"),
+    }
+}
+
+fn eic(cpu: &mut CPU, args: &[&str]) {
+    match args {
+        ["on"] => cpu.set_interrupt_controller(Some(Box::new(PriorityController::new()))),
+        ["off"] => cpu.set_interrupt_controller(None),
+        [source, level] => match (source.parse(), level.parse()) {
+            (Ok(source), Ok(level)) => cpu.set_interrupt_priority(source, level),
+            _ => println!("Source and level have to be numbers"),
+        },
+        _ => println!("Usage: eic on/off or eic source level"),
+    }
+}
+
+fn irq(cpu: &mut CPU, args: &[&str]) {
+    match args {
+        [line, state @ ("on" | "off")] => match line.parse() {
+            //set_interrupt_line knows how many lines there are, 6 or 64 in EIC mode
+            Ok(line) => cpu.set_interrupt_line(line, *state == "on"),
+            Err(_) => println!("'{}' is not an interrupt line", line),
+        },
+        _ => println!("Usage: irq line on/off"),
+    }
+}
+
+fn mpu(cpu: &mut CPU, args: &[&str]) {
+    match args {
+        [] => cpu.print_mpu(),
+        ["on"] | ["off"] => cpu.set_mpu(args[0] == "on"),
+        [index, region @ ..] => match (index.parse(), region) {
+            (Ok(index), ["off"]) => cpu.set_mpu_region(index, None),
+            (Ok(index), _) => match Region::parse(region) {
+                Ok(region) => cpu.set_mpu_region(index, Some(region)),
+                Err(error) => println!("Invalid MPU region: {}", error),
+            },
+            _ => println!("The region has to be a number"),
+        },
+    }
+}
+
+fn exceptions(cpu: &mut CPU, args: &[&str]) {
+    match args {
+        [] => cpu.print_default_handler(),
+        [code, action] => match (code.parse(), Action::parse(action)) {
+            (Ok(code), Ok(action)) => cpu.set_default_action(code, action),
+            (Err(_), _) => println!("The exception code has to be a number"),
+            (_, Err(error)) => println!("{}", error),
+        },
+        _ => println!("Usage: exceptions, or exceptions code skip/halt"),
+    }
+}
+
+fn overflow(cpu: &mut CPU, arg: &str) {
+    let action = match arg {
+        "stop" => Action::Halt,
+        "trap" => Action::Skip,
+        _ => return println!("Usage: overflow stop/trap"),
+    };
+    cpu.set_default_action(Exception::Overflow as u32, action);
+}
+
 fn read_regs(cpu: &CPU, format_hex: bool) {
     cpu.print_reg(format_hex);
 }
@@ -81,3 +353,20 @@ fn read_mem(cpu: &CPU, start: u32, end: u32) {
 fn read_inst(cpu: &CPU, offset: i16 ) {
     cpu.print_instruction(offset);
 }
+
+fn disasm(cpu: &CPU, n: u32, style: Style) {
+    cpu.print_disassembly(n, style);
+}
+
+fn asm(cpu: &mut CPU, address: &str, instruction: &str) {
+    let address = match u32::from_str_radix(address.trim_start_matches("0x"), 16) {
+        Ok(address) if address % 4 == 0 => address,
+        Ok(_) => return println!("Instructions have to be word aligned"),
+        Err(_) => return println!("'{}' is not a hex address", address),
+    };
+
+    match assemble_line(instruction, address) {
+        Ok(words) => cpu.patch_instructions(address, &words),
+        Err(error) => println!("Can't assemble '{}': {}", instruction, error),
+    }
+}
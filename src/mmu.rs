@@ -0,0 +1,368 @@
+// Address translation.
+//
+// RAM is one flat 4GB array of physical memory. How a program's virtual addresses land in it is
+// decided when the machine is built: either every address is its own physical address (the way
+// MARS does it), or the core has fixed mapping translation like a microAptiv UC or M4K, or a TLB
+// like a 4KEc. In both of the latter kseg0 and kseg1 are windows onto the low 512MB. With fixed
+// mapping kuseg simply moves up by 1GB, with a TLB kuseg, kseg2 and kseg3 are mapped page by page
+// by the kernel through the EntryHi/EntryLo0/EntryLo1/PageMask registers and the
+// tlbwi/tlbwr/tlbr/tlbp instructions.
+
+use crate::exceptionprocessor::Exception;
+
+// the unmapped kernel segments start here, kseg2 (mapped again) after them
+const KSEG0: u32 = 0x8000_0000;
+const KSEG2: u32 = 0xC000_0000;
+const KSEG_PHYSICAL: u32 = 0x1FFF_FFFF;     //what is left of a kseg0/kseg1 address in physical memory
+const FIXED_KUSEG_OFFSET: u32 = 0x4000_0000;    //where fixed mapping puts kuseg in physical memory
+
+// where the machine starts after a reset: the .text base of MARS programs, or the reset vector
+// PIC32 firmware has its boot code at
+const TEXT_BASE: u32 = 0x0040_0000;
+const RESET_VECTOR: u32 = 0xBFC0_0000;
+
+// JTLB entries, Random cycles through the ones above Wired
+pub const TLB_ENTRIES: u32 = 16;
+
+// EntryLo fields
+const ENTRYLO_G: u32 = 1 << 0;          //global, the ASID doesn't matter
+const ENTRYLO_V: u32 = 1 << 1;          //valid
+const ENTRYLO_D: u32 = 1 << 2;          //dirty, writes are allowed
+const ENTRYLO_PFN_SHIFT: u32 = 6;
+const ENTRYLO_WRITABLE: u32 = 0x3FFF_FFFF;  //PFN, C, D, V, G
+
+const ENTRYHI_VPN2: u32 = 0xFFFF_E000;  //virtual page pair number
+const ENTRYHI_ASID: u32 = 0xFF;         //address space of the running process
+const PAGEMASK_WRITABLE: u32 = 0x1FFF_E000;
+const CONTEXT_PTEBASE: u32 = 0xFF80_0000;   //page table base, software's part of Context
+const CONTEXT_BADVPN2_SHIFT: u32 = 9;   //VPN2 of the faulting address ends up in bits 22-4
+const CONTEXT_BADVPN2: u32 = 0x007F_FFF0;
+const INDEX_P: u32 = 1 << 31;           //tlbp found nothing
+
+// how virtual addresses become physical ones
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Translation {
+    Identity,   //every address is its own physical address
+    Fixed,      //kseg0/kseg1 are the low 512MB, kuseg is 1GB further up, kseg2/kseg3 stay
+    Tlb,        //kseg0/kseg1 are the low 512MB, kuseg/kseg2/kseg3 go through the TLB
+}
+
+impl Translation {
+    //parse the name of a translation mode given on the command line
+    pub fn parse(name: &str) -> Result<Translation, String> {
+        match name.to_lowercase().as_str() {
+            "identity" => Ok(Translation::Identity),
+            "fmt" | "fixed" => Ok(Translation::Fixed),
+            "tlb" => Ok(Translation::Tlb),
+            _ => Err(format!("'{}' is not a translation mode, use identity, fmt or tlb", name)),
+        }
+    }
+
+    //where a program section linked at an address gets loaded, kernel code and data have to end
+    //up where kseg0 and kseg1 look, with fixed mapping user code and data where kuseg looks
+    pub fn load_address(&self, address: u32) -> u32 {
+        match self {
+            Translation::Fixed => fixed_mapping(address, false),
+            Translation::Tlb if (KSEG0..KSEG2).contains(&address) => address & KSEG_PHYSICAL,
+            _ => address,
+        }
+    }
+
+    //the address execution starts at after a reset
+    pub fn reset_address(&self) -> u32 {
+        match self {
+            Translation::Fixed => RESET_VECTOR,
+            _ => TEXT_BASE,
+        }
+    }
+}
+
+// fixed mapping translation of an address, kuseg is unmapped while the CPU is in error mode (ERL)
+pub fn fixed_mapping(address: u32, unmapped_kuseg: bool) -> u32 {
+    match address {
+        0..KSEG0 if unmapped_kuseg => address,
+        0..KSEG0 => address + FIXED_KUSEG_OFFSET,
+        KSEG0..KSEG2 => address & KSEG_PHYSICAL,
+        _ => address,
+    }
+}
+
+// what an address is translated for
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    //pick the load or store flavour of an exception, fetches count as loads
+    pub fn exception(self, load: Exception, store: Exception) -> Exception {
+        if self == Access::Store { store } else { load }
+    }
+}
+
+// why a translation failed, refills have their own vector
+#[derive(Clone, Copy)]
+pub struct Fault {
+    pub exception: Exception,
+    pub refill: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Entry {
+    mask: u32,      //PageMask, which VPN2 bits are page offset instead
+    vpn2: u32,
+    asid: u32,
+    global: bool,
+    lo: [u32; 2],   //EntryLo of the even and the odd page
+}
+
+pub struct Tlb {
+    entries: [Entry; TLB_ENTRIES as usize],
+    Index: u32,
+    EntryLo: [u32; 2],
+    Context: u32,
+    PageMask: u32,
+    Wired: u32,
+    EntryHi: u32,
+    random_since: u64,  //cycle Wired was written at, Random counts down from the top entry since
+}
+
+impl Tlb {
+    //construct a TLB with every entry invalid. Like boot code would, every entry gets a different
+    //kseg0 address, so they can't match anything
+    pub fn new() -> Tlb {
+        let mut entries = [Entry::default(); TLB_ENTRIES as usize];
+        for (index, entry) in entries.iter_mut().enumerate() {
+            entry.vpn2 = KSEG0 + ((index as u32) << 13);
+        }
+        Tlb { entries, Index: 0, EntryLo: [0; 2], Context: 0, PageMask: 0, Wired: 0, EntryHi: 0, random_since: 0 }
+    }
+
+    //translate an address, unmapped segments included
+    pub fn translate(&self, address: u32, access: Access, unmapped_kuseg: bool) -> Result<u32, Fault> {
+        if (KSEG0..KSEG2).contains(&address) {
+            return Ok(address & KSEG_PHYSICAL);
+        }
+        if unmapped_kuseg && address < KSEG0 {
+            return Ok(address);
+        }
+
+        let entry = match self.lookup(address) {
+            Some(index) => &self.entries[index],
+            None => return Err(Fault { exception: access.exception(Exception::TlbLoad, Exception::TlbStore), refill: true }),
+        };
+
+        //the lowest VPN2 bit that isn't page offset picks the even or the odd page
+        let page = (entry.mask | 0x1FFF) >> 1;
+        let lo = entry.lo[(address & (page + 1) != 0) as usize];
+        if lo & ENTRYLO_V == 0 {
+            return Err(Fault { exception: access.exception(Exception::TlbLoad, Exception::TlbStore), refill: false });
+        }
+        if access == Access::Store && lo & ENTRYLO_D == 0 {
+            return Err(Fault { exception: Exception::TlbModified, refill: false });
+        }
+
+        let frame = (lo >> ENTRYLO_PFN_SHIFT) << 12;
+        Ok((frame & !page) | (address & page))
+    }
+
+    //the number of the entry matching an address in the current address space
+    fn lookup(&self, address: u32) -> Option<usize> {
+        let asid = self.EntryHi & ENTRYHI_ASID;
+        self.entries.iter().position(|entry| {
+            let vpn2 = ENTRYHI_VPN2 & !entry.mask;
+            address & vpn2 == entry.vpn2 & vpn2 && (entry.global || entry.asid == asid)
+        })
+    }
+
+    //remember a faulting address so the handler can refill the right entry
+    pub fn fault(&mut self, address: u32) {
+        self.EntryHi = (address & ENTRYHI_VPN2) | (self.EntryHi & ENTRYHI_ASID);
+        self.Context = (self.Context & !CONTEXT_BADVPN2) | ((address >> CONTEXT_BADVPN2_SHIFT) & CONTEXT_BADVPN2);
+    }
+
+    //the entry tlbwr replaces at a given cycle, one of the entries above Wired
+    fn random(&self, cycles: u64) -> u32 {
+        let wired = self.Wired.min(TLB_ENTRIES - 1);
+        TLB_ENTRIES - 1 - ((cycles - self.random_since) % (TLB_ENTRIES - wired) as u64) as u32
+    }
+
+    //tlbr: read the entry Index points to into EntryHi, EntryLo0, EntryLo1 and PageMask
+    pub fn read_entry(&mut self) {
+        let entry = self.entries[(self.Index % TLB_ENTRIES) as usize];
+        self.EntryHi = entry.vpn2 | entry.asid;
+        self.PageMask = entry.mask;
+        self.EntryLo = entry.lo.map(|lo| if entry.global { lo | ENTRYLO_G } else { lo & !ENTRYLO_G });
+    }
+
+    //tlbwi and tlbwr: write EntryHi, EntryLo0, EntryLo1 and PageMask into the entry Index points
+    //to, or a random one
+    pub fn write_entry(&mut self, random: bool, cycles: u64) {
+        let index = if random { self.random(cycles) } else { self.Index % TLB_ENTRIES };
+        self.entries[index as usize] = Entry {
+            mask: self.PageMask,
+            vpn2: self.EntryHi & ENTRYHI_VPN2 & !self.PageMask,
+            asid: self.EntryHi & ENTRYHI_ASID,
+            global: self.EntryLo[0] & self.EntryLo[1] & ENTRYLO_G != 0,
+            lo: self.EntryLo.map(|lo| lo & !ENTRYLO_G),
+        };
+    }
+
+    //tlbp: look for the entry matching EntryHi and put its number in Index
+    pub fn probe(&mut self) {
+        self.Index = match self.lookup(self.EntryHi) {
+            Some(index) => index as u32,
+            None => INDEX_P,
+        };
+    }
+
+    //read one of the TLB's CP0 registers at a given CPU cycle
+    pub fn read(&self, register: u8, cycles: u64) -> u32 {
+        match register {
+            0 => self.Index,
+            1 => self.random(cycles),
+            2 => self.EntryLo[0],
+            3 => self.EntryLo[1],
+            4 => self.Context,
+            5 => self.PageMask,
+            6 => self.Wired,
+            10 => self.EntryHi,
+            _ => 0,
+        }
+    }
+
+    //write one of the TLB's CP0 registers at a given CPU cycle
+    pub fn write(&mut self, register: u8, value: u32, cycles: u64) {
+        match register {
+            0 => self.Index = value % TLB_ENTRIES,
+            2 => self.EntryLo[0] = value & ENTRYLO_WRITABLE,
+            3 => self.EntryLo[1] = value & ENTRYLO_WRITABLE,
+            4 => self.Context = (self.Context & !CONTEXT_PTEBASE) | (value & CONTEXT_PTEBASE),
+            5 => self.PageMask = value & PAGEMASK_WRITABLE,
+            6 => {
+                //writing Wired starts Random over at the top
+                self.Wired = value % TLB_ENTRIES;
+                self.random_since = cycles;
+            }
+            10 => self.EntryHi = value & (ENTRYHI_VPN2 | ENTRYHI_ASID),
+            _ => (),
+        }
+    }
+
+    //print every valid entry
+    pub fn print(&self) {
+        println!("\t----- TLB -----\t");
+        println!("Entry\tVPN2\t\tASID\tMask\t\tEntryLo0\tEntryLo1");
+        for (index, entry) in self.entries.iter().enumerate() {
+            if (entry.lo[0] | entry.lo[1]) & ENTRYLO_V == 0 {
+                continue;
+            }
+            let asid = if entry.global { "G".to_string() } else { entry.asid.to_string() };
+            println!("{:>2}\t0x{:0>8X}\t{}\t0x{:0>8X}\t0x{:0>8X}\t0x{:0>8X}", index, entry.vpn2, asid, entry.mask, entry.lo[0], entry.lo[1]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a TLB with one entry written through the CP0 registers like tlbwi would
+    fn tlb(entry_hi: u32, lo0: u32, lo1: u32, mask: u32) -> Tlb {
+        let mut tlb = Tlb::new();
+        tlb.write(10, entry_hi, 0);
+        tlb.write(2, lo0, 0);
+        tlb.write(3, lo1, 0);
+        tlb.write(5, mask, 0);
+        tlb.write(0, 3, 0);
+        tlb.write_entry(false, 0);
+        tlb
+    }
+
+    //EntryLo for a page frame with some of V, D and G set
+    fn lo(frame: u32, flags: u32) -> u32 {
+        (frame >> 12 << ENTRYLO_PFN_SHIFT) | flags
+    }
+
+    fn fault(result: Result<u32, Fault>) -> (Exception, bool) {
+        match result {
+            Ok(physical) => panic!("translated to 0x{:08x}", physical),
+            Err(fault) => (fault.exception, fault.refill),
+        }
+    }
+
+    #[test]
+    fn tlb_unmapped_segments() {
+        let tlb = Tlb::new();
+        assert_eq!(tlb.translate(0x8000_1234, Access::Load, false).ok(), Some(0x0000_1234));
+        assert_eq!(tlb.translate(0xBFC0_0000, Access::Fetch, false).ok(), Some(0x1FC0_0000));
+        assert_eq!(tlb.translate(0x0040_0000, Access::Fetch, true).ok(), Some(0x0040_0000));
+    }
+
+    #[test]
+    fn tlb_miss_refills() {
+        let tlb = Tlb::new();
+        assert_eq!(fault(tlb.translate(0x0040_0000, Access::Fetch, false)), (Exception::TlbLoad, true));
+        assert_eq!(fault(tlb.translate(0x1001_0000, Access::Store, false)), (Exception::TlbStore, true));
+        assert_eq!(fault(tlb.translate(0xC000_0000, Access::Load, false)), (Exception::TlbLoad, true));
+    }
+
+    #[test]
+    fn tlb_even_and_odd_pages() {
+        let tlb = tlb(0x0040_0000, lo(0x0012_3000, ENTRYLO_V | ENTRYLO_G), lo(0x0045_6000, ENTRYLO_V | ENTRYLO_D | ENTRYLO_G), 0);
+        assert_eq!(tlb.translate(0x0040_0abc, Access::Fetch, false).ok(), Some(0x0012_3abc));
+        assert_eq!(tlb.translate(0x0040_1abc, Access::Store, false).ok(), Some(0x0045_6abc));
+        //the next pair isn't mapped
+        assert_eq!(fault(tlb.translate(0x0040_2000, Access::Load, false)), (Exception::TlbLoad, true));
+    }
+
+    #[test]
+    fn tlb_invalid_and_clean_pages() {
+        let tlb = tlb(0x0040_0000, lo(0x0012_3000, ENTRYLO_V | ENTRYLO_G), lo(0x0045_6000, ENTRYLO_G), 0);
+        assert_eq!(fault(tlb.translate(0x0040_0000, Access::Store, false)), (Exception::TlbModified, false));
+        assert_eq!(fault(tlb.translate(0x0040_1000, Access::Load, false)), (Exception::TlbLoad, false));
+        assert_eq!(fault(tlb.translate(0x0040_1000, Access::Store, false)), (Exception::TlbStore, false));
+    }
+
+    #[test]
+    fn tlb_address_spaces() {
+        let mut tlb = tlb(0x0040_0000 | 5, lo(0x0012_3000, ENTRYLO_V), lo(0x0045_6000, ENTRYLO_V), 0);
+        assert_eq!(tlb.translate(0x0040_0000, Access::Load, false).ok(), Some(0x0012_3000));
+
+        tlb.write(10, 6, 0);
+        assert_eq!(fault(tlb.translate(0x0040_0000, Access::Load, false)), (Exception::TlbLoad, true));
+    }
+
+    #[test]
+    fn tlb_large_pages() {
+        //16KB pages, a pair covers 32KB
+        let tlb = tlb(0x0040_0000, lo(0x0010_0000, ENTRYLO_V | ENTRYLO_G), lo(0x0020_0000, ENTRYLO_V | ENTRYLO_G), 0x6000);
+        assert_eq!(tlb.translate(0x0040_3ffc, Access::Load, false).ok(), Some(0x0010_3ffc));
+        assert_eq!(tlb.translate(0x0040_4000, Access::Load, false).ok(), Some(0x0020_0000));
+        assert_eq!(tlb.translate(0x0040_7ffc, Access::Load, false).ok(), Some(0x0020_3ffc));
+    }
+
+    #[test]
+    fn fixed_mapping_segments() {
+        //kuseg moves up by 1GB unless ERL is set
+        assert_eq!(fixed_mapping(0x0040_0000, false), 0x4040_0000);
+        assert_eq!(fixed_mapping(0x7FFF_FFFC, false), 0xBFFF_FFFC);
+        assert_eq!(fixed_mapping(0x0040_0000, true), 0x0040_0000);
+        //kseg0 and kseg1 are the low 512MB
+        assert_eq!(fixed_mapping(0x9D00_0000, false), 0x1D00_0000);
+        assert_eq!(fixed_mapping(0xBFC0_0000, false), 0x1FC0_0000);
+        assert_eq!(fixed_mapping(0xBFC0_0000, true), 0x1FC0_0000);
+        //kseg2 and kseg3 stay where they are
+        assert_eq!(fixed_mapping(0xC000_0000, false), 0xC000_0000);
+        assert_eq!(fixed_mapping(0xFFFF_FFFC, false), 0xFFFF_FFFC);
+    }
+
+    #[test]
+    fn fixed_mapping_reset() {
+        assert_eq!(Translation::Fixed.reset_address(), RESET_VECTOR);
+        assert_eq!(Translation::Fixed.load_address(RESET_VECTOR), 0x1FC0_0000);
+        assert_eq!(Translation::Identity.reset_address(), TEXT_BASE);
+    }
+}
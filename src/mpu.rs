@@ -0,0 +1,160 @@
+// Memory protection unit, the way microcontroller cores without a TLB keep tasks apart.
+//
+// Up to 16 regions of virtual addresses each say what kernel and user mode code may do there:
+// read, write and/or execute. Where regions overlap the one with the highest number wins.
+// Addresses outside of every region are open to the kernel and closed to user code. A violation
+// raises the same exceptions a TLB with read and execute inhibit bits would: TLBRI for loads,
+// TLBXI for fetches and Mod for stores.
+
+use crate::exceptionprocessor::Exception;
+use crate::mmu::Access;
+
+pub const MPU_REGIONS: usize = 16;
+
+// permission bits
+pub const READ: u8 = 1 << 0;
+pub const WRITE: u8 = 1 << 1;
+pub const EXECUTE: u8 = 1 << 2;
+
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub base: u32,
+    pub size: u32,
+    pub kernel: u8,     //what kernel mode may do in the region
+    pub user: u8,       //what user mode may do in the region
+}
+
+impl Region {
+    //parse "base size kernel user", with hex addresses and permissions like rwx, r-x or -
+    pub fn parse(words: &[&str]) -> Result<Region, String> {
+        let hex = |word: &str| u32::from_str_radix(word.trim_start_matches("0x"), 16).map_err(|_| format!("'{}' is not a hex number", word));
+
+        let region = match words {
+            [base, size, kernel, user] => Region { base: hex(base)?, size: hex(size)?, kernel: permissions(kernel)?, user: permissions(user)? },
+            _ => return Err("a region needs a base, a size and the kernel and user permissions".to_string()),
+        };
+
+        if region.size == 0 || !region.base.is_multiple_of(4) || !region.size.is_multiple_of(4) || region.base.checked_add(region.size - 1).is_none() {
+            return Err("regions have to be word aligned, not empty and end below 4GB".to_string());
+        }
+        Ok(region)
+    }
+
+    fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.base) < self.size
+    }
+}
+
+// permissions from a string of r, w, x and -
+fn permissions(word: &str) -> Result<u8, String> {
+    word.chars().try_fold(0, |permissions, c| match c {
+        'r' => Ok(permissions | READ),
+        'w' => Ok(permissions | WRITE),
+        'x' => Ok(permissions | EXECUTE),
+        '-' => Ok(permissions),
+        _ => Err(format!("'{}' are no permissions, use r, w, x and -", word)),
+    })
+}
+
+// permissions as rwx with dashes for the missing ones
+fn describe(permissions: u8) -> String {
+    [(READ, 'r'), (WRITE, 'w'), (EXECUTE, 'x')].iter()
+        .map(|&(bit, c)| if permissions & bit != 0 { c } else { '-' })
+        .collect()
+}
+
+pub struct Mpu {
+    regions: [Option<Region>; MPU_REGIONS],
+}
+
+impl Mpu {
+    //construct an MPU without any regions
+    pub fn new() -> Mpu {
+        Mpu { regions: [None; MPU_REGIONS] }
+    }
+
+    //define or (with None) remove a region
+    pub fn set_region(&mut self, index: usize, region: Option<Region>) {
+        self.regions[index] = region;
+    }
+
+    //may an access to an address happen in kernel or user mode? If not, the exception it raises
+    #[inline]
+    pub fn check(&self, address: u32, access: Access, user: bool) -> Result<(), Exception> {
+        let permissions = match self.regions.iter().rev().flatten().find(|region| region.contains(address)) {
+            Some(region) => if user { region.user } else { region.kernel },
+            None if user => 0,
+            None => READ | WRITE | EXECUTE,
+        };
+
+        let (needed, exception) = match access {
+            Access::Fetch => (EXECUTE, Exception::ExecuteInhibit),
+            Access::Load => (READ, Exception::ReadInhibit),
+            Access::Store => (WRITE, Exception::TlbModified),
+        };
+        if permissions & needed == 0 { Err(exception) } else { Ok(()) }
+    }
+
+    //print every defined region
+    pub fn print(&self) {
+        println!("\t----- MPU REGIONS -----\t");
+        println!("Region\tStart\t\tEnd\t\tKernel\tUser");
+        for (index, region) in self.regions.iter().enumerate() {
+            if let Some(region) = region {
+                println!("{:>2}\t0x{:0>8X}\t0x{:0>8X}\t{}\t{}", index, region.base, region.base + (region.size - 1), describe(region.kernel), describe(region.user));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mpu(regions: &[&str]) -> Mpu {
+        let mut mpu = Mpu::new();
+        for (index, region) in regions.iter().enumerate() {
+            let words: Vec<&str> = region.split_whitespace().collect();
+            mpu.set_region(index, Some(Region::parse(&words).unwrap()));
+        }
+        mpu
+    }
+
+    #[test]
+    fn violations() {
+        let mpu = mpu(&["1000 1000 rwx r--"]);
+        assert_eq!(mpu.check(0x1000, Access::Load, true), Ok(()));
+        assert_eq!(mpu.check(0x1FFC, Access::Store, true), Err(Exception::TlbModified));
+        assert_eq!(mpu.check(0x1800, Access::Fetch, true), Err(Exception::ExecuteInhibit));
+        assert_eq!(mpu.check(0x1800, Access::Fetch, false), Ok(()));
+
+        //outside of every region only the kernel may go
+        assert_eq!(mpu.check(0x2000, Access::Load, true), Err(Exception::ReadInhibit));
+        assert_eq!(mpu.check(0x2000, Access::Store, false), Ok(()));
+    }
+
+    #[test]
+    fn highest_region_wins() {
+        let mpu = mpu(&["0 10000 rwx rwx", "4000 1000 rwx r--", "4800 100 rwx ---"]);
+        assert_eq!(mpu.check(0x3FFC, Access::Store, true), Ok(()));
+        assert_eq!(mpu.check(0x4000, Access::Store, true), Err(Exception::TlbModified));
+        assert_eq!(mpu.check(0x4800, Access::Load, true), Err(Exception::ReadInhibit));
+        assert_eq!(mpu.check(0x4900, Access::Load, true), Ok(()));
+
+        //the order regions were defined in doesn't matter, only their numbers
+        let mut mpu = Mpu::new();
+        mpu.set_region(5, Some(Region::parse(&["0", "10000", "rwx", "---"]).unwrap()));
+        mpu.set_region(2, Some(Region::parse(&["4000", "1000", "rwx", "rwx"]).unwrap()));
+        assert_eq!(mpu.check(0x4000, Access::Load, true), Err(Exception::ReadInhibit));
+    }
+
+    #[test]
+    fn parse() {
+        assert!(Region::parse(&["1000", "0", "rwx", "rwx"]).is_err());
+        assert!(Region::parse(&["1002", "100", "rwx", "rwx"]).is_err());
+        assert!(Region::parse(&["FFFFFF00", "200", "rwx", "rwx"]).is_err());
+        assert!(Region::parse(&["1000", "100", "rwz", "rwx"]).is_err());
+        let region = Region::parse(&["0x1000", "100", "r-x", "-"]).unwrap();
+        assert_eq!((region.base, region.size, region.kernel, region.user), (0x1000, 0x100, READ | EXECUTE, 0));
+    }
+}
@@ -0,0 +1,238 @@
+// Timing model of the classic five stage MIPS pipeline: IF, ID, EX, MEM and WB.
+//
+// Instructions are still executed by the functional model, at the moment they are fetched, so
+// the pipeline only decides when things happen and never what happens. It models full
+// forwarding into EX, branches resolved in ID (with the delay slot filling the only cycle that
+// would be lost otherwise), load-use stalls and the extra stalls of branches waiting for their
+// operands in ID.
+
+use crate::cpu::CPU;
+use crate::disassembler::{self, Style};
+use crate::instruction::{Instruction, Op};
+
+#[derive(Clone)]
+struct Slot {
+    address: u32,
+    inst: Instruction,
+    reads: Vec<u8>,
+    writes: Vec<u8>,
+    load: bool,         //result only available after MEM
+    reads_in_id: bool,  //branches and jumps need their operands one stage early
+}
+
+impl Slot {
+    fn new(address: u32, inst: Instruction) -> Slot {
+        let (reads, writes) = inst.dataflow();
+        let load = matches!(inst.op, Some(Op::LB) | Some(Op::LH) | Some(Op::LWL) | Some(Op::LW) | Some(Op::LBU)
+            | Some(Op::LHU) | Some(Op::LWR) | Some(Op::LL) | Some(Op::SC) | Some(Op::MFC0));
+        let reads_in_id = inst.has_delay_slot();
+
+        Slot { address, inst, reads, writes, load, reads_in_id }
+    }
+
+    //does this instruction produce a register the other one reads?
+    fn feeds(&self, other: &Slot) -> bool {
+        self.writes.iter().any(|w| other.reads.contains(w))
+    }
+}
+
+// why the instruction in ID has to wait
+#[derive(Clone, Copy)]
+enum Stall {
+    LoadUse,        //needs a loaded value in EX that is still being loaded
+    BranchOperand,  //branch needs a value in ID that isn't computed yet
+}
+
+const STAGE_NAMES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+const IF: usize = 0;
+const ID: usize = 1;
+const EX: usize = 2;
+const MEM: usize = 3;
+const WB: usize = 4;
+
+pub struct Pipeline {
+    stages: [Option<Slot>; 5],
+    pub cycles: u64,
+    pub retired: u64,
+    pub load_use_stalls: u64,
+    pub branch_stalls: u64,
+    pub forwards: u64,
+    pub memory_stalls: u64,     //cycles spent waiting for cache misses
+}
+
+impl Pipeline {
+    //construct an empty pipeline
+    pub fn new() -> Pipeline {
+        Pipeline { stages: [None, None, None, None, None], cycles: 0, retired: 0, load_use_stalls: 0, branch_stalls: 0, forwards: 0, memory_stalls: 0 }
+    }
+
+    //advance the pipeline by one clock cycle
+    pub fn cycle(&mut self, cpu: &mut CPU) {
+        self.cycles += 1;
+
+        //whatever sat in WB is done now
+        if self.stages[WB].is_some() {
+            self.retired += 1;
+        }
+
+        let stall = self.hazard();
+        match stall {
+            Some(Stall::LoadUse) => self.load_use_stalls += 1,
+            Some(Stall::BranchOperand) => self.branch_stalls += 1,
+            None => (),
+        }
+
+        //cycles that start an instruction are accounted for when it executes
+        if stall.is_some() {
+            cpu.elapse(1, true);
+        }
+
+        if stall.is_none() {
+            self.count_forwards();
+        }
+
+        //shift everything down one stage, a stall keeps IF and ID and sends a bubble into EX
+        self.stages[WB] = self.stages[MEM].take();
+        self.stages[MEM] = self.stages[EX].take();
+        if stall.is_none() {
+            self.stages[EX] = self.stages[ID].take();
+            self.stages[ID] = self.stages[IF].take();
+            self.stages[IF] = Some(self.fetch(cpu));
+        }
+
+        //the whole pipeline freezes while a cache miss is served
+        let memory_stall = cpu.take_memory_stalls();
+        self.cycles += memory_stall;
+        self.memory_stalls += memory_stall;
+
+        if cpu.trace {
+            self.print(stall);
+        }
+    }
+
+    //fetch (and execute) the next instruction on the path the functional model takes
+    fn fetch(&mut self, cpu: &mut CPU) -> Slot {
+        let address = cpu.PC;
+        let inst = cpu.fetch(address);
+        cpu.step(CPU::handler(inst.op), inst);
+        Slot::new(address, inst)
+    }
+
+    //why the instruction in ID can't move on this cycle, if it can't
+    fn hazard(&self) -> Option<Stall> {
+        let decoding = self.stages[ID].as_ref()?;
+
+        //a load in EX has its data only after MEM
+        if let Some(executing) = &self.stages[EX] {
+            if executing.feeds(decoding) && (executing.load || decoding.reads_in_id) {
+                return Some(if decoding.reads_in_id { Stall::BranchOperand } else { Stall::LoadUse });
+            }
+        }
+
+        //branches compare in ID, so they even wait for loads in MEM
+        if let Some(accessing) = &self.stages[MEM] {
+            if decoding.reads_in_id && accessing.load && accessing.feeds(decoding) {
+                return Some(Stall::BranchOperand);
+            }
+        }
+
+        None
+    }
+
+    //count the operands forwarded to the instruction about to leave ID
+    fn count_forwards(&mut self) {
+        let decoding = match &self.stages[ID] {
+            Some(decoding) => decoding,
+            None => return,
+        };
+
+        //branches get their operands from the EX/MEM latch right now, everything else
+        //from whatever is in EX and MEM at the moment once it reaches EX itself
+        let producers: &[usize] = if decoding.reads_in_id { &[MEM] } else { &[EX, MEM] };
+        let forwards = producers.iter()
+            .filter(|&&stage| self.stages[stage].as_ref().is_some_and(|producer| producer.feeds(decoding)))
+            .count();
+
+        self.forwards += forwards as u64;
+    }
+
+    //print what every stage holds
+    fn print(&self, stall: Option<Stall>) {
+        match stall {
+            Some(Stall::LoadUse) => println!("Cycle {} (stall: load-use)", self.cycles),
+            Some(Stall::BranchOperand) => println!("Cycle {} (stall: branch operand)", self.cycles),
+            None => println!("Cycle {}", self.cycles),
+        }
+
+        for (name, stage) in STAGE_NAMES.iter().zip(&self.stages) {
+            match stage {
+                Some(slot) => println!("  {:<4} 0x{:0>8X}  {}", name, slot.address, disassembler::disassemble(slot.inst.word, slot.address, Style::Mars)),
+                None => println!("  {:<4} -- bubble --", name),
+            }
+        }
+    }
+
+    //print cycles, instructions and what the cycles went into
+    pub fn print_stats(&self) {
+        println!("Cycles:\t\t\t{}", self.cycles);
+        println!("Instructions retired:\t{}", self.retired);
+        if self.retired > 0 {
+            println!("CPI:\t\t\t{:.3}", self.cycles as f64 / self.retired as f64);
+        }
+        println!("Load-use stalls:\t{}", self.load_use_stalls);
+        println!("Branch operand stalls:\t{}", self.branch_stalls);
+        println!("Cache miss stalls:\t{}", self.memory_stalls);
+        println!("Forwarded operands:\t{}", self.forwards);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::tests::cpu;
+
+    fn cycles(program: &[&str], n: usize) -> Pipeline {
+        let mut cpu = cpu(program);
+        let mut pipeline = Pipeline::new();
+        for _ in 0..n {
+            pipeline.cycle(&mut cpu);
+        }
+        pipeline
+    }
+
+    #[test]
+    fn load_use() {
+        let pipeline = cycles(&[
+            "lw $t1, 0($sp)",
+            "addu $t2, $t1, $t1",   //has to wait a cycle for the load
+            "lw $t3, 0($sp)",
+            "nop",
+            "addu $t4, $t3, $t3",   //gets it forwarded from MEM
+        ], 12);
+        assert_eq!(pipeline.load_use_stalls, 1);
+        assert_eq!(pipeline.branch_stalls, 0);
+        assert_eq!(pipeline.forwards, 2);
+    }
+
+    #[test]
+    fn branch_operands() {
+        let pipeline = cycles(&[
+            "addiu $t0, $zero, 1",
+            "beq $t0, $zero, 0x00400100",   //waits for the ALU result
+            "nop",
+            "lw $t1, 0($sp)",
+            "bne $t1, $zero, 0x00400100",   //waits for the load to get through MEM
+            "nop",
+        ], 14);
+        assert_eq!(pipeline.load_use_stalls, 0);
+        assert_eq!(pipeline.branch_stalls, 3);
+    }
+
+    #[test]
+    fn one_cycle_per_instruction_without_hazards() {
+        let pipeline = cycles(&["addiu $t0, $zero, 1", "addiu $t1, $zero, 2", "addu $t2, $t0, $t1"], 20);
+        //the first instruction leaves WB in cycle 6
+        assert_eq!(pipeline.retired, 15);
+        assert_eq!(pipeline.load_use_stalls + pipeline.branch_stalls, 0);
+    }
+}
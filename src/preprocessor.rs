@@ -0,0 +1,505 @@
+// Text level preprocessing for the assembler: .include, .eqv and .macro/.end_macro.
+//
+// The output is a flat list of lines, each remembering the file and line it came from (and the
+// macro call it was expanded at, if any) so the assembler can report errors against the source
+// the user actually wrote.
+
+use crate::assembler::{self, AsmError};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// give up on includes and macros nested deeper than this, they are most likely recursive
+const MAX_DEPTH: usize = 32;
+
+// one line of preprocessed source
+#[derive(Clone)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+    pub origin: Option<String>,     //"macro 'name' used at file:line" for expanded lines
+}
+
+impl SourceLine {
+    //build an error pointing at this line
+    pub fn error(&self, message: String) -> AsmError {
+        let message = match &self.origin {
+            Some(origin) => format!("{} (in {})", message, origin),
+            None => message,
+        };
+        AsmError { file: self.file.clone(), line: self.line, message }
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+pub struct Preprocessor {
+    eqvs: HashMap<String, String>,
+    macros: HashMap<(String, usize), Macro>,    //keyed by name and number of parameters, like MARS
+    expansions: usize,                          //used to make labels inside macros unique
+    output: Vec<SourceLine>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Preprocessor {
+        Preprocessor { eqvs: HashMap::new(), macros: HashMap::new(), expansions: 0, output: vec![] }
+    }
+
+    //preprocess a file and everything it includes
+    pub fn run_file(mut self, path: &str) -> Result<Vec<SourceLine>, AsmError> {
+        let source = read_source(path, None)?;
+        self.source(path, &source, 0)?;
+        Ok(self.output)
+    }
+
+    fn source(&mut self, file: &str, source: &str, depth: usize) -> Result<(), AsmError> {
+        let lines: Vec<SourceLine> = source.lines().enumerate()
+            .map(|(index, text)| SourceLine { file: file.to_string(), line: index + 1, text: text.to_string(), origin: None })
+            .collect();
+
+        self.lines(&lines, depth)
+    }
+
+    fn lines(&mut self, lines: &[SourceLine], depth: usize) -> Result<(), AsmError> {
+        let mut index = 0;
+
+        while index < lines.len() {
+            let line = &lines[index];
+            index += 1;
+
+            let code = strip_comment(&line.text);
+            let mut words = code.split_whitespace();
+
+            match words.next().map(|w| w.to_lowercase()).as_deref() {
+                Some(".eqv") => {
+                    let name = words.next().ok_or_else(|| line.error(".eqv needs a name and a value".to_string()))?;
+                    let value = code.trim_start()[4..].trim_start()[name.len()..].trim();
+                    if value.is_empty() {
+                        return Err(line.error(format!(".eqv '{}' needs a value", name)));
+                    }
+                    let value = self.substitute_eqvs(value);
+                    self.eqvs.insert(name.to_string(), value);
+                }
+                Some(".include") => {
+                    let path = code.trim_start()[8..].trim();
+                    let path = path.strip_prefix('"').and_then(|p| p.strip_suffix('"'))
+                        .ok_or_else(|| line.error(".include needs a quoted file name".to_string()))?;
+                    if depth >= MAX_DEPTH {
+                        return Err(line.error(format!("includes nested too deep at '{}'", path)));
+                    }
+
+                    let resolved = resolve_include(&line.file, path);
+                    let source = read_source(&resolved, Some(line))?;
+                    self.source(&resolved, &source, depth + 1)?;
+                }
+                Some(".macro") => {
+                    let (name, params) = parse_macro_header(&code).map_err(|message| line.error(message))?;
+
+                    //collect the body up to .end_macro
+                    let mut body = vec![];
+                    loop {
+                        let inner = lines.get(index).ok_or_else(|| line.error(format!("macro '{}' is missing .end_macro", name)))?;
+                        index += 1;
+
+                        match strip_comment(&inner.text).split_whitespace().next().map(|w| w.to_lowercase()).as_deref() {
+                            Some(".end_macro") => break,
+                            Some(".macro") => return Err(inner.error("macros can't be defined inside macros".to_string())),
+                            _ => body.push(inner.clone()),
+                        }
+                    }
+
+                    self.macros.insert((name, params.len()), Macro { params, body });
+                }
+                Some(".end_macro") => return Err(line.error(".end_macro without .macro".to_string())),
+                _ => {
+                    let text = self.substitute_eqvs(&line.text);
+                    let line = SourceLine { text, ..line.clone() };
+
+                    if !self.expand_macro(&line, depth)? {
+                        self.output.push(line);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    //expand the line if it is a macro call, returns false if it isn't one
+    fn expand_macro(&mut self, line: &SourceLine, depth: usize) -> Result<bool, AsmError> {
+        let code = strip_comment(&line.text);
+        let labels = leading_labels(&code);
+        let rest = code[labels.1..].trim();
+
+        let name_end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        if name.is_empty() || !self.macros.keys().any(|(n, _)| n == name) {
+            return Ok(false);
+        }
+
+        let args = split_arguments(rest[name_end..].trim());
+        let key = (name.to_string(), args.len());
+        if !self.macros.contains_key(&key) {
+            return Err(line.error(format!("no macro '{}' taking {} argument(s)", name, args.len())));
+        }
+        if depth >= MAX_DEPTH {
+            return Err(line.error(format!("macro '{}' nested too deep", name)));
+        }
+
+        //labels in front of the call stay where they are
+        if !labels.0.is_empty() {
+            let text = labels.0.iter().map(|l| format!("{}:", l)).collect::<Vec<_>>().join(" ");
+            self.output.push(SourceLine { text, ..line.clone() });
+        }
+
+        self.expansions += 1;
+        let suffix = format!("_M{}", self.expansions);
+        let origin = format!("macro '{}' used at {}:{}", name, line.file, line.line);
+
+        let definition = &self.macros[&key];
+        let local_labels: Vec<String> = definition.body.iter().flat_map(|l| leading_labels(&l.text).0).collect();
+        let bindings: HashMap<&str, &str> = definition.params.iter().map(|p| p.as_str()).zip(args.iter().map(|a| a.as_str())).collect();
+
+        let expanded: Vec<SourceLine> = definition.body.iter().map(|body_line| {
+            let text = replace_words(&body_line.text, &|word| {
+                if let Some(value) = bindings.get(word) {
+                    Some(value.to_string())
+                }
+                else if local_labels.iter().any(|l| l == word) {
+                    Some(format!("{}{}", word, suffix))
+                }
+                else {
+                    None
+                }
+            });
+            SourceLine { text, origin: Some(origin.clone()), ..body_line.clone() }
+        }).collect();
+
+        self.lines(&expanded, depth + 1)?;
+        Ok(true)
+    }
+
+    fn substitute_eqvs(&self, text: &str) -> String {
+        if self.eqvs.is_empty() {
+            return text.to_string();
+        }
+        replace_words(text, &|word| self.eqvs.get(word).cloned())
+    }
+}
+
+fn read_source(path: &str, included_from: Option<&SourceLine>) -> Result<String, AsmError> {
+    fs::read_to_string(path).map_err(|e| {
+        let message = format!("can't read {}: {}", path, e);
+        match included_from {
+            Some(line) => line.error(message),
+            None => AsmError { file: path.to_string(), line: 0, message },
+        }
+    })
+}
+
+// included files are looked up next to the including file first
+fn resolve_include(including: &str, path: &str) -> String {
+    let relative: PathBuf = Path::new(including).parent().unwrap_or_else(|| Path::new("")).join(path);
+    if relative.exists() {
+        relative.to_string_lossy().into_owned()
+    }
+    else {
+        path.to_string()
+    }
+}
+
+// drop everything after a # that isn't inside a string or character literal
+fn strip_comment(text: &str) -> String {
+    replace_words(text, &|_| None)
+}
+
+// walk over the words of a line outside of strings and comments and replace some of them,
+// the comment is dropped in the process. Words starting with % or $ are macro parameters.
+fn replace_words(text: &str, replace: &dyn Fn(&str) -> Option<String>) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == '#' {
+            break;
+        }
+        else if c == '"' || c == '\'' {
+            //copy literals untouched
+            out.push(c);
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' && i + 1 < chars.len() {
+                    out.push(chars[i]);
+                    i += 1;
+                }
+                out.push(chars[i]);
+                i += 1;
+            }
+            if i < chars.len() {
+                out.push(chars[i]);
+                i += 1;
+            }
+        }
+        else if c.is_ascii_digit() {
+            //numbers are never replaced
+            let start = i;
+            i += 1;
+            while i < chars.len() && chars[i].is_alphanumeric() {
+                i += 1;
+            }
+            out.extend(&chars[start..i]);
+        }
+        else if c == '$' {
+            //registers aren't either, but $ can start a macro parameter too
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            match assembler::parse_register(&word[1..]) {
+                Some(_) => out.push_str(&word),
+                None => out.push_str(&replace(&word).unwrap_or(word)),
+            }
+        }
+        else if c.is_alphabetic() || c == '_' || c == '.' || c == '%' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            out.push_str(&replace(&word).unwrap_or(word));
+        }
+        else {
+            out.push(c);
+            i += 1;
+        }
+    }
+
+    out
+}
+
+// the labels at the start of a line and the byte offset right after them
+fn leading_labels(text: &str) -> (Vec<String>, usize) {
+    let mut labels = vec![];
+    let mut end = 0;
+
+    loop {
+        let rest = &text[end..];
+        let trimmed = rest.trim_start();
+        let word_len = trimmed.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')).unwrap_or(trimmed.len());
+        let after = trimmed[word_len..].trim_start();
+
+        if word_len == 0 || !after.starts_with(':') {
+            return (labels, end);
+        }
+
+        labels.push(trimmed[..word_len].to_string());
+        end = text.len() - after.len() + 1;
+    }
+}
+
+// split macro arguments, either "(a, b)" or "a, b" or "a b"
+fn split_arguments(text: &str) -> Vec<String> {
+    let text = match text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+        Some(inner) => inner,
+        None => text,
+    };
+
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quote = None;
+
+    for c in text.chars() {
+        match (quote, c) {
+            (Some(q), _) if c == q => { quote = None; current.push(c); }
+            (Some(_), _) => current.push(c),
+            (None, '"') | (None, '\'') => { quote = Some(c); current.push(c); }
+            (None, '(') => { depth += 1; current.push(c); }
+            (None, ')') => { depth -= 1; current.push(c); }
+            (None, ',') | (None, ' ') | (None, '\t') if depth == 0 => {
+                if !current.is_empty() {
+                    args.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        args.push(current);
+    }
+
+    args
+}
+
+// ".macro name (%a, %b)" or ".macro name %a %b", MARS also takes $a and $b
+fn parse_macro_header(code: &str) -> Result<(String, Vec<String>), String> {
+    let rest = code.trim_start()[6..].trim();
+    let name_end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+    let name = &rest[..name_end];
+    if name.is_empty() {
+        return Err(".macro needs a name".to_string());
+    }
+
+    let params = split_arguments(rest[name_end..].trim());
+    for param in &params {
+        if !(param.starts_with('%') || param.starts_with('$')) || param.len() < 2 {
+            return Err(format!("macro parameter '{}' has to start with % or $", param));
+        }
+        if param.starts_with('$') && assembler::parse_register(&param[1..]).is_some() {
+            return Err(format!("macro parameter '{}' is a register", param));
+        }
+    }
+
+    Ok((name.to_string(), params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    //write some files to a fresh directory and preprocess the first one
+    fn run(files: &[(&str, &str)]) -> Result<Vec<SourceLine>, AsmError> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("rem-pp-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, source) in files {
+            fs::write(dir.join(name), source).unwrap();
+        }
+        let result = Preprocessor::new().run_file(dir.join(files[0].0).to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    //the non-empty output lines, trimmed
+    fn texts(source: &str) -> Vec<String> {
+        run(&[("main.asm", source)]).unwrap().iter().map(|l| l.text.trim().to_string()).filter(|t| !t.is_empty()).collect()
+    }
+
+    fn error(files: &[(&str, &str)]) -> AsmError {
+        match run(files) {
+            Ok(_) => panic!("preprocessed without an error"),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn macro_parameters() {
+        let source = "
+            .macro add3 (%d, %a, %b)
+            add %d, %a, %b
+            .end_macro
+            .macro neg $dst $src
+            sub $dst, $zero, $src
+            .end_macro
+            add3 ($t0, $t1, 5)
+            neg $s0 $s1
+        ";
+        assert_eq!(texts(source), vec!["add $t0, $t1, 5", "sub $s0, $zero, $s1"]);
+    }
+
+    #[test]
+    fn macros_by_argument_count() {
+        let source = "
+            .macro m
+            nop
+            .end_macro
+            .macro m %a
+            li %a, 1
+            .end_macro
+            m
+            m $v0
+        ";
+        assert_eq!(texts(source), vec!["nop", "li $v0, 1"]);
+    }
+
+    #[test]
+    fn local_labels_are_renamed() {
+        let source = "
+            .macro spin %n
+            li $t0, %n
+            loop: addi $t0, $t0, -1
+            bnez $t0, loop
+            .end_macro
+            start: spin 3
+            spin 4
+        ";
+        assert_eq!(texts(source), vec![
+            "start:",
+            "li $t0, 3", "loop_M1: addi $t0, $t0, -1", "bnez $t0, loop_M1",
+            "li $t0, 4", "loop_M2: addi $t0, $t0, -1", "bnez $t0, loop_M2",
+        ]);
+    }
+
+    #[test]
+    fn register_parameters() {
+        let message = error(&[("main.asm", ".macro m $t0\n.end_macro")]).message;
+        assert_eq!(message, "macro parameter '$t0' is a register");
+        let message = error(&[("main.asm", ".macro m x\n.end_macro")]).message;
+        assert_eq!(message, "macro parameter 'x' has to start with % or $");
+    }
+
+    #[test]
+    fn eqv() {
+        let source = "
+            .eqv SIZE 16
+            .eqv DOUBLE SIZE * 2    # eqvs can use earlier ones
+            li $t0, DOUBLE
+            la $a0, \"SIZE\"
+        ";
+        assert_eq!(texts(source), vec!["li $t0, 16 * 2", "la $a0, \"SIZE\""]);
+    }
+
+    #[test]
+    fn include() {
+        let lines = run(&[
+            ("main.asm", "nop\n.include \"lib.asm\"\nsyscall"),
+            ("lib.asm", "\n.eqv CODE 10\nli $v0, CODE"),
+        ]).unwrap();
+        let texts: Vec<&str> = lines.iter().map(|l| l.text.as_str()).filter(|t| !t.is_empty()).collect();
+        assert_eq!(texts, vec!["nop", "li $v0, 10", "syscall"]);
+
+        //lines remember where they came from
+        let included = lines.iter().find(|l| l.text.starts_with("li")).unwrap();
+        assert!(included.file.ends_with("lib.asm"));
+        assert_eq!(included.line, 3);
+    }
+
+    #[test]
+    fn recursion_is_stopped() {
+        let message = error(&[("main.asm", ".include \"main.asm\"")]).message;
+        assert!(message.starts_with("includes nested too deep"), "{}", message);
+
+        let message = error(&[("main.asm", ".macro forever\nforever\n.end_macro\nforever")]).message;
+        assert!(message.starts_with("macro 'forever' nested too deep"), "{}", message);
+    }
+
+    #[test]
+    fn errors_name_the_macro_use() {
+        let lines = run(&[("main.asm", ".macro m\nbogus $t0\n.end_macro\nnop\nm")]).unwrap();
+        let error = lines.last().unwrap().error("unknown instruction 'bogus'".to_string());
+        assert!(error.file.ends_with("main.asm"));
+        assert_eq!(error.line, 2);
+        assert!(error.message.starts_with("unknown instruction 'bogus' (in macro 'm' used at "), "{}", error.message);
+        assert!(error.message.ends_with("main.asm:5)"), "{}", error.message);
+    }
+
+    #[test]
+    fn macro_errors() {
+        assert_eq!(error(&[("main.asm", ".macro m\nnop")]).message, "macro 'm' is missing .end_macro");
+        assert_eq!(error(&[("main.asm", ".end_macro")]).message, ".end_macro without .macro");
+        assert_eq!(error(&[("main.asm", ".macro m %a\n.end_macro\nm 1, 2")]).message, "no macro 'm' taking 2 argument(s)");
+    }
+}
@@ -0,0 +1,160 @@
+// Per-core instruction timing.
+//
+// Every class of instructions gets an issue time (cycles it keeps the pipeline busy) and a
+// latency (cycles until its result can be used). Instructions reading a result that isn't ready
+// yet stall until it is, which is what makes e.g. an mflo right after a div expensive on cores
+// with an iterative divider. The numbers of the built in profiles are approximations taken from
+// the cores' software user manuals, worst case where the hardware has early outs.
+
+use crate::instruction::{Instruction, Op};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Alu,
+    Load,
+    Store,
+    Branch,
+    Multiply,       //mult, multu and mul
+    MultiplyAdd,    //madd, maddu, msub, msubu
+    Divide,
+    Cop0,
+}
+
+const CLASSES: [(Class, &str); 8] = [
+    (Class::Alu, "alu"),
+    (Class::Load, "load"),
+    (Class::Store, "store"),
+    (Class::Branch, "branch"),
+    (Class::Multiply, "mul"),
+    (Class::MultiplyAdd, "madd"),
+    (Class::Divide, "div"),
+    (Class::Cop0, "cop0"),
+];
+
+impl Class {
+    pub fn of(inst: &Instruction) -> Class {
+        match inst.op {
+            Some(Op::LB) | Some(Op::LH) | Some(Op::LWL) | Some(Op::LW) | Some(Op::LBU) | Some(Op::LHU) | Some(Op::LWR) | Some(Op::LL) => Class::Load,
+            Some(Op::SB) | Some(Op::SH) | Some(Op::SWL) | Some(Op::SW) | Some(Op::SWR) | Some(Op::SC) => Class::Store,
+            Some(Op::MULT) | Some(Op::MULTU) | Some(Op::MUL) => Class::Multiply,
+            Some(Op::MADD) | Some(Op::MADDU) | Some(Op::MSUB) | Some(Op::MSUBU) => Class::MultiplyAdd,
+            Some(Op::DIV) | Some(Op::DIVU) => Class::Divide,
+            Some(Op::MFC0) | Some(Op::MTC0) | Some(Op::DI) | Some(Op::EI) | Some(Op::ERET) => Class::Cop0,
+            _ if inst.has_delay_slot() => Class::Branch,
+            _ => Class::Alu,
+        }
+    }
+
+    //the multiply/divide unit runs next to the pipeline, so its latencies apply even when the
+    //pipeline model takes care of everything else
+    fn in_mdu(self) -> bool {
+        matches!(self, Class::Multiply | Class::MultiplyAdd | Class::Divide)
+    }
+
+    pub fn parse(name: &str) -> Option<Class> {
+        CLASSES.iter().find(|(_, n)| *n == name).map(|(class, _)| *class)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Cost {
+    pub issue: u32,
+    pub latency: u32,
+}
+
+const fn cost(issue: u32, latency: u32) -> Cost {
+    Cost { issue, latency }
+}
+
+// issue and latency of every class, in the order of CLASSES
+pub struct Profile {
+    pub name: String,
+    costs: [Cost; 8],
+}
+
+impl Profile {
+    //one cycle for everything, what the functional model assumes
+    pub fn ideal() -> Profile {
+        Profile { name: "ideal".to_string(), costs: [cost(1, 1); 8] }
+    }
+
+    //the built in profiles
+    pub fn named(name: &str) -> Option<Profile> {
+        let costs = match name {
+            "ideal" => return Some(Profile::ideal()),
+            //M4K with the fast multiply/divide unit: 32x32 multiplies in two cycles, up to 35 cycles for a divide
+            "m4k" => [cost(1, 1), cost(1, 2), cost(1, 1), cost(1, 1), cost(1, 2), cost(1, 2), cost(1, 35), cost(1, 2)],
+            //M4K with the area efficient unit: one bit per cycle for multiplies and divides
+            "m4k-small" => [cost(1, 1), cost(1, 2), cost(1, 1), cost(1, 1), cost(1, 33), cost(1, 33), cost(1, 34), cost(1, 2)],
+            //microAptiv UP/UC with the high performance MDU
+            "microaptiv" => [cost(1, 1), cost(1, 2), cost(1, 1), cost(1, 1), cost(1, 2), cost(1, 3), cost(1, 34), cost(1, 2)],
+            _ => return None,
+        };
+
+        Some(Profile { name: name.to_string(), costs })
+    }
+
+    pub fn set(&mut self, class: Class, cost: Cost) {
+        self.costs[class as usize] = cost;
+        if !self.name.ends_with('*') {
+            self.name.push('*');    //mark it as changed
+        }
+    }
+
+    fn cost(&self, class: Class) -> Cost {
+        self.costs[class as usize]
+    }
+
+    pub fn print(&self) {
+        println!("Timing profile {}:", self.name);
+        for (class, name) in CLASSES {
+            let cost = self.cost(class);
+            println!("  {:<8}issue {:>3}  latency {:>3}", name, cost.issue, cost.latency);
+        }
+    }
+}
+
+pub struct Timing {
+    pub profile: Profile,
+    ideal: bool,        //nothing to do, skip looking at the instructions at all
+    ready: [u64; 34],   //cycle each register's pending result becomes available
+}
+
+impl Timing {
+    pub fn new(profile: Profile) -> Timing {
+        let ideal = profile.costs.iter().all(|cost| cost.issue == 1 && cost.latency == 1);
+        Timing { profile, ideal, ready: [0; 34] }
+    }
+
+    pub fn is_ideal(&self) -> bool {
+        self.ideal
+    }
+
+    //cycles until the result of an instruction can be used
+    pub fn latency(&self, inst: &Instruction) -> u32 {
+        self.profile.cost(Class::of(inst)).latency
+    }
+
+    //the extra cycles (stalled, issuing) an instruction issued at cycle now takes beyond the usual one
+    pub fn account(&mut self, inst: &Instruction, now: u64, pipelined: bool) -> (u64, u64) {
+        if self.ideal {
+            return (0, 0);
+        }
+
+        let class = Class::of(inst);
+        let cost = self.profile.cost(class);
+        let (reads, writes) = inst.dataflow();
+
+        //wait for the operands
+        let ready = reads.iter().map(|&r| self.ready[r as usize]).max().unwrap_or(0);
+        let stall = ready.saturating_sub(now);
+
+        //the pipeline model already stalls for everything outside the multiply/divide unit
+        let latency = if pipelined && !class.in_mdu() { 1 } else { cost.latency };
+        for &r in &writes {
+            self.ready[r as usize] = now + stall + latency as u64;
+        }
+
+        (stall, cost.issue.saturating_sub(1) as u64)
+    }
+}
@@ -0,0 +1,421 @@
+// Educational out-of-order execution model after Tomasulo, extended with a reorder buffer.
+//
+// Instructions are fetched along a predicted path and issued in order into reservation stations
+// and the reorder buffer. The register status table renames every destination to the reorder
+// buffer entry producing it, so an instruction only waits for the values it really needs. It
+// executes as soon as they are all there, broadcasts its result on the common data bus (one per
+// cycle) and finally commits, strictly in program order.
+//
+// Like the pipeline model this one only decides when things happen. The functional model executes
+// each instruction when it commits, so everything it does, exceptions included, is precise by
+// construction: if the instruction the functional model goes on with isn't the next one in the
+// reorder buffer, the fetch path was wrong (a mispredicted branch or an exception) and everything
+// younger is flushed.
+
+use crate::cpu::CPU;
+use crate::disassembler::{self, Style};
+use crate::instruction::{self, Instruction, Op, REGISTER_NAMES};
+use crate::timing::Class;
+
+use std::collections::VecDeque;
+
+const ROB_SIZE: usize = 8;
+
+// groups of reservation stations, each station has its own functional unit
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Integer,        //alu operations, branches and coprocessor 0
+    Memory,         //load and store buffers
+    MultiplyDivide,
+}
+
+const UNITS: [(Unit, &str, usize); 3] = [
+    (Unit::Integer, "Int", 3),
+    (Unit::Memory, "Mem", 3),
+    (Unit::MultiplyDivide, "Mdu", 2),
+];
+
+impl Unit {
+    fn of(class: Class) -> Unit {
+        match class {
+            Class::Load | Class::Store => Unit::Memory,
+            Class::Multiply | Class::MultiplyAdd | Class::Divide => Unit::MultiplyDivide,
+            _ => Unit::Integer,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Issued,             //waiting in a reservation station for operands
+    Executing(u32),     //cycles left
+    Done,               //result computed, waiting for the common data bus
+    Written,            //result in the reorder buffer, waiting to commit
+}
+
+// where an operand comes from
+#[derive(Clone, Copy)]
+enum Source {
+    Register(u8),   //the committed register file
+    Rob(usize),     //a result already written to the reorder buffer
+}
+
+struct Entry {
+    slot: usize,    //number of the reorder buffer entry, what the renamed registers point to
+    address: u32,
+    inst: Instruction,
+    class: Class,
+    writes: Vec<u8>,
+    latency: u32,   //cycles in the functional unit, from the timing profile
+    state: State,
+    station: (Unit, usize),
+    ready: Vec<Source>,     //operand values at hand (Vj, Vk)
+    waiting: Vec<usize>,    //entries still computing an operand (Qj, Qk)
+    woken: u64,             //cycle the last operand arrived, execution starts the cycle after
+}
+
+pub struct Tomasulo {
+    rob: VecDeque<Entry>,
+    next_slot: usize,
+    stations: Vec<(Unit, Vec<Option<usize>>)>,  //the entry occupying every station
+    rename: [Option<usize>; 34],                //register status: entry producing each register
+    fetch_pc: u32,
+    predicted: Option<u32>,     //where fetch goes after the delay slot of the last branch issued
+    committed_last: Option<u32>,
+    pub cycles: u64,
+    pub committed: u64,
+    pub flushes: u64,
+    pub flushed: u64,           //instructions thrown away by flushes
+    pub rob_full: u64,          //cycles issue waited for a reorder buffer entry
+    pub stations_full: u64,     //cycles issue waited for a reservation station
+    pub bus_conflicts: u64,     //results that had to wait for the common data bus
+    pub memory_stalls: u64,     //cycles spent waiting for cache misses
+}
+
+impl Tomasulo {
+    //construct an empty machine that starts fetching at an address
+    pub fn new(pc: u32) -> Tomasulo {
+        Tomasulo {
+            rob: VecDeque::new(),
+            next_slot: 0,
+            stations: UNITS.iter().map(|&(unit, _, count)| (unit, vec![None; count])).collect(),
+            rename: [None; 34],
+            fetch_pc: pc,
+            predicted: None,
+            committed_last: None,
+            cycles: 0,
+            committed: 0,
+            flushes: 0,
+            flushed: 0,
+            rob_full: 0,
+            stations_full: 0,
+            bus_conflicts: 0,
+            memory_stalls: 0,
+        }
+    }
+
+    //advance the machine by one clock cycle
+    pub fn cycle(&mut self, cpu: &mut CPU) {
+        self.cycles += 1;
+        self.committed_last = None;
+
+        //going through the stages backwards keeps an instruction from passing several in one cycle
+        let committed = self.commit(cpu);
+        self.write_result();
+        self.execute();
+        self.issue(cpu);
+
+        //cycles that commit an instruction are accounted for when it executes
+        if !committed {
+            cpu.elapse(1, true);
+        }
+
+        let memory_stall = cpu.take_memory_stalls();
+        self.cycles += memory_stall;
+        self.memory_stalls += memory_stall;
+
+        if cpu.trace {
+            self.print(cpu);
+        }
+    }
+
+    //retire the oldest instruction if its result is written, this is where it really executes
+    fn commit(&mut self, cpu: &mut CPU) -> bool {
+        match self.rob.front() {
+            Some(head) if head.state == State::Written => (),
+            _ => return false,
+        }
+
+        //fetch again, a store committed since might have changed the instruction
+        let head = self.rob.pop_front().unwrap();
+        let inst = cpu.fetch(head.address);
+        if head.address != cpu.PC || inst.word != head.inst.word {
+            self.rob.push_front(head);
+            self.flush(cpu.PC);
+            return false;
+        }
+
+        cpu.step(CPU::handler(inst.op), inst);
+        self.committed += 1;
+        self.committed_last = Some(head.address);
+        for register in &mut self.rename {
+            if *register == Some(head.slot) {
+                *register = None;
+            }
+        }
+
+        //the functional model knows where execution really goes on
+        let next = self.rob.front().map_or(self.fetch_pc, |entry| entry.address);
+        if next != cpu.PC {
+            self.flush(cpu.PC);
+        }
+
+        true
+    }
+
+    //throw away everything in flight and fetch again from an address
+    fn flush(&mut self, pc: u32) {
+        self.flushes += 1;
+        self.flushed += self.rob.len() as u64;
+        self.rob.clear();
+        for (_, stations) in &mut self.stations {
+            stations.fill(None);
+        }
+        self.rename = [None; 34];
+        self.fetch_pc = pc;
+        self.predicted = None;
+    }
+
+    //start instructions whose operands are all there and count down the running ones
+    fn execute(&mut self) {
+        let cycle = self.cycles;
+        for index in 0..self.rob.len() {
+            let entry = &self.rob[index];
+            if entry.state == State::Issued && entry.waiting.is_empty() && entry.woken < cycle {
+                //without values to compare addresses, loads wait for all older stores
+                let blocked = entry.class == Class::Load && self.rob.iter().take(index)
+                    .any(|older| older.class == Class::Store && older.state != State::Written);
+                if !blocked {
+                    self.rob[index].state = State::Executing(entry.latency);
+                }
+            }
+
+            let entry = &mut self.rob[index];
+            if let State::Executing(left) = entry.state {
+                entry.state = if left <= 1 { State::Done } else { State::Executing(left - 1) };
+            }
+        }
+    }
+
+    //put finished results on the common data bus, one per cycle, waking up whoever waits for them
+    fn write_result(&mut self) {
+        let cycle = self.cycles;
+        let mut bus_free = true;
+
+        for index in 0..self.rob.len() {
+            if self.rob[index].state != State::Done {
+                continue;
+            }
+
+            //stores and branches have nothing to broadcast and don't need the bus
+            let broadcasts = !self.rob[index].writes.is_empty();
+            if broadcasts && !bus_free {
+                self.bus_conflicts += 1;
+                continue;
+            }
+            bus_free &= !broadcasts;
+
+            let entry = &mut self.rob[index];
+            entry.state = State::Written;
+            let (slot, (unit, station)) = (entry.slot, entry.station);
+            self.stations_of(unit)[station] = None;
+
+            for waiting in &mut self.rob {
+                let before = waiting.waiting.len();
+                waiting.waiting.retain(|&producer| producer != slot);
+                for _ in waiting.waiting.len()..before {
+                    waiting.ready.push(Source::Rob(slot));
+                    waiting.woken = cycle;
+                }
+            }
+        }
+    }
+
+    //put the next instruction on the predicted path into the reorder buffer and a reservation station
+    fn issue(&mut self, cpu: &mut CPU) {
+        if self.rob.len() == ROB_SIZE {
+            self.rob_full += 1;
+            return;
+        }
+
+        let address = self.fetch_pc;
+        let inst = cpu.fetch(address);
+        let class = Class::of(&inst);
+        let unit = Unit::of(class);
+        let station = match self.stations_of(unit).iter().position(|station| station.is_none()) {
+            Some(station) => station,
+            None => {
+                self.stations_full += 1;
+                return;
+            }
+        };
+
+        let slot = self.next_slot;
+        self.next_slot = (slot + 1) % ROB_SIZE;
+        self.stations_of(unit)[station] = Some(slot);
+
+        //rename: take operands from the register file, the reorder buffer or wait for them
+        let (mut reads, writes) = inst.dataflow();
+        reads.sort();
+        reads.dedup();
+        let (mut ready, mut waiting) = (vec![], vec![]);
+        for register in reads {
+            match self.rename[register as usize] {
+                Some(producer) if self.rob.iter().any(|entry| entry.slot == producer && entry.state != State::Written) => waiting.push(producer),
+                Some(producer) => ready.push(Source::Rob(producer)),
+                None => ready.push(Source::Register(register)),
+            }
+        }
+        for &register in &writes {
+            self.rename[register as usize] = Some(slot);
+        }
+
+        self.rob.push_back(Entry {
+            slot, address, inst, class, writes,
+            latency: cpu.latency(&inst),
+            state: State::Issued,
+            station: (unit, station),
+            ready, waiting,
+            woken: self.cycles,
+        });
+
+        //after a delay slot fetch goes where the branch before it was predicted to go
+        self.fetch_pc = self.predicted.take().unwrap_or(address.wrapping_add(4));
+        if inst.has_delay_slot() {
+            self.predicted = Some(predict(address, &inst));
+        }
+    }
+
+    fn stations_of(&mut self, unit: Unit) -> &mut Vec<Option<usize>> {
+        &mut self.stations.iter_mut().find(|(u, _)| *u == unit).unwrap().1
+    }
+
+    fn entry(&self, slot: usize) -> Option<&Entry> {
+        self.rob.iter().find(|entry| entry.slot == slot)
+    }
+
+    //print the reorder buffer, reservation stations and register status table
+    fn print(&self, cpu: &CPU) {
+        match self.committed_last {
+            Some(address) => println!("Cycle {} (committed 0x{:0>8X})", self.cycles, address),
+            None => println!("Cycle {}", self.cycles),
+        }
+
+        println!("  Reorder buffer:");
+        for entry in &self.rob {
+            let state = match entry.state {
+                State::Issued => "issued".to_string(),
+                State::Executing(left) => format!("executing ({} left)", left),
+                State::Done => "waiting for CDB".to_string(),
+                State::Written => "written".to_string(),
+            };
+            let destination: Vec<String> = entry.writes.iter().map(|&register| name(register)).collect();
+            println!("    #{}  0x{:0>8X}  {:<20}{:<10}{}", entry.slot, entry.address, state, destination.join(","),
+                disassembler::disassemble(entry.inst.word, entry.address, Style::Mars));
+        }
+        println!("    next fetch from 0x{:0>8X}, PC is 0x{:0>8X}", self.fetch_pc, cpu.PC);
+
+        println!("  Reservation stations:");
+        for ((_, stations), (_, unit_name, _)) in self.stations.iter().zip(UNITS) {
+            for (index, station) in stations.iter().enumerate() {
+                match station.and_then(|slot| self.entry(slot)) {
+                    Some(entry) => {
+                        let ready: Vec<String> = entry.ready.iter().map(|source| match *source {
+                            Source::Register(register) => name(register),
+                            Source::Rob(slot) => format!("#{}", slot),
+                        }).collect();
+                        let waiting: Vec<String> = entry.waiting.iter().map(|slot| format!("#{}", slot)).collect();
+                        println!("    {}{}  busy  #{:<3}{:<8}V: {:<16}Q: {}", unit_name, index + 1, entry.slot,
+                            mnemonic(&entry.inst), ready.join(" "), waiting.join(" "));
+                    }
+                    None => println!("    {}{}  free", unit_name, index + 1),
+                }
+            }
+        }
+
+        let renamed: Vec<String> = self.rename.iter().enumerate()
+            .filter_map(|(register, slot)| slot.map(|slot| format!("{}=#{}", name(register as u8), slot)))
+            .collect();
+        println!("  Register status: {}", if renamed.is_empty() { "-".to_string() } else { renamed.join(" ") });
+    }
+
+    //print cycles, instructions and where the cycles went
+    pub fn print_stats(&self) {
+        println!("Cycles:\t\t\t{}", self.cycles);
+        println!("Instructions committed:\t{}", self.committed);
+        if self.committed > 0 {
+            println!("CPI:\t\t\t{:.3}", self.cycles as f64 / self.committed as f64);
+            println!("IPC:\t\t\t{:.3}", self.committed as f64 / self.cycles as f64);
+        }
+        println!("Flushes:\t\t{} ({} instructions thrown away)", self.flushes, self.flushed);
+        println!("ROB full stalls:\t{}", self.rob_full);
+        println!("Station full stalls:\t{}", self.stations_full);
+        println!("CDB conflicts:\t\t{}", self.bus_conflicts);
+        println!("Cache miss stalls:\t{}", self.memory_stalls);
+    }
+}
+
+//where fetch goes after the delay slot of a branch: jumps to their target, conditional branches
+//taken if they go backwards (loops) and not taken otherwise. Register jumps aren't known before
+//they execute and are predicted to fall through, so they always flush.
+fn predict(address: u32, inst: &Instruction) -> u32 {
+    match inst.op {
+        Some(Op::J) | Some(Op::JAL) => inst.jump_target(address),
+        Some(Op::JR) | Some(Op::JALR) => address.wrapping_add(8),
+        _ => {
+            let target = inst.branch_target(address);
+            if target <= address { target } else { address.wrapping_add(8) }
+        }
+    }
+}
+
+fn name(register: u8) -> String {
+    match register {
+        32 => "$hi".to_string(),
+        33 => "$lo".to_string(),
+        _ => format!("${}", REGISTER_NAMES[register as usize]),
+    }
+}
+
+fn mnemonic(inst: &Instruction) -> &'static str {
+    instruction::lookup(inst.word).map_or("?", |info| info.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::tests::{cpu, TEXT};
+
+    #[test]
+    fn completes_out_of_order_and_commits_in_order() {
+        let mut cpu = cpu(&["addiu $t0, $zero, 7", "addiu $t1, $zero, 2", "div $t0, $t1", "addiu $t2, $zero, 1", "addiu $t3, $t2, 1", "mflo $t4"]);
+        cpu.set_timing_profile("m4k");
+        let mut tomasulo = Tomasulo::new(TEXT);
+
+        let (mut commits, mut overtaken) = (vec![], false);
+        while tomasulo.committed < 6 && tomasulo.cycles < 100 {
+            tomasulo.cycle(&mut cpu);
+            commits.extend(tomasulo.committed_last);
+
+            //the adds behind the divide are done while it is still running
+            let divide = tomasulo.rob.iter().position(|entry| entry.inst.op == Some(Op::DIV));
+            overtaken |= divide.is_some_and(|divide| tomasulo.rob.iter().skip(divide + 1).any(|entry| entry.state == State::Written)
+                && tomasulo.rob[divide].state != State::Written);
+        }
+
+        assert!(overtaken);
+        assert_eq!(commits, (0..6).map(|i| TEXT + 4 * i).collect::<Vec<_>>());
+        assert_eq!((cpu.GPR[11], cpu.GPR[12]), (2, 3));
+        assert_eq!(tomasulo.flushes, 0);
+    }
+}